@@ -1,5 +0,0 @@
-"glslang-master-windows-x64-Release\bin\glslangValidator.exe" -V shader.comp -o comp.spv
-"glslang-master-windows-x64-Release\bin\glslangValidator.exe" -V shader.frag -o frag.spv
-"glslang-master-windows-x64-Release\bin\glslangValidator.exe" -V shader.vert -o vert.spv
-"glslang-master-windows-x64-Release\bin\glslangValidator.exe" -V texture_traverse.frag -o tex_frag.spv
-pause
\ No newline at end of file
@@ -3,10 +3,12 @@
 #extension GL_EXT_scalar_block_layout : enable
 //#extension GL_EXT_debug_printf : enable
 
+// Same inputs as texture_traverse.frag, so both fit shader.vert
 layout (location = 0) in vec4 screen_pos;
-layout (location = 1) in vec2 out_uv;
+layout (location = 1) flat in vec4 pos_on_edge;
 layout (location = 2) in vec4 world_pos; // pos_on_edge + local_pos
-layout (location = 3) flat in uint loc_idx;
+layout (location = 3) in vec2 out_uv;
+layout (location = 4) flat in uint loc_idx;
 
 layout (location = 0) out vec4 frag_color;
 
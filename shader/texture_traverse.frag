@@ -1,12 +1,41 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
 #extension GL_ARB_shading_language_420pack : enable
-#extension GL_EXT_debug_printf : enable
 // #extension EXT_gpu_shader4 : require
 
-#define MAX_DEPTH 6
+// The engine passes the values of the Rust constants as defines,
+// the fallbacks only keep the file compilable on its own
+#ifndef MAX_DEPTH
+#define MAX_DEPTH 8
+#endif
+#ifndef MAX_DEPTH_LIMIT
+#define MAX_DEPTH_LIMIT 16
+#endif
+#ifndef MAX_STEP
 #define MAX_STEP 50
+#endif
+#ifndef TEXTURE_ALIGN
 #define TEXTURE_ALIGN 16
+#endif
+
+// Compiled a second time with BRICK_TEXTURE_3D for the 3D brick layout
+#ifndef BRICK_TEXTURE_RES
+#ifdef BRICK_TEXTURE_3D
+#define BRICK_TEXTURE_RES 256
+#else
+#define BRICK_TEXTURE_RES 4096
+#endif
+#endif
+
+// Cells per axis of the global distance field
+#ifndef GLOBAL_SDF_RES
+#define GLOBAL_SDF_RES 64
+#endif
+
+// Brick slot of proxies whose brick is not in the atlas
+#ifndef MISSING_SLOT
+#define MISSING_SLOT 4294967295u
+#endif
 
 layout (location = 0) in vec4 screen_pos;
 layout (location = 1) flat in vec4 pos_on_edge;
@@ -39,19 +68,23 @@ struct Ray {
 };
 
 struct LocInfo {
-    // For proper alignment set depth to 16
-    uint parent_list[16];
-    uint last_hit_idx[16];
-
-    uint padding[2];
+    // Sized like LocInfo on the cpu, not by the octree depth
+    uint parent_list[MAX_DEPTH_LIMIT];
+    uint last_hit_idx[MAX_DEPTH_LIMIT];
 
     uint depth;
     float span;
+
+    uint brick_slot;
+    uint padding;
+    // Corner of the brick the proxy lies in, w unused
+    vec4 brick_origin;
 };
 
 layout (set = 0, binding = 0) uniform Uniform {
     mat4 view_proj;
     vec4 pos;
+    vec4 velocity;
 
     vec4 cam_pos;
     vec4 cam_front;
@@ -69,8 +102,14 @@ layout (set = 0, binding = 0) uniform Uniform {
 } uniform_buffer;
 
 layout (set = 1, binding = 0) buffer NodeData { uint node_data[4096]; };
-layout (set = 2, binding = 0) buffer LocationData { LocInfo loc_info[16]; };
-layout (set = 3, binding = 0) uniform sampler2D brick_texture;
+layout (set = 2, binding = 0) buffer LocationData { LocInfo loc_info[]; };
+#ifdef BRICK_TEXTURE_3D
+layout (set = 3, binding = 0) uniform texture3D brick_texture;
+#else
+layout (set = 3, binding = 0) uniform texture2D brick_texture;
+#endif
+layout (set = 4, binding = 0) uniform sampler brick_sampler;
+layout (set = 5, binding = 0) uniform texture3D global_sdf;
 
 vec3 rayCubeIntersect(vec3 origin, vec3 dir, vec3 inv_ray_dir, float span) {
     float size_cp = span * 0.5;
@@ -79,6 +118,57 @@ vec3 rayCubeIntersect(vec3 origin, vec3 dir, vec3 inv_ray_dir, float span) {
     return -inv_pos * inv_ray_dir;
 }
 
+#ifdef BRICK_TEXTURE_3D
+// Same as BrickLayout::slot_to_offset
+ivec3 slot_to_px(uint slot) {
+    uint grid = uint(BRICK_TEXTURE_RES / TEXTURE_ALIGN);
+
+    return ivec3(
+        int(slot % grid),
+        int((slot / grid) % grid),
+        int(slot / (grid * grid))
+    ) * TEXTURE_ALIGN;
+}
+#else
+// Same as BrickLayout::slot_to_offset
+ivec2 slot_to_px(uint slot) {
+    uint length = slot * uint(TEXTURE_ALIGN * TEXTURE_ALIGN);
+
+    return ivec2(
+        int((length / uint(BRICK_TEXTURE_RES)) * uint(TEXTURE_ALIGN)),
+        int(length % uint(BRICK_TEXTURE_RES))
+    );
+}
+#endif
+
+// Voxel at brick_pos inside the brick in slot, outside counts as empty
+vec4 fetch_voxel(uint slot, vec3 brick_pos) {
+    if (any(lessThan(brick_pos, vec3(0.0))) || any(greaterThanEqual(brick_pos, vec3(TEXTURE_ALIGN)))) {
+        return vec4(0.0, 0.0, 0.0, 1.0);
+    }
+
+#ifdef BRICK_TEXTURE_3D
+    ivec3 px = slot_to_px(slot) + ivec3(brick_pos);
+    return texelFetch(sampler3D(brick_texture, brick_sampler), px, 0);
+#else
+    ivec2 px = slot_to_px(slot) + ivec2(pos_to_px(brick_pos));
+    return texelFetch(sampler2D(brick_texture, brick_sampler), px, 0);
+#endif
+}
+
+// Distance to the nearest occupied cell of the octree, rays can
+// skip it without checking the bricks. 0 outside of the field
+float global_distance(vec3 pos) {
+    float cell_span = uniform_buffer.root_span / float(GLOBAL_SDF_RES);
+    ivec3 cell = ivec3(floor(pos / cell_span));
+
+    if (any(lessThan(cell, ivec3(0))) || any(greaterThanEqual(cell, ivec3(GLOBAL_SDF_RES)))) {
+        return 0.0;
+    }
+
+    return texelFetch(sampler3D(global_sdf, brick_sampler), cell, 0).r;
+}
+
 // todo: fix shader, dunno maybe look into cpu side
 // todo: edit to support sdf with jfa
 // todo: be happy :)
@@ -99,22 +189,26 @@ void main() {
     vec3 hit_mask_vec = vec3(lessThan(hit, min(hit.yzx, hit.zxy)));
     float len = dot(hit, hit_mask_vec);
 
-    vec3 pos_on_edge = floor(world_pos);
-    vec3 local_pos = world_pos - pos_on_edge;
-
-    if (gl_FragCoord.xy.x < 4 && gl_FragCoord.xy.y < 4) {
-        //debugPrintfEXT("\n%f %d %d", span, depth, loc_info[loc_idx].parent_list[0]);
+    uint brick_slot = loc_info[loc_idx].brick_slot;
+    if (brick_slot == MISSING_SLOT) {
+        // Brick is still streamed in
+        frag_color = vec4(0.5, 0.5, 0.5, 1.0);
+        return;
     }
 
+    // Proxies can be smaller than bricks, so voxels are fetched
+    // relative to the brick and not to the proxy
+    vec3 brick_origin = loc_info[loc_idx].brick_origin.xyz;
+
+    vec3 voxel_pos = floor(world_pos);
+    vec3 local_pos = world_pos - voxel_pos;
+
     len = 50.0;
 
     float max_len = len;
     float dist = 0.0;
 
-    vec2 base_pos = vec2(0); // todo
-    // vec2 max_pos = vec2(TEXTURE_ALIGN, TEXTURE_ALIGN + (TEXTURE_ALIGN * TEXTURE_ALIGN)) + base_pos;
-
-    vec4 col = texelFetch(brick_texture, ivec2(pos_to_px(pos_on_edge)), 0);
+    vec4 col = fetch_voxel(brick_slot, voxel_pos - brick_origin);
 
     bool out_parent = false;
 
@@ -124,6 +218,20 @@ void main() {
             return;
         }
 
+        // Jump over empty space, the field never crosses into geometry
+        float skip = global_distance(voxel_pos + local_pos);
+        if (skip >= 1.0) {
+            vec3 skip_pos = voxel_pos + local_pos + ray.dir * skip;
+
+            voxel_pos = floor(skip_pos);
+            local_pos = skip_pos - voxel_pos;
+            dist += skip;
+
+            col = fetch_voxel(brick_slot, voxel_pos - brick_origin);
+            out_parent = dist > max_len;
+            continue;
+        }
+
         hit = rayCubeIntersect(local_pos, ray.dir, ray.inv_ray_dir, 1.0);
         hit_mask_vec = vec3(lessThan(hit, min(hit.yzx, hit.zxy)));
 
@@ -132,13 +240,13 @@ void main() {
         vec3 local_pos_on_edge = hit_mask_vec * sign(ray.dir);
 
         local_pos += ray.dir * len - local_pos_on_edge;
-        pos_on_edge += local_pos_on_edge;
+        voxel_pos += local_pos_on_edge;
 
         dist += len;
 
         local_pos += len * ray.dir;
 
-        col = texelFetch(brick_texture, ivec2(pos_to_px(pos_on_edge)), 0);
+        col = fetch_voxel(brick_slot, voxel_pos - brick_origin);
         out_parent = dist > max_len;
     }
 }
\ No newline at end of file
@@ -0,0 +1,288 @@
+use std::{
+    env,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use ash::vk;
+use image::{Rgba, RgbaImage};
+use nalgebra_glm::{Vec2, Vec4};
+
+use crate::{pipe::capture::Capture, tree::octree::Octree, uniform::Uniform, vector::Vector};
+
+// Checked in golden images, one png per case. Relative to the
+// crate, so the check works from any working directory
+pub const GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/golden");
+// Small, so the software renderer stays fast in debug builds
+pub const GOLDEN_RES: vk::Extent2D = vk::Extent2D {
+    width: 160,
+    height: 90,
+};
+
+/// Renderer the cases are rendered with, chosen at startup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GoldenBackend {
+    #[default]
+    Software,
+    Vulkan,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GoldenMode {
+    // Compare against the golden images
+    Check,
+    // Overwrite the golden images with the current renders
+    Update,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum GoldenScene {
+    Builder(fn(&mut Octree)),
+    // Json dump, relative to the golden dir
+    File(&'static str),
+}
+
+/// Canned scene seen from a fixed camera pose.
+#[derive(Clone, Copy, Debug)]
+pub struct GoldenCase {
+    pub name: &'static str,
+    pub scene: GoldenScene,
+
+    pub cam_pos: Vec4,
+    // x = Yaw | y = Pitch in degrees, same as Uniform::mouse_rot
+    pub mouse_rot: Vec2,
+}
+
+/// A pixel is bad if any channel differs by more than channel,
+/// a case fails if more than max_bad_ratio of its pixels are
+/// bad. GPU and CPU may round a few pixels differently.
+#[derive(Clone, Copy, Debug)]
+pub struct GoldenTolerance {
+    pub channel: u8,
+    pub max_bad_ratio: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct GoldenResult {
+    pub name: String,
+
+    pub bad_count: usize,
+    pub px_count: usize,
+
+    pub passed: bool,
+}
+
+pub fn golden_case_list() -> Vec<GoldenCase> {
+    vec![
+        GoldenCase {
+            name: "test_scene_front",
+            scene: GoldenScene::Builder(Octree::test_scene),
+            cam_pos: Vec4::new(-12.0, 4.0, 4.0, 0.0),
+            mouse_rot: Vec2::new(0.0, 0.0),
+        },
+        GoldenCase {
+            name: "test_scene_corner",
+            scene: GoldenScene::Builder(Octree::test_scene),
+            cam_pos: Vec4::new(-20.0, 30.0, -20.0, 0.0),
+            mouse_rot: Vec2::new(45.0, -40.0),
+        },
+        GoldenCase {
+            name: "single_voxel",
+            scene: GoldenScene::Builder(single_voxel_scene),
+            cam_pos: Vec4::new(-6.0, 9.0, 9.0, 0.0),
+            mouse_rot: Vec2::new(0.0, 0.0),
+        },
+        GoldenCase {
+            name: "voxel_line",
+            scene: GoldenScene::File("scene/voxel_line.json"),
+            cam_pos: Vec4::new(-10.0, 12.0, -16.0, 0.0),
+            mouse_rot: Vec2::new(30.0, -20.0),
+        },
+    ]
+}
+
+/// Dir for actual and diff images of failed cases, golden/ in
+/// the cargo target dir.
+pub fn golden_out_dir() -> PathBuf {
+    env::var_os("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("target"))
+        .join("golden")
+}
+
+pub fn single_voxel_scene(octree: &mut Octree) {
+    octree.insert_node(Vec4::ftv(8.0));
+}
+
+impl GoldenCase {
+    pub fn octree(&self) -> Result<Octree, Box<dyn Error>> {
+        match self.scene {
+            GoldenScene::Builder(build) => {
+                let mut octree = Octree::default();
+                build(&mut octree);
+                Ok(octree)
+            }
+            GoldenScene::File(path) => Octree::load_dump(
+                Path::new(GOLDEN_DIR)
+                    .join(path)
+                    .to_str()
+                    .ok_or("ERR_GOLDEN_PATH")?,
+            ),
+        }
+    }
+
+    pub fn uniform(&self, octree: &Octree) -> Uniform {
+        let mut uniform = Uniform::new(octree.root_span);
+        uniform.apply_resolution(GOLDEN_RES);
+
+        uniform.cam_pos = self.cam_pos;
+        uniform.mouse_rot = self.mouse_rot;
+        // Look dir is derived from the mouse rotation
+        uniform.move_mouse(Vec2::zeros());
+        uniform.update_uniform(Duration::ZERO);
+
+        uniform
+    }
+
+    pub fn golden_path(&self) -> String {
+        format!("{}/{}.png", GOLDEN_DIR, self.name)
+    }
+}
+
+impl GoldenTolerance {
+    /// Count bad pixels of actual against golden. The diff image
+    /// shows bad pixels red over a faded copy of the golden image.
+    pub fn compare(&self, actual: &RgbaImage, golden: &RgbaImage) -> (usize, RgbaImage) {
+        let mut diff = RgbaImage::new(golden.width(), golden.height());
+        let mut bad_count = 0;
+
+        diff.enumerate_pixels_mut().for_each(|(x, y, px)| {
+            let golden_px = golden.get_pixel(x, y);
+            let actual_px = actual.get_pixel(x, y);
+
+            let is_bad = golden_px
+                .0
+                .iter()
+                .zip(actual_px.0.iter())
+                .any(|(a, b)| a.abs_diff(*b) > self.channel);
+
+            *px = if is_bad {
+                bad_count += 1;
+                Rgba([255, 0, 0, 255])
+            } else {
+                let luma = golden_px.0[..3].iter().map(|&val| val as u32).sum::<u32>() / 3;
+                let faded = (luma / 4 + 128) as u8;
+                Rgba([faded, faded, faded, 255])
+            };
+        });
+
+        (bad_count, diff)
+    }
+}
+
+/// Render every case and compare it against its golden image,
+/// or write it as the new golden image. Returns whether all
+/// cases passed.
+pub fn run_golden<Render: FnMut(&Octree, &Uniform) -> Result<Capture, Box<dyn Error>>>(
+    mode: GoldenMode,
+    tolerance: &GoldenTolerance,
+    mut render: Render,
+) -> Result<bool, Box<dyn Error>> {
+    let mut result_list = vec![];
+
+    for case in golden_case_list() {
+        let octree = case.octree()?;
+        let actual = render(&octree, &case.uniform(&octree))?;
+
+        if mode == GoldenMode::Update {
+            fs::create_dir_all(GOLDEN_DIR)?;
+            actual.save(&case.golden_path())?;
+            continue;
+        }
+
+        result_list.push(check_case(&case, &actual.to_rgba(), tolerance)?);
+    }
+
+    result_list
+        .iter()
+        .for_each(|result| log::info!("{}", result));
+
+    Ok(result_list.iter().all(|result| result.passed))
+}
+
+fn check_case(
+    case: &GoldenCase,
+    actual: &RgbaImage,
+    tolerance: &GoldenTolerance,
+) -> Result<GoldenResult, Box<dyn Error>> {
+    let px_count = actual.pixels().len();
+
+    let (bad_count, diff) = match image::open(case.golden_path()) {
+        Ok(golden) if golden.width() == actual.width() && golden.height() == actual.height() => {
+            tolerance.compare(actual, &golden.to_rgba8())
+        }
+        // Missing or resized golden images fail as a whole
+        _ => (
+            px_count,
+            RgbaImage::from_pixel(actual.width(), actual.height(), Rgba([255, 0, 0, 255])),
+        ),
+    };
+
+    let passed = bad_count as f32 <= px_count as f32 * tolerance.max_bad_ratio;
+
+    if !passed {
+        let out_dir = golden_out_dir();
+
+        fs::create_dir_all(&out_dir)?;
+        actual.save(out_dir.join(format!("{}_actual.png", case.name)))?;
+        diff.save(out_dir.join(format!("{}_diff.png", case.name)))?;
+    }
+
+    Ok(GoldenResult {
+        name: case.name.to_string(),
+        bad_count,
+        px_count,
+        passed,
+    })
+}
+
+impl fmt::Display for GoldenResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Golden [ {} ] {} with [ {} / {} ] bad pixels",
+            self.name,
+            if self.passed { "passed" } else { "FAILED" },
+            self.bad_count,
+            self.px_count
+        )
+    }
+}
+
+impl Default for GoldenTolerance {
+    fn default() -> Self {
+        Self {
+            channel: 4,
+            max_bad_ratio: 0.002,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Pref, Render};
+
+    #[test]
+    fn software_matches_golden() {
+        let pref = Pref {
+            golden_backend: GoldenBackend::Software,
+            ..Render::get_pref()
+        };
+
+        let passed = Render::run_golden(&pref, GoldenMode::Check).expect("ERR_RUN_GOLDEN");
+        assert!(passed, "see {} for diffs", golden_out_dir().display());
+    }
+}
@@ -4,7 +4,7 @@ use winit::{
     event::{ElementState, VirtualKeyCode},
 };
 
-use crate::{interface::interface::Interface, uniform::Uniform, tree::octree::Octree, Pref};
+use crate::{interface::interface::Interface, tree::octree::Octree, uniform::Uniform, Pref};
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum Action {
@@ -22,11 +22,21 @@ pub enum Action {
     ESCAPE,
 
     RESET,
+
+    SCREENSHOT,
+
+    PLACE,
+    REMOVE,
+
+    MODE,
 }
 
 pub struct Input {
     pub binding_list: [Action; 256],
     pub key_down: [bool; 256],
+
+    // Actions pressed since they were last taken
+    pub pressed_list: Vec<Action>,
 }
 
 impl Input {
@@ -46,7 +56,18 @@ impl Input {
 
         binding_list[VirtualKeyCode::R as usize] = Action::RESET;
 
-        Input { binding_list, key_down: [false; 256] }
+        binding_list[VirtualKeyCode::F12 as usize] = Action::SCREENSHOT;
+
+        binding_list[VirtualKeyCode::E as usize] = Action::PLACE;
+        binding_list[VirtualKeyCode::Q as usize] = Action::REMOVE;
+
+        binding_list[VirtualKeyCode::M as usize] = Action::MODE;
+
+        Input {
+            binding_list,
+            key_down: [false; 256],
+            pressed_list: vec![],
+        }
     }
 
     pub fn handle_key_input(
@@ -59,6 +80,12 @@ impl Input {
         interface: &Interface,
     ) {
         if state == &ElementState::Pressed {
+            // Ignore key repeat, only the first press counts
+            let action = self.binding_list[*keycode as usize];
+            if !self.key_down[*keycode as usize] && action != Action::NONE {
+                self.pressed_list.push(action);
+            }
+
             self.key_down[*keycode as usize] = true;
             /*
             match self.binding_list[*keycode as usize] {
@@ -95,6 +122,14 @@ impl Input {
         }
     }
 
+    /// True if action was pressed since the last call.
+    pub fn take_pressed(&mut self, action: Action) -> bool {
+        let pressed = self.pressed_list.contains(&action);
+        self.pressed_list.retain(|&other| other != action);
+
+        pressed
+    }
+
     pub fn handle_mouse_input(&self, position: PhysicalPosition<f64>, uniform: &mut Uniform) {
         let mouse_pos = Vec2::new(position.x as f32, position.y as f32);
         let mouse_delta = mouse_pos - uniform.res / 2.0;
@@ -0,0 +1,358 @@
+use std::{fmt, ptr};
+
+use ash::{vk, Device};
+
+use super::phydev::PhyDeviceGroup;
+
+// Memory of one block, shared by every small resource of a memory type
+pub const MEM_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+// Resources at least this large get an allocation of their own
+pub const DEDICATED_MIN_SIZE: u64 = MEM_BLOCK_SIZE / 4;
+
+/// Buffers and optimal tiled images are kept in separate blocks,
+/// so bufferImageGranularity never has to be respected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResourceKind {
+    #[default]
+    Buffer,
+    Image,
+}
+
+/// Part of a memory block a resource is bound to.
+#[derive(Clone, Copy, Debug)]
+pub struct Allocation {
+    pub mem: vk::DeviceMemory,
+    pub offset: u64,
+    pub size: u64,
+
+    // Mapped start of the allocation, null if not host visible
+    pub ptr: *mut u8,
+    pub block_idx: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct MemBlock {
+    pub mem: vk::DeviceMemory,
+    pub size: u64,
+
+    pub mem_type_idx: u32,
+    pub kind: ResourceKind,
+    // Holds exactly one resource and is freed with it
+    pub dedicated: bool,
+
+    // Whole block stays mapped while it exists
+    pub ptr: *mut u8,
+    // (offset, size) of every free range, sorted by offset
+    pub free_list: Vec<(u64, u64)>,
+    pub allocation_count: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemStats {
+    pub block_count: usize,
+    pub block_bytes: u64,
+
+    pub dedicated_count: usize,
+    pub dedicated_bytes: u64,
+
+    pub allocation_count: usize,
+    pub used_bytes: u64,
+}
+
+/// Sub-allocates buffers and images from large blocks per memory
+/// type, instead of one vkAllocateMemory per resource. Freed ranges
+/// are merged with their neighbors, empty blocks are given back.
+#[derive(Clone, Debug, Default)]
+pub struct MemAllocator {
+    // Freed blocks leave None behind, so block indices stay valid
+    pub block_list: Vec<Option<MemBlock>>,
+}
+
+impl MemBlock {
+    /// Offset of the first free range which fits size at alignment.
+    fn find_range(&self, size: u64, alignment: u64) -> Option<(usize, u64)> {
+        self.free_list
+            .iter()
+            .enumerate()
+            .find_map(|(range_idx, &(offset, range_size))| {
+                let aligned = offset.next_multiple_of(alignment.max(1));
+                (aligned + size <= offset + range_size).then_some((range_idx, aligned))
+            })
+    }
+
+    /// Take size bytes at aligned out of the free range, the
+    /// padding in front of it stays free.
+    fn take_range(&mut self, range_idx: usize, aligned: u64, size: u64) {
+        let (offset, range_size) = self.free_list[range_idx];
+        let end = offset + range_size;
+
+        let mut split_list = vec![];
+        if aligned > offset {
+            split_list.push((offset, aligned - offset));
+        }
+        if aligned + size < end {
+            split_list.push((aligned + size, end - aligned - size));
+        }
+
+        self.free_list.splice(range_idx..range_idx + 1, split_list);
+        self.allocation_count += 1;
+    }
+
+    fn give_back(&mut self, offset: u64, size: u64) {
+        let idx = self.free_list.partition_point(|&(other, _)| other < offset);
+        self.free_list.insert(idx, (offset, size));
+
+        // Merge with the following and then the previous range
+        if idx + 1 < self.free_list.len() {
+            let (next_offset, next_size) = self.free_list[idx + 1];
+            if offset + size == next_offset {
+                self.free_list[idx].1 += next_size;
+                self.free_list.remove(idx + 1);
+            }
+        }
+        if idx > 0 {
+            let (prev_offset, prev_size) = self.free_list[idx - 1];
+            if prev_offset + prev_size == offset {
+                self.free_list[idx - 1].1 += self.free_list[idx].1;
+                self.free_list.remove(idx);
+            }
+        }
+
+        self.allocation_count -= 1;
+    }
+
+    fn free_bytes(&self) -> u64 {
+        self.free_list.iter().map(|&(_, size)| size).sum()
+    }
+}
+
+impl MemAllocator {
+    /// Memory for a resource with mem_req, which still has to be
+    /// bound at the offset of the allocation. Large images pass
+    /// dedicated_img to get memory of their own.
+    pub fn alloc(
+        &mut self,
+        device: &Device,
+        phy_device: &PhyDeviceGroup,
+        mem_req: &vk::MemoryRequirements,
+        mem_flags: vk::MemoryPropertyFlags,
+        kind: ResourceKind,
+        dedicated_img: Option<vk::Image>,
+    ) -> Allocation {
+        let mem_type_idx = phy_device
+            .find_memorytype_index(mem_req, mem_flags)
+            .expect("ERR_MEM_TYPE_INDEX");
+
+        let dedicated = mem_req.size >= DEDICATED_MIN_SIZE;
+        if dedicated {
+            let block_idx = self.create_block(
+                device,
+                phy_device,
+                mem_req.size,
+                mem_type_idx,
+                kind,
+                dedicated_img.filter(|_| kind == ResourceKind::Image),
+            );
+            let block = self.block_list[block_idx].as_mut().unwrap();
+            block.dedicated = true;
+            block.free_list.clear();
+            block.allocation_count = 1;
+
+            return Allocation {
+                mem: block.mem,
+                offset: 0,
+                size: mem_req.size,
+                ptr: block.ptr,
+                block_idx,
+            };
+        }
+
+        let found = self
+            .block_list
+            .iter()
+            .enumerate()
+            .find_map(|(block_idx, block)| {
+                let block = block.as_ref()?;
+                if block.dedicated || block.mem_type_idx != mem_type_idx || block.kind != kind {
+                    return None;
+                }
+
+                block
+                    .find_range(mem_req.size, mem_req.alignment)
+                    .map(|(range_idx, aligned)| (block_idx, range_idx, aligned))
+            });
+
+        let (block_idx, range_idx, aligned) = match found {
+            Some(found) => found,
+            None => {
+                let block_idx =
+                    self.create_block(device, phy_device, MEM_BLOCK_SIZE, mem_type_idx, kind, None);
+                (block_idx, 0, 0)
+            }
+        };
+
+        let block = self.block_list[block_idx].as_mut().unwrap();
+        block.take_range(range_idx, aligned, mem_req.size);
+
+        Allocation {
+            mem: block.mem,
+            offset: aligned,
+            size: mem_req.size,
+            ptr: if block.ptr.is_null() {
+                ptr::null_mut()
+            } else {
+                unsafe { block.ptr.add(aligned as usize) }
+            },
+            block_idx,
+        }
+    }
+
+    /// Give the range back, the block is freed once it is empty.
+    pub fn free(&mut self, device: &Device, allocation: &Allocation) {
+        let block = match self
+            .block_list
+            .get_mut(allocation.block_idx)
+            .and_then(|block| block.as_mut())
+        {
+            Some(block) => block,
+            // Default allocation, nothing was allocated
+            None => return,
+        };
+
+        if block.dedicated {
+            block.allocation_count = 0;
+        } else {
+            block.give_back(allocation.offset, allocation.size);
+        }
+
+        if block.allocation_count == 0 {
+            unsafe { device.free_memory(block.mem, None) };
+            self.block_list[allocation.block_idx] = None;
+        }
+    }
+
+    fn create_block(
+        &mut self,
+        device: &Device,
+        phy_device: &PhyDeviceGroup,
+        size: u64,
+        mem_type_idx: u32,
+        kind: ResourceKind,
+        dedicated_img: Option<vk::Image>,
+    ) -> usize {
+        unsafe {
+            let mut dedicated_info = vk::MemoryDedicatedAllocateInfo {
+                image: dedicated_img.unwrap_or_default(),
+                ..Default::default()
+            };
+
+            let mut allocate_info = vk::MemoryAllocateInfo::builder()
+                .allocation_size(size)
+                .memory_type_index(mem_type_idx);
+            if dedicated_img.is_some() {
+                allocate_info = allocate_info.push_next(&mut dedicated_info);
+            }
+
+            let mem = device
+                .allocate_memory(&allocate_info, None)
+                .expect("ERR_ALLOCATE_MEM");
+
+            let host_visible = phy_device.mem_prop.memory_types[mem_type_idx as usize]
+                .property_flags
+                .contains(vk::MemoryPropertyFlags::HOST_VISIBLE);
+            let ptr = if host_visible {
+                device
+                    .map_memory(mem, 0, size, vk::MemoryMapFlags::empty())
+                    .expect("ERR_MAP_MEM") as *mut u8
+            } else {
+                ptr::null_mut()
+            };
+
+            let block = MemBlock {
+                mem,
+                size,
+
+                mem_type_idx,
+                kind,
+                dedicated: false,
+
+                ptr,
+                free_list: vec![(0, size)],
+                allocation_count: 0,
+            };
+
+            log::info!(
+                "Allocated memory block of [ {} ] bytes with type [ {} ] ...",
+                size,
+                mem_type_idx
+            );
+
+            match self.block_list.iter().position(|block| block.is_none()) {
+                Some(block_idx) => {
+                    self.block_list[block_idx] = Some(block);
+                    block_idx
+                }
+                None => {
+                    self.block_list.push(Some(block));
+                    self.block_list.len() - 1
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> MemStats {
+        self.block_list
+            .iter()
+            .flatten()
+            .fold(MemStats::default(), |mut stats, block| {
+                if block.dedicated {
+                    stats.dedicated_count += 1;
+                    stats.dedicated_bytes += block.size;
+                } else {
+                    stats.block_count += 1;
+                    stats.block_bytes += block.size;
+                }
+
+                stats.allocation_count += block.allocation_count;
+                stats.used_bytes += block.size - block.free_bytes();
+
+                stats
+            })
+    }
+
+    /// Free every block, resources still bound to them have to be
+    /// destroyed already.
+    pub fn destroy(&mut self, device: &Device) {
+        self.block_list
+            .drain(..)
+            .flatten()
+            .for_each(|block| unsafe { device.free_memory(block.mem, None) });
+    }
+}
+
+impl Default for Allocation {
+    fn default() -> Self {
+        Self {
+            mem: Default::default(),
+            offset: Default::default(),
+            size: Default::default(),
+            ptr: ptr::null_mut(),
+            block_idx: usize::MAX,
+        }
+    }
+}
+
+impl fmt::Display for MemStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GPU memory [ {} ] allocations using [ {:.2} / {:.2} ] MiB in [ {} ] blocks and [ {} ] dedicated with [ {:.2} ] MiB",
+            self.allocation_count,
+            self.used_bytes as f64 / 1048576.0,
+            self.block_bytes as f64 / 1048576.0,
+            self.block_count,
+            self.dedicated_count,
+            self.dedicated_bytes as f64 / 1048576.0,
+        )
+    }
+}
@@ -0,0 +1,174 @@
+use std::{fs, mem::size_of, path::PathBuf};
+
+use ash::{vk, Device};
+
+use super::phydev::PhyDeviceGroup;
+
+// Header vkGetPipelineCacheData puts in front of the data
+const HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+
+/// Pipeline cache shared by every pipeline creation. It is loaded
+/// from a file per device and driver version at startup and saved
+/// again on exit, so shader variants compiled once are reused.
+#[derive(Clone, Debug, Default)]
+pub struct PipeCache {
+    pub cache: vk::PipelineCache,
+    pub path: PathBuf,
+}
+
+impl PipeCache {
+    pub fn load(device: &Device, phy_device: &PhyDeviceGroup) -> Self {
+        unsafe {
+            let prop = &phy_device.device_prop;
+            let file_name = Self::file_name(prop);
+            let path = Self::cache_dir().join(&file_name);
+
+            Self::remove_stale(&file_name);
+
+            // Data of another device or driver is ignored, the drivers
+            // are not required to reject it themselves
+            let data = match fs::read(&path) {
+                Ok(data) if Self::is_compatible(&data, prop) => {
+                    log::info!(
+                        "Loading PipelineCache of [ {} ] bytes from {} ...",
+                        data.len(),
+                        path.display()
+                    );
+                    data
+                }
+                Ok(_) => {
+                    log::info!("Ignoring stale PipelineCache {} ...", path.display());
+                    vec![]
+                }
+                Err(_) => {
+                    log::info!("Creating empty PipelineCache ...");
+                    vec![]
+                }
+            };
+
+            let cache_info = vk::PipelineCacheCreateInfo::builder().initial_data(&data);
+            let cache = device
+                .create_pipeline_cache(&cache_info, None)
+                .or_else(|_| {
+                    device.create_pipeline_cache(&vk::PipelineCacheCreateInfo::default(), None)
+                })
+                .expect("ERR_CREATE_PIPELINE_CACHE");
+
+            Self { cache, path }
+        }
+    }
+
+    /// Cache dir of the user, which survives a reboot unlike the temp
+    /// dir. Falls back to the temp dir, a lost cache only costs
+    /// startup time.
+    pub fn cache_dir() -> PathBuf {
+        let env_dir = |name: &str| std::env::var_os(name).filter(|dir| !dir.is_empty());
+
+        let base_dir = if cfg!(windows) {
+            env_dir("LOCALAPPDATA").map(PathBuf::from)
+        } else if cfg!(target_os = "macos") {
+            env_dir("HOME").map(|home| PathBuf::from(home).join("Library/Caches"))
+        } else {
+            env_dir("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env_dir("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        };
+
+        base_dir
+            .unwrap_or_else(std::env::temp_dir)
+            .join(env!("CARGO_PKG_NAME"))
+    }
+
+    /// Delete the caches of other devices and driver versions, every
+    /// driver update would leave one behind otherwise.
+    pub fn remove_stale(file_name: &str) {
+        let entry_list = match fs::read_dir(Self::cache_dir()) {
+            Ok(entry_list) => entry_list,
+            Err(_) => return,
+        };
+
+        entry_list
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("pipeline_cache_") && name != file_name)
+            })
+            .for_each(|path| {
+                log::info!("Removing stale PipelineCache {} ...", path.display());
+                if let Err(err) = fs::remove_file(&path) {
+                    log::info!("Removing PipelineCache failed: {} ...", err);
+                }
+            });
+    }
+
+    /// Name from vendor, device, cache UUID and driver version, so
+    /// every driver update starts with a new file.
+    pub fn file_name(prop: &vk::PhysicalDeviceProperties) -> String {
+        let uuid: String = prop
+            .pipeline_cache_uuid
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        format!(
+            "pipeline_cache_{:04x}_{:04x}_{}_{}.bin",
+            prop.vendor_id, prop.device_id, uuid, prop.driver_version
+        )
+    }
+
+    /// Check the header of cache data against the device, laid out
+    /// as length, version, vendor id, device id and cache UUID.
+    pub fn is_compatible(data: &[u8], prop: &vk::PhysicalDeviceProperties) -> bool {
+        if data.len() < HEADER_SIZE {
+            return false;
+        }
+
+        let read_u32 = |idx: usize| {
+            let offset = idx * size_of::<u32>();
+            u32::from_le_bytes(data[offset..offset + size_of::<u32>()].try_into().unwrap())
+        };
+
+        read_u32(0) as usize >= HEADER_SIZE
+            && read_u32(1) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+            && read_u32(2) == prop.vendor_id
+            && read_u32(3) == prop.device_id
+            && data[16..HEADER_SIZE] == prop.pipeline_cache_uuid
+    }
+
+    /// Write the cache next to a temp file first, an interrupted
+    /// write must not leave a broken cache behind.
+    pub fn save(&self, device: &Device) {
+        unsafe {
+            let data = match device.get_pipeline_cache_data(self.cache) {
+                Ok(data) => data,
+                Err(err) => {
+                    log::info!("Reading PipelineCache failed: {} ...", err);
+                    return;
+                }
+            };
+
+            let tmp_path = self.path.with_extension("tmp");
+            let result = self
+                .path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&tmp_path, &data))
+                .and_then(|_| fs::rename(&tmp_path, &self.path));
+
+            match result {
+                Ok(_) => log::info!(
+                    "Saved PipelineCache of [ {} ] bytes to {} ...",
+                    data.len(),
+                    self.path.display()
+                ),
+                Err(err) => log::info!("Saving PipelineCache failed: {} ...", err),
+            }
+        }
+    }
+
+    pub fn destroy(&self, device: &Device) {
+        unsafe { device.destroy_pipeline_cache(self.cache, None) };
+    }
+}
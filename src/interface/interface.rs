@@ -1,5 +1,8 @@
 use crate::{
-    interface::{phydev::PhyDeviceGroup, surface::SurfaceGroup, swapchain::SwapchainGroup},
+    interface::{
+        alloc::MemAllocator, cache::PipeCache, phydev::PhyDeviceGroup, surface::SurfaceGroup,
+        swapchain::SwapchainGroup,
+    },
     Pref,
 };
 use ash::{
@@ -11,8 +14,9 @@ use ash::{
 };
 use raw_window_handle::HasRawDisplayHandle;
 use std::{
+    cell::RefCell,
     error::Error,
-    ffi::{c_void, CStr, CString},
+    ffi::{c_char, c_void, CStr, CString},
 };
 use winit::{event_loop::EventLoop, monitor::MonitorHandle, window::WindowBuilder};
 
@@ -22,29 +26,43 @@ pub struct Interface {
     pub debug_util_loader: DebugUtils,
     pub debug_call_back: vk::DebugUtilsMessengerEXT,
 
-    pub window: winit::window::Window,
+    // Missing in headless mode
+    pub window: Option<winit::window::Window>,
     pub monitor_list: Vec<MonitorHandle>,
-    pub monitor: MonitorHandle,
+    pub monitor: Option<MonitorHandle>,
 
     pub surface: SurfaceGroup,
     pub phy_device: PhyDeviceGroup,
 
     pub device: Device,
     pub present_queue: vk::Queue,
+    // Memory of every buffer and image, borrowed while allocating
+    pub allocator: RefCell<MemAllocator>,
+    // Shared by every pipeline, saved on drop
+    pub pipe_cache: PipeCache,
 
     pub swapchain: SwapchainGroup,
 
     pub pool: vk::CommandPool,
     pub setup_cmd_buffer: vk::CommandBuffer,
     pub comp_cmd_buffer: vk::CommandBuffer,
-    pub draw_cmd_buffer: vk::CommandBuffer,
-
-    pub present_complete: vk::Semaphore,
-    pub render_complete: vk::Semaphore,
 
     pub setup_cmd_fence: vk::Fence,
     pub comp_cmd_fence: vk::Fence,
-    pub draw_cmd_fence: vk::Fence,
+
+    // One per frame in flight, used round robin
+    pub frame_list: Vec<FrameSync>,
+}
+
+/// Command buffer and sync objects of one frame in flight.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameSync {
+    pub cmd_buffer: vk::CommandBuffer,
+    // Signaled once the gpu is done with the frame
+    pub fence: vk::Fence,
+
+    pub present_complete: vk::Semaphore,
+    pub render_complete: vk::Semaphore,
 }
 
 #[macro_export]
@@ -100,10 +118,6 @@ impl Interface {
 
             let entry = Entry::load().unwrap();
 
-            log::info!("Creating VulkanInstance ...");
-            let name = CString::new(pref.name.clone()).unwrap();
-            let engine_name = CString::new(pref.engine_name.clone()).unwrap();
-
             let mut ext_name_list =
                 ash_window::enumerate_required_extensions(window.raw_display_handle())
                     .unwrap()
@@ -116,6 +130,122 @@ impl Interface {
                 ext_names.push(KhrGetPhysicalDeviceProperties2Fn::name().as_ptr());
             }
 
+            let instance = Self::create_instance(&entry, pref, &ext_name_list);
+            let (debug_util_loader, debug_call_back) =
+                Self::create_debug_messenger(&entry, &instance, true);
+
+            let mut surface = SurfaceGroup::new(&entry, &instance, &window);
+
+            log::info!("Creating PhyDevice ...");
+            let phy_device = PhyDeviceGroup::default()
+                .get_phy_device_list(&instance)
+                .get_suitable_phy_device(&instance, Some(&surface))
+                .get_phy_device_prop(&instance);
+
+            log::info!("Load Surface information ...");
+            surface = surface.get_surface_info(&phy_device, &window, pref);
+
+            let device_ext_list = [
+                Swapchain::name().as_ptr(),
+                DynamicRendering::name().as_ptr(),
+                #[cfg(any(target_os = "macos", target_os = "ios",))]
+                KhrPortabilitySubsetFn::name().as_ptr(),
+            ];
+
+            let (device, present_queue) =
+                Self::create_device(&instance, &phy_device, &device_ext_list);
+
+            log::info!("Creating Swapchain ...");
+            let mut swapchain = SwapchainGroup::new(&instance, &device).create_swapchain(&surface);
+
+            log::info!("Load PresentImgList ...");
+            swapchain = swapchain.get_present_img(&surface, &device);
+
+            Self::create_cmd(
+                entry,
+                instance,
+                (debug_util_loader, debug_call_back),
+                (Some(window), monitor_list, Some(monitor)),
+                surface,
+                phy_device,
+                device,
+                present_queue,
+                swapchain,
+                pref.frames_in_flight,
+            )
+        }
+    }
+
+    /// Interface without window, surface and swapchain. Renders
+    /// into offscreen images only, so it also runs on machines
+    /// without display, e.g. with a software driver like lavapipe.
+    /// The render resolution is fixed to extent.
+    pub fn init_headless(pref: &Pref, extent: vk::Extent2D) -> Self {
+        unsafe {
+            let entry = Entry::load().unwrap();
+
+            // Validation is optional on build machines
+            let debug_available = entry
+                .enumerate_instance_extension_properties(None)
+                .unwrap()
+                .iter()
+                .any(|ext| CStr::from_ptr(ext.extension_name.as_ptr()) == DebugUtils::name());
+
+            let ext_name_list = if debug_available {
+                vec![DebugUtils::name().as_ptr()]
+            } else {
+                vec![]
+            };
+
+            let instance = Self::create_instance(&entry, pref, &ext_name_list);
+            let (debug_util_loader, debug_call_back) =
+                Self::create_debug_messenger(&entry, &instance, debug_available);
+
+            log::info!("Creating PhyDevice ...");
+            let phy_device = PhyDeviceGroup::default()
+                .get_phy_device_list(&instance)
+                .get_suitable_phy_device(&instance, None)
+                .get_phy_device_prop(&instance);
+
+            let surface = SurfaceGroup::headless(&entry, &instance, extent);
+
+            let device_ext_list = [DynamicRendering::name().as_ptr()];
+            let (device, present_queue) =
+                Self::create_device(&instance, &phy_device, &device_ext_list);
+
+            // Loader only, there are no present images
+            let swapchain = SwapchainGroup::new(&instance, &device);
+
+            Self::create_cmd(
+                entry,
+                instance,
+                (debug_util_loader, debug_call_back),
+                (None, vec![], None),
+                surface,
+                phy_device,
+                device,
+                present_queue,
+                swapchain,
+                pref.frames_in_flight,
+            )
+        }
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.window.is_none()
+    }
+
+    /// Window of the interface, only missing in headless mode.
+    pub fn window(&self) -> &winit::window::Window {
+        self.window.as_ref().expect("ERR_NO_WINDOW")
+    }
+
+    fn create_instance(entry: &Entry, pref: &Pref, ext_name_list: &[*const c_char]) -> Instance {
+        unsafe {
+            log::info!("Creating VulkanInstance ...");
+            let name = CString::new(pref.name.clone()).unwrap();
+            let engine_name = CString::new(pref.engine_name.clone()).unwrap();
+
             let (major, minor) = match entry.try_enumerate_instance_version().unwrap() {
                 Some(version) => (
                     vk::api_version_major(version),
@@ -141,14 +271,23 @@ impl Interface {
 
             let create_info = vk::InstanceCreateInfo::builder()
                 .application_info(&app_info)
-                .enabled_extension_names(&ext_name_list)
+                .enabled_extension_names(ext_name_list)
                 .flags(create_flag);
 
-            let instance: Instance = entry
+            entry
                 .create_instance(&create_info, None)
-                .expect("ERR_CREATE_INSTANCE");
+                .expect("ERR_CREATE_INSTANCE")
+        }
+    }
 
-            // Debug part -> Validation layer stuff
+    /// Debug part -> Validation layer stuff. The messenger stays
+    /// null if the debug utils extension is not enabled.
+    fn create_debug_messenger(
+        entry: &Entry,
+        instance: &Instance,
+        enabled: bool,
+    ) -> (DebugUtils, vk::DebugUtilsMessengerEXT) {
+        unsafe {
             let debug_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
                 .message_severity(
                     vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
@@ -162,29 +301,25 @@ impl Interface {
                 )
                 .pfn_user_callback(Some(vulkan_debug_callback));
 
-            let debug_util_loader = DebugUtils::new(&entry, &instance);
-            let debug_call_back = debug_util_loader
-                .create_debug_utils_messenger(&debug_info, None)
-                .unwrap();
-
-            let mut surface = SurfaceGroup::new(&entry, &instance, &window);
-
-            log::info!("Creating PhyDevice ...");
-            let phy_device = PhyDeviceGroup::default()
-                .get_phy_device_list(&instance)
-                .get_suitable_phy_device(&instance, &surface)
-                .get_phy_device_prop(&instance);
-
-            log::info!("Load Surface information ...");
-            surface = surface.get_surface_info(&phy_device, &window, pref);
+            let debug_util_loader = DebugUtils::new(entry, instance);
+            let debug_call_back = if enabled {
+                debug_util_loader
+                    .create_debug_utils_messenger(&debug_info, None)
+                    .unwrap()
+            } else {
+                vk::DebugUtilsMessengerEXT::null()
+            };
 
-            let device_ext_list = [
-                Swapchain::name().as_ptr(),
-                DynamicRendering::name().as_ptr(),
-                #[cfg(any(target_os = "macos", target_os = "ios",))]
-                KhrPortabilitySubsetFn::name().as_ptr(),
-            ];
+            (debug_util_loader, debug_call_back)
+        }
+    }
 
+    fn create_device(
+        instance: &Instance,
+        phy_device: &PhyDeviceGroup,
+        device_ext_list: &[*const c_char],
+    ) -> (Device, vk::Queue) {
+        unsafe {
             let feature = vk::PhysicalDeviceFeatures {
                 shader_clip_distance: 1,
                 ..Default::default()
@@ -200,7 +335,7 @@ impl Interface {
 
             let device_create_info = vk::DeviceCreateInfo::builder()
                 .queue_create_infos(std::slice::from_ref(&queue_info))
-                .enabled_extension_names(&device_ext_list)
+                .enabled_extension_names(device_ext_list)
                 .enabled_features(&feature)
                 .push_next(&mut dynamic_rendering_feature);
 
@@ -210,9 +345,30 @@ impl Interface {
 
             let present_queue = device.get_device_queue(phy_device.queue_family_index, 0);
 
-            log::info!("Creating Swapchain ...");
-            let mut swapchain = SwapchainGroup::new(&instance, &device).create_swapchain(&surface);
+            (device, present_queue)
+        }
+    }
 
+    /// Create command pool, command buffer, fence and semaphore
+    /// and finish the interface.
+    #[allow(clippy::too_many_arguments)]
+    fn create_cmd(
+        entry: Entry,
+        instance: Instance,
+        (debug_util_loader, debug_call_back): (DebugUtils, vk::DebugUtilsMessengerEXT),
+        (window, monitor_list, monitor): (
+            Option<winit::window::Window>,
+            Vec<MonitorHandle>,
+            Option<MonitorHandle>,
+        ),
+        surface: SurfaceGroup,
+        phy_device: PhyDeviceGroup,
+        device: Device,
+        present_queue: vk::Queue,
+        swapchain: SwapchainGroup,
+        frame_count: usize,
+    ) -> Self {
+        unsafe {
             log::info!("Creating CommandPool ...");
             let pool_create_info = vk::CommandPoolCreateInfo::builder()
                 .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
@@ -222,7 +378,7 @@ impl Interface {
 
             log::info!("Creating CommandBuffer ...");
             let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
-                .command_buffer_count(3)
+                .command_buffer_count(2 + frame_count as u32)
                 .command_pool(pool)
                 .level(vk::CommandBufferLevel::PRIMARY);
 
@@ -232,10 +388,6 @@ impl Interface {
 
             let setup_cmd_buffer = command_buffer_list[0];
             let comp_cmd_buffer = command_buffer_list[1];
-            let draw_cmd_buffer = command_buffer_list[1];
-
-            log::info!("Load PresentImgList ...");
-            swapchain = swapchain.get_present_img(&surface, &device);
 
             log::info!("Init Fence ...");
             let fence_create_info =
@@ -247,19 +399,27 @@ impl Interface {
             let comp_cmd_fence = device
                 .create_fence(&fence_create_info, None)
                 .expect("FENCE_CREATE_ERR");
-            let draw_cmd_fence = device
-                .create_fence(&fence_create_info, None)
-                .expect("FENCE_CREATE_ERR");
 
-            log::info!("Init Semaphore ...");
+            log::info!("Init [ {} ] frames in flight ...", frame_count);
             let semaphore_create_info = vk::SemaphoreCreateInfo::default();
 
-            let present_complete = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
-            let render_complete = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
+            let frame_list = command_buffer_list[2..]
+                .iter()
+                .map(|&cmd_buffer| FrameSync {
+                    cmd_buffer,
+                    fence: device
+                        .create_fence(&fence_create_info, None)
+                        .expect("FENCE_CREATE_ERR"),
+                    present_complete: device
+                        .create_semaphore(&semaphore_create_info, None)
+                        .unwrap(),
+                    render_complete: device
+                        .create_semaphore(&semaphore_create_info, None)
+                        .unwrap(),
+                })
+                .collect();
+
+            let pipe_cache = PipeCache::load(&device, &phy_device);
 
             log::info!("Interface finished ...");
             Interface {
@@ -279,33 +439,35 @@ impl Interface {
 
                 device,
                 present_queue,
+                allocator: RefCell::new(MemAllocator::default()),
+                pipe_cache,
 
                 swapchain,
 
                 pool,
                 setup_cmd_buffer,
                 comp_cmd_buffer,
-                draw_cmd_buffer,
-
-                present_complete,
-                render_complete,
 
                 setup_cmd_fence,
                 comp_cmd_fence,
-                draw_cmd_fence,
+
+                frame_list,
             }
         }
     }
 
+    /// Acquire the next present image with the semaphores of
+    /// frame, the fence of frame has to be waited on already.
     pub fn swap_draw_next<Function: FnOnce(u32)>(
         &self,
+        frame: &FrameSync,
         function: Function,
     ) -> Result<bool, Box<dyn Error>> {
         unsafe {
             let next_image = self.swapchain.loader.acquire_next_image(
                 self.swapchain.swapchain,
                 std::u64::MAX,
-                self.present_complete,
+                frame.present_complete,
                 vk::Fence::null(),
             );
 
@@ -321,7 +483,7 @@ impl Interface {
 
             let present_info = vk::PresentInfoKHR {
                 wait_semaphore_count: 1,
-                p_wait_semaphores: &self.render_complete,
+                p_wait_semaphores: &frame.render_complete,
                 swapchain_count: 1,
                 p_swapchains: &self.swapchain.swapchain,
                 p_image_indices: &present_index,
@@ -370,10 +532,7 @@ impl Interface {
                 .expect("FENCE_RESET_FAILED");
 
             self.device
-                .reset_command_buffer(
-                    cmd_buffer,
-                    vk::CommandBufferResetFlags::RELEASE_RESOURCES,
-                )
+                .reset_command_buffer(cmd_buffer, vk::CommandBufferResetFlags::RELEASE_RESOURCES)
                 .expect("ERR_RESET_CMD_BUFFER");
 
             let cmd_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
@@ -389,8 +548,9 @@ impl Interface {
                 .end_command_buffer(cmd_buffer)
                 .expect("ERR_END_CMD_BUFFER");
 
+            // The present image is first written by the blit
             let submit_info = vk::SubmitInfo::builder()
-                .wait_dst_stage_mask(&[vk::PipelineStageFlags::BOTTOM_OF_PIPE])
+                .wait_dst_stage_mask(&[vk::PipelineStageFlags::TRANSFER])
                 .wait_semaphores(present_complete)
                 .command_buffers(&[cmd_buffer])
                 .signal_semaphores(render_complete)
@@ -402,6 +562,15 @@ impl Interface {
         }
     }
 
+    /// Block until the gpu is done with the last submit of frame.
+    pub fn wait_for_frame(&self, frame: &FrameSync) {
+        unsafe {
+            self.device
+                .wait_for_fences(&[frame.fence], true, u64::MAX)
+                .expect("DEVICE_LOST");
+        }
+    }
+
     pub fn wait_for_gpu(&self) -> Result<(), Box<dyn Error>> {
         unsafe { Ok(self.device.device_wait_idle().unwrap()) }
     }
@@ -410,15 +579,26 @@ impl Interface {
 impl Drop for Interface {
     fn drop(&mut self) {
         unsafe {
-            self.device.destroy_fence(self.draw_cmd_fence, None);
-            self.device.destroy_fence(self.setup_cmd_fence, None);
+            self.pipe_cache.save(&self.device);
+            self.pipe_cache.destroy(&self.device);
 
-            self.device.destroy_semaphore(self.present_complete, None);
-            self.device.destroy_semaphore(self.render_complete, None);
+            self.device.destroy_fence(self.setup_cmd_fence, None);
+            self.device.destroy_fence(self.comp_cmd_fence, None);
+
+            for frame in &self.frame_list {
+                self.device.destroy_fence(frame.fence, None);
+                self.device.destroy_semaphore(frame.present_complete, None);
+                self.device.destroy_semaphore(frame.render_complete, None);
+                self.device
+                    .free_command_buffers(self.pool, &[frame.cmd_buffer]);
+            }
 
-            self.device.free_command_buffers(self.pool, &[self.setup_cmd_buffer, self.draw_cmd_buffer]);
+            self.device
+                .free_command_buffers(self.pool, &[self.setup_cmd_buffer, self.comp_cmd_buffer]);
 
             self.device.destroy_command_pool(self.pool, None);
+
+            self.allocator.borrow_mut().destroy(&self.device);
         }
     }
 }
@@ -1,4 +1,6 @@
+pub mod alloc;
+pub mod cache;
 pub mod interface;
 pub mod phydev;
 pub mod surface;
-pub mod swapchain;
\ No newline at end of file
+pub mod swapchain;
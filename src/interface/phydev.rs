@@ -39,23 +39,26 @@ impl PhyDeviceGroup {
     /// if suitable.
     ///
     /// This function primarily checks if there is any graphic support
-    /// in the available queue family.
+    /// in the available queue family. Present support is only
+    /// required if there is a surface, headless needs none.
     ///
     /// *Add Other criteria for device selection here*
 
     pub fn is_device_suitable(
         info: &vk::QueueFamilyProperties,
-        surface: &SurfaceGroup,
+        surface: Option<&SurfaceGroup>,
         device: &vk::PhysicalDevice,
         index: usize,
     ) -> Option<(vk::PhysicalDevice, u32)> {
         unsafe {
             // Check for graphic queue support
             let supported = info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                && surface
-                    .loader
-                    .get_physical_device_surface_support(*device, index as u32, surface.surface)
-                    .unwrap();
+                && surface.is_none_or(|surface| {
+                    surface
+                        .loader
+                        .get_physical_device_surface_support(*device, index as u32, surface.surface)
+                        .unwrap()
+                });
 
             // Return device and index if suitable
             if supported {
@@ -76,7 +79,11 @@ impl PhyDeviceGroup {
     /// If not suitable device is found, we throw an exception,
     /// because then the application won't be able to run.
 
-    pub fn get_suitable_phy_device(&self, instance: &Instance, surface: &SurfaceGroup) -> Self {
+    pub fn get_suitable_phy_device(
+        &self,
+        instance: &Instance,
+        surface: Option<&SurfaceGroup>,
+    ) -> Self {
         unsafe {
             let mut result = self.clone();
 
@@ -62,6 +62,33 @@ impl SurfaceGroup {
         }
     }
 
+    /// Surface group without surface for headless rendering.
+    /// Format and resolution describe the offscreen target.
+    pub fn headless(entry: &Entry, instance: &Instance, extent: vk::Extent2D) -> Self {
+        let loader = Surface::new(entry, instance);
+
+        Self {
+            loader,
+            surface: vk::SurfaceKHR::null(),
+
+            format: vk::SurfaceFormatKHR {
+                format: vk::Format::R8G8B8A8_UNORM,
+                color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            },
+            capa: Default::default(),
+
+            swap_img_count: 0,
+
+            render_res: extent,
+            surface_res: extent,
+
+            pre_transform: Default::default(),
+
+            present_mode_list: Default::default(),
+            present_mode: Default::default(),
+        }
+    }
+
     /// Set other param. of surface group. This function will
     /// gather information about surface format, surface capability,
     /// swapchain image count, surface resolution, surface pre transform and
@@ -89,7 +116,7 @@ impl SurfaceGroup {
                 .loader
                 .get_physical_device_surface_capabilities(phy_device.device, result.surface)
                 .unwrap();
-            
+
             log::info!("Getting info about swapchain image count ...");
             result.swap_img_count = result.capa.min_image_count + 1;
             if result.capa.max_image_count > 0
@@ -100,8 +127,16 @@ impl SurfaceGroup {
             log::info!("Swapchain image count is [ {} ]...", result.swap_img_count);
 
             result = result.get_surface_res(&window, pref);
-            log::info!("Surface resolution is [ {} x {} ]...", result.surface_res.width, result.surface_res.height);
-            log::info!("Render resolution is [ {} x {} ]...", result.render_res.width, result.render_res.height);
+            log::info!(
+                "Surface resolution is [ {} x {} ]...",
+                result.surface_res.width,
+                result.surface_res.height
+            );
+            log::info!(
+                "Render resolution is [ {} x {} ]...",
+                result.render_res.width,
+                result.render_res.height
+            );
 
             result.pre_transform = if result
                 .capa
@@ -126,7 +161,10 @@ impl SurfaceGroup {
                 .find(|&mode| mode == pref.pref_present_mode)
                 // Else use present mode fifo
                 .unwrap_or(vk::PresentModeKHR::FIFO);
-            log::info!("Selected present mode is [ {} ]...", result.present_mode.as_raw());
+            log::info!(
+                "Selected present mode is [ {} ]...",
+                result.present_mode.as_raw()
+            );
 
             result
         }
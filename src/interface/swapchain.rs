@@ -44,7 +44,12 @@ impl SwapchainGroup {
                 .image_color_space(surface.format.color_space)
                 .image_format(surface.format.format)
                 .image_extent(surface.surface_res)
-                .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+                .image_usage(
+                    vk::ImageUsageFlags::COLOR_ATTACHMENT
+                        | vk::ImageUsageFlags::TRANSFER_DST
+                        // Screenshots read the present image back
+                        | (surface.capa.supported_usage_flags & vk::ImageUsageFlags::TRANSFER_SRC),
+                )
                 .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
                 .pre_transform(surface.pre_transform)
                 .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
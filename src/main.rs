@@ -1,19 +1,33 @@
 use std::{
     borrow::BorrowMut,
+    error::Error,
     io::Write,
-    mem, thread,
+    thread,
     time::{Duration, Instant},
 };
 
 use ash::vk;
-use cgmath::{Vector2, num_traits::Pow};
+use cgmath::Vector2;
 use env_logger::fmt::{Color, Formatter};
-use input::Input;
+use golden::{GoldenBackend, GoldenMode, GoldenTolerance};
+use input::{Action, Input};
 use interface::interface::Interface;
 use log::Record;
-use nalgebra_glm::{cross, normalize, vec3_to_vec4, Vec2};
-use pipe::engine::Engine;
-use tree::octree::Octree;
+use nalgebra_glm::{cross, normalize, vec3_to_vec4, Vec2, Vec4};
+use pipe::{
+    atlas::BrickLayout,
+    capture::Capture,
+    engine::{Engine, RenderMode},
+    reload::ShaderWatcher,
+    shader,
+    software::SoftwareRenderer,
+};
+use tree::{
+    brick::{BrickMap, BRICK_SIZE},
+    edt::DistanceMethod,
+    jfa::{jfa_step_list, JfaVariant},
+    octree::Octree,
+};
 use uniform::Uniform;
 use winit::{
     dpi::PhysicalPosition,
@@ -23,6 +37,7 @@ use winit::{
 };
 
 mod bit;
+mod golden;
 mod input;
 mod interface;
 mod pipe;
@@ -30,14 +45,17 @@ mod tree;
 mod uniform;
 mod vector;
 
-const DEFAULT_STORAGE_BUFFER_SIZE: u64 = 1342177280;
-const DEFAULT_UNIFORM_BUFFER_SIZE: u64 = 16384;
+const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+// Distance in front of the camera, where voxels are placed and removed
+const EDIT_DISTANCE: f32 = 4.0;
 
 pub struct RenderState {
     pub out_of_date: bool,
     pub idle: bool,
 
     pub frame_time: Duration,
+    // Frame graph on the gpu, averaged over the profile window
+    pub gpu_time: Duration,
 }
 
 // Complete Render Pipeline
@@ -53,6 +71,8 @@ pub struct Render {
 
     interface: Interface,
     graphic_pipe: Engine,
+
+    shader_watcher: Option<ShaderWatcher>,
 }
 
 // General Setting
@@ -70,6 +90,42 @@ pub struct Pref {
     pub render_res: vk::Extent2D,
 
     pub mov_speed: f32,
+
+    // Frames the cpu records ahead of the gpu, at least 1
+    pub frames_in_flight: usize,
+
+    // Load octree from json dump instead of test scene
+    pub scene_path: Option<String>,
+    // Write dot and json dump of the tree (or the subtree at dump pos)
+    pub dump_path: Option<String>,
+    pub dump_pos: Option<Vec4>,
+
+    // Storage of the bricks on the gpu, 2D atlas or 3D texture
+    pub brick_layout: BrickLayout,
+    // Build the brick distance fields on the cpu instead of the gpu
+    pub cpu_distance_field: bool,
+    pub distance_method: DistanceMethod,
+    // Extra passes of the jump flood
+    pub jfa_variant: JfaVariant,
+    // Log the error of JFA against the exact distance at startup
+    pub distance_report: bool,
+
+    // Render a single frame without window to this png or exr file
+    pub headless_path: Option<String>,
+    // Same as headless, but rendered on the cpu without vulkan
+    pub software_path: Option<String>,
+
+    // Render the golden cases and check or update them, then exit
+    pub golden_mode: Option<GoldenMode>,
+    pub golden_backend: GoldenBackend,
+
+    // Compile every shader without a device, then exit
+    pub check_shaders: bool,
+    // Recreate pipelines when files in shader/ change
+    pub hot_reload: bool,
+
+    // Renderer of the frame, switched at runtime with MODE
+    pub render_mode: RenderMode,
 }
 
 fn main() {
@@ -92,19 +148,45 @@ fn main() {
     env_logger::builder().format(log_format).init();
 
     log::info!("Starting Application ...");
+    let pref = Render::get_pref();
+
+    if pref.check_shaders {
+        if !shader::check_shaders() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(mode) = pref.golden_mode {
+        let passed = Render::run_golden(&pref, mode).expect("ERR_RUN_GOLDEN");
+
+        if !passed {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(path) = pref.software_path.clone() {
+        Render::render_software(&pref, &path).expect("ERR_RENDER_SOFTWARE");
+        return;
+    }
+
+    if let Some(path) = pref.headless_path.clone() {
+        Render::render_headless(&pref, &path).expect("ERR_RENDER_HEADLESS");
+        return;
+    }
+
     thread::spawn(|| loop {});
 
-    let mut render = Render::get_render();
+    let mut render = Render::get_render(pref);
     render.execute(Instant::now());
 
     render.graphic_pipe.drop_graphic(&render.interface);
 }
 
 impl Render {
-    pub fn get_render() -> Render {
-        let event_loop = EventLoop::new();
-
-        let pref = Pref {
+    pub fn get_pref() -> Pref {
+        Pref {
             pref_present_mode: vk::PresentModeKHR::IMMEDIATE,
             img_filter: vk::Filter::LINEAR,
             img_scale: 1.0,
@@ -124,65 +206,94 @@ impl Render {
             },
 
             mov_speed: 0.05,
-        };
+
+            frames_in_flight: std::env::args()
+                .skip_while(|arg| arg != "--frames-in-flight")
+                .nth(1)
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(DEFAULT_FRAMES_IN_FLIGHT)
+                .max(1),
+
+            scene_path: None,
+            dump_path: None,
+            dump_pos: None,
+
+            brick_layout: if std::env::args().any(|arg| arg == "--brick-3d") {
+                BrickLayout::Texture3D
+            } else {
+                BrickLayout::Atlas2D
+            },
+            cpu_distance_field: std::env::args().any(|arg| arg == "--cpu-sdf"),
+            distance_method: if std::env::args().any(|arg| arg == "--sdf-exact") {
+                DistanceMethod::Exact
+            } else {
+                DistanceMethod::Jfa
+            },
+            jfa_variant: if std::env::args().any(|arg| arg == "--jfa-plus-2") {
+                JfaVariant::JfaPlusTwo
+            } else {
+                JfaVariant::OnePlusJfa
+            },
+            distance_report: std::env::args().any(|arg| arg == "--sdf-report"),
+
+            headless_path: std::env::args()
+                .skip_while(|arg| arg != "--headless")
+                .nth(1),
+            software_path: std::env::args()
+                .skip_while(|arg| arg != "--software")
+                .nth(1),
+
+            golden_mode: if std::env::args().any(|arg| arg == "--golden-update") {
+                Some(GoldenMode::Update)
+            } else if std::env::args().any(|arg| arg == "--golden") {
+                Some(GoldenMode::Check)
+            } else {
+                None
+            },
+            golden_backend: if std::env::args().any(|arg| arg == "--golden-vulkan") {
+                GoldenBackend::Vulkan
+            } else {
+                GoldenBackend::Software
+            },
+
+            check_shaders: std::env::args().any(|arg| arg == "--check-shaders"),
+            hot_reload: cfg!(debug_assertions) || std::env::args().any(|arg| arg == "--hot-reload"),
+
+            render_mode: std::env::args()
+                .skip_while(|arg| arg != "--render-mode")
+                .nth(1)
+                .and_then(|name| RenderMode::parse(&name))
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn get_render(pref: Pref) -> Render {
+        let event_loop = EventLoop::new();
 
         let state = RenderState {
             out_of_date: false,
             idle: false,
             frame_time: Duration::ZERO,
+            gpu_time: Duration::ZERO,
         };
 
-        let mut octree = Octree::default();
+        let octree = Self::get_octree(&pref);
 
         let input = Input::new();
         let mut uniform = Uniform::new(octree.root_span);
 
-        octree.test_scene();
-
         let interface = Interface::init(&event_loop, &pref);
         uniform.res = Vec2::new(
             interface.surface.surface_res.width as f32,
             interface.surface.surface_res.height as f32,
         );
 
-        let mut graphic_pipe = Engine::create_base(&interface, &uniform, &octree);
-        // graphic_pipe = graphic_pipe.create_compute(&interface, &uniform, &octree);
-        graphic_pipe = graphic_pipe
-            .create_jfa_comp(&interface, &uniform, &octree)
-            .create_graphic(&interface, &uniform, &octree);
-
-        graphic_pipe.run_jfa_iteration(
-            &interface,
-            vk::Extent3D {
-                width: 4096,
-                height: 4096,
-                depth: 1,
-            },
-            1,
-        );
+        let graphic_pipe = Self::create_engine(&interface, &pref, &uniform, &octree);
 
-        for idx in 0..4 {
-            graphic_pipe.run_jfa_iteration(
-                &interface,
-                vk::Extent3D {
-                    width: 4096,
-                    height: 4096,
-                    depth: 1,
-                },
-                (8.0 * 0.5.pow(idx)) as u32,
-            );
-        }
+        let shader_watcher = pref
+            .hot_reload
+            .then(|| ShaderWatcher::new(shader::SHADER_DIR.as_ref()));
 
-        graphic_pipe.run_jfa_iteration(
-            &interface,
-            vk::Extent3D {
-                width: 4096,
-                height: 4096,
-                depth: 1,
-            },
-            1,
-        );
-        
         Render {
             state,
             event_loop,
@@ -192,6 +303,185 @@ impl Render {
             input,
             interface,
             graphic_pipe,
+            shader_watcher,
+        }
+    }
+
+    pub fn get_octree(pref: &Pref) -> Octree {
+        let mut octree = match &pref.scene_path {
+            Some(path) => Octree::load_dump(path).expect("ERR_LOAD_SCENE"),
+            None => {
+                let mut octree = Octree::default();
+                octree.test_scene();
+                octree
+            }
+        };
+
+        // Whole tree is uploaded at startup, nothing is dirty yet
+        octree.take_dirty();
+
+        if let Some(path) = &pref.dump_path {
+            octree
+                .save_dump(path, pref.dump_pos)
+                .expect("ERR_DUMP_OCTREE");
+        }
+
+        octree
+    }
+
+    /// Engine with the pipelines of the render mode and the
+    /// distance fields built. Other modes are created on switching.
+    pub fn create_engine(
+        interface: &Interface,
+        pref: &Pref,
+        uniform: &Uniform,
+        octree: &Octree,
+    ) -> Engine {
+        let mut graphic_pipe = Engine::create_base(interface, pref, uniform, octree);
+        // graphic_pipe = graphic_pipe.create_compute(&interface, &uniform, &octree);
+        graphic_pipe = graphic_pipe
+            .create_jfa_comp(interface, uniform, octree)
+            .create_edt_comp(interface)
+            .create_sdf_mip_comp(interface)
+            .create_mode(interface, uniform, octree, pref.render_mode);
+
+        // Distance field is already built on the cpu otherwise
+        if !pref.cpu_distance_field {
+            match pref.distance_method {
+                DistanceMethod::Jfa => graphic_pipe.build_distance_field(
+                    interface,
+                    pref.brick_layout.extent(),
+                    BRICK_SIZE,
+                    pref.jfa_variant,
+                ),
+                DistanceMethod::Exact => graphic_pipe.run_edt(interface),
+            }
+
+            graphic_pipe.build_sdf_pyramid(interface);
+
+            if pref.distance_report {
+                graphic_pipe.gpu_distance_report(interface, pref);
+            }
+        }
+
+        graphic_pipe
+    }
+
+    /// Camera of the headless renders, looking along the x axis.
+    pub fn get_headless_uniform(octree: &Octree, extent: vk::Extent2D) -> Uniform {
+        let mut uniform = Uniform::new(octree.root_span);
+        uniform.apply_resolution(extent);
+        // Look dir is only set by the mouse otherwise
+        uniform.move_mouse(Vec2::zeros());
+        uniform.update_uniform(Duration::ZERO);
+
+        uniform
+    }
+
+    /// Render a single frame at the render resolution without
+    /// window and write it to path, PNG or EXR by extension.
+    pub fn render_headless(pref: &Pref, path: &str) -> Result<(), Box<dyn Error>> {
+        let octree = Self::get_octree(pref);
+        let uniform = Self::get_headless_uniform(&octree, pref.render_res);
+
+        Self::capture_headless(pref, &octree, &uniform)?.save(path)
+    }
+
+    /// Single frame at the resolution of the uniform, with an
+    /// engine of its own.
+    pub fn capture_headless(
+        pref: &Pref,
+        octree: &Octree,
+        uniform: &Uniform,
+    ) -> Result<Capture, Box<dyn Error>> {
+        let extent = vk::Extent2D {
+            width: uniform.res.x as u32,
+            height: uniform.res.y as u32,
+        };
+
+        let interface = Interface::init_headless(pref, extent);
+        let graphic_pipe = Self::create_engine(&interface, pref, uniform, octree);
+
+        graphic_pipe.write_uniform(0, uniform);
+
+        let capture = graphic_pipe.render_offscreen(&interface, pref.render_mode);
+
+        interface.wait_for_gpu()?;
+        graphic_pipe.drop_graphic(&interface);
+
+        Ok(capture)
+    }
+
+    /// Same frame as render_headless, but with the software
+    /// renderer. Bricks always get their distance field on the cpu.
+    pub fn render_software(pref: &Pref, path: &str) -> Result<(), Box<dyn Error>> {
+        let octree = Self::get_octree(pref);
+        let uniform = Self::get_headless_uniform(&octree, pref.render_res);
+
+        Self::capture_software(pref, &octree, &uniform).save(path)
+    }
+
+    pub fn capture_software(pref: &Pref, octree: &Octree, uniform: &Uniform) -> Capture {
+        let mut brick_map = BrickMap::new(octree);
+        match pref.distance_method {
+            DistanceMethod::Jfa => {
+                brick_map.jump_flood(&jfa_step_list(BRICK_SIZE, pref.jfa_variant))
+            }
+            DistanceMethod::Exact => brick_map.exact_distance(),
+        }
+
+        SoftwareRenderer::new(octree, &brick_map).render(uniform)
+    }
+
+    /// Render the golden cases with the chosen backend.
+    pub fn run_golden(pref: &Pref, mode: GoldenMode) -> Result<bool, Box<dyn Error>> {
+        log::info!(
+            "Running golden cases with {:?} backend in {:?} mode ...",
+            pref.golden_backend,
+            mode
+        );
+
+        golden::run_golden(
+            mode,
+            &GoldenTolerance::default(),
+            |octree, uniform| match pref.golden_backend {
+                GoldenBackend::Software => Ok(Self::capture_software(pref, octree, uniform)),
+                GoldenBackend::Vulkan => Self::capture_headless(pref, octree, uniform),
+            },
+        )
+    }
+
+    /// Place or remove a voxel in front of the camera.
+    pub fn edit_octree(input: &mut Input, uniform: &Uniform, octree: &mut Octree) {
+        let place = input.take_pressed(Action::PLACE);
+        let remove = input.take_pressed(Action::REMOVE);
+
+        let edit_pos = uniform.cam_pos + normalize(&uniform.look_dir) * EDIT_DISTANCE;
+        let in_root = edit_pos.xyz().min() >= 0.0 && edit_pos.xyz().max() < octree.root_span;
+
+        if place && in_root {
+            octree.insert_node(edit_pos);
+        }
+
+        if remove && in_root {
+            octree.remove_node(edit_pos);
+        }
+    }
+
+    /// Draw with the next render mode from the next frame on, its
+    /// pipelines are created on first use.
+    pub fn switch_render_mode(
+        graphic_pipe: &mut Engine,
+        pref: &mut Pref,
+        interface: &Interface,
+        uniform: &Uniform,
+        octree: &Octree,
+    ) {
+        pref.render_mode = pref.render_mode.next();
+        log::info!("Switching to {:?} render mode ...", pref.render_mode);
+
+        if !graphic_pipe.is_created(pref.render_mode) {
+            *graphic_pipe = graphic_pipe.create_mode(interface, uniform, octree, pref.render_mode);
         }
     }
 
@@ -231,9 +521,9 @@ impl Render {
                         ..
                     } => {
                         self.input.handle_mouse_input(position, &mut self.uniform);
-                        self.interface.window.set_cursor_visible(false);
+                        self.interface.window().set_cursor_visible(false);
                         self.interface
-                            .window
+                            .window()
                             .set_cursor_position(PhysicalPosition::new(
                                 self.uniform.res.x / 2.0,
                                 self.uniform.res.y / 2.0,
@@ -249,7 +539,7 @@ impl Render {
                     // Adjust Surface and Draw
                     {
                         if self.state.out_of_date {
-                            let dim = self.interface.window.inner_size();
+                            let dim = self.interface.window().inner_size();
                             if dim.width > 0 && dim.height > 0 {
                                 // Not Minimized
                                 self.graphic_pipe.recreate_swapchain(
@@ -266,27 +556,66 @@ impl Render {
                             }
                         } else {
                             // Update Octree
-                            // self.octree = Octree::default();
-                            // self.octree.test_scene();
-                            // self.graphic_pipe.update_buffer(&self.interface, self.graphic_pipe.octree_buffer_memory, &self.octree.data.clone(), );
+                            Self::edit_octree(&mut self.input, &self.uniform, &mut self.octree);
+                            self.graphic_pipe.update_octree(
+                                &self.interface,
+                                &mut self.octree,
+                                &self.pref,
+                            );
+
+                            self.graphic_pipe.stream_bricks(&self.uniform);
+
+                            if self.input.take_pressed(Action::MODE) {
+                                Self::switch_render_mode(
+                                    &mut self.graphic_pipe,
+                                    &mut self.pref,
+                                    &self.interface,
+                                    &self.uniform,
+                                    &self.octree,
+                                );
+                            }
 
-                            // Update Uniform
+                            // Swap in pipelines of edited shaders between frames
+                            if let Some(shader_watcher) = self.shader_watcher.as_mut() {
+                                let changed_list = shader_watcher.poll();
+                                if !changed_list.is_empty() {
+                                    self.graphic_pipe.reload_shaders(
+                                        &self.interface,
+                                        &self.pref,
+                                        &changed_list,
+                                    );
+                                }
+                            }
+
+                            // Update Uniform, written into the slice of the frame by draw_graphic
                             self.uniform.update_uniform(app_start.elapsed());
 
-                            self.graphic_pipe.uniform_buffer.rewrite_mem(
-                                &self.interface,
-                                mem::align_of::<Uniform>() as u64,
-                                mem::size_of::<Uniform>() as u64,
-                                &[self.uniform],
-                            );
+                            let screenshot = self.input.take_pressed(Action::SCREENSHOT);
 
                             // Draw and capture FrameTime
                             let start = Instant::now();
                             self.state.out_of_date = self
                                 .graphic_pipe
-                                .draw_graphic(&self.interface, &self.pref, &self.uniform)
+                                .draw_graphic(
+                                    &self.interface,
+                                    &self.pref,
+                                    &self.uniform,
+                                    screenshot,
+                                )
                                 .expect("RENDER_FAILED");
                             self.state.frame_time = start.elapsed();
+                            self.state.gpu_time = self.graphic_pipe.gpu_frame_time();
+
+                            if screenshot {
+                                match self
+                                    .graphic_pipe
+                                    .read_capture(&self.interface)
+                                    .save_timestamped("screenshot")
+                                {
+                                    Ok(path) => log::info!("Screenshot saved to {} ...", path),
+                                    Err(error) => log::info!("Screenshot failed: {}", error),
+                                }
+                            }
 
                             if self.input.key_down[VirtualKeyCode::W as usize] == true {
                                 self.uniform.velocity +=
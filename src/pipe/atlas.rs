@@ -0,0 +1,288 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use ash::vk;
+use nalgebra_glm::{normalize, Vec3};
+
+use crate::{
+    tree::{
+        brick::{BrickMap, BRICK_BYTES, BRICK_SIZE},
+        octree::{BRICK_TEXTURE_RES, BRICK_TEXTURE_RES_3D},
+    },
+    uniform::Uniform,
+};
+
+// Bricks which are placed into the atlas per frame
+pub const MAX_STREAM_PER_FRAME: usize = 64;
+// Frames a brick has to be unseen until it can be evicted
+pub const EVICT_AFTER_FRAMES: u64 = 120;
+// Bricks further away than this are not requested
+pub const STREAM_DISTANCE: f32 = 192.0;
+// Cosine of the half angle of the cone bricks are requested in
+pub const STREAM_CONE_COS: f32 = 0.5;
+
+// Atlas slot written to location info, if the brick is not resident
+pub const MISSING_SLOT: u32 = u32::MAX;
+
+/// How bricks are stored in the brick texture, chosen at startup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BrickLayout {
+    // Bricks are flattened into columns of z slices in a 2D image
+    #[default]
+    Atlas2D,
+    // Bricks are placed in a grid inside a 3D image, which keeps
+    // hardware filtering inside a brick working
+    Texture3D,
+}
+
+/// Slot allocator for the brick texture. Bricks of the BrickMap
+/// are requested when the camera sees them, placed into free
+/// slots and evicted again, when they weren't seen for a while.
+#[derive(Clone)]
+pub struct BrickAtlas {
+    pub capacity: usize,
+    pub frame: u64,
+
+    pub free_list: Vec<u32>,
+
+    // Atlas slot -> brick idx
+    pub slot_list: Vec<Option<usize>>,
+    // Atlas slot -> frame the brick in it was last seen
+    pub last_used: Vec<u64>,
+    // Brick idx -> atlas slot
+    pub resident_map: HashMap<usize, u32>,
+
+    pub request_queue: VecDeque<usize>,
+    pub queued_set: HashSet<usize>,
+}
+
+impl BrickLayout {
+    pub fn extent(&self) -> vk::Extent3D {
+        match self {
+            BrickLayout::Atlas2D => vk::Extent3D {
+                width: BRICK_TEXTURE_RES,
+                height: BRICK_TEXTURE_RES,
+                depth: 1,
+            },
+            BrickLayout::Texture3D => vk::Extent3D {
+                width: BRICK_TEXTURE_RES_3D,
+                height: BRICK_TEXTURE_RES_3D,
+                depth: BRICK_TEXTURE_RES_3D,
+            },
+        }
+    }
+
+    pub fn image_type(&self) -> vk::ImageType {
+        match self {
+            BrickLayout::Atlas2D => vk::ImageType::TYPE_2D,
+            BrickLayout::Texture3D => vk::ImageType::TYPE_3D,
+        }
+    }
+
+    pub fn view_type(&self) -> vk::ImageViewType {
+        match self {
+            BrickLayout::Atlas2D => vk::ImageViewType::TYPE_2D,
+            BrickLayout::Texture3D => vk::ImageViewType::TYPE_3D,
+        }
+    }
+
+    /// Extent of one brick inside the image.
+    pub fn brick_extent(&self) -> vk::Extent3D {
+        match self {
+            BrickLayout::Atlas2D => vk::Extent3D {
+                width: BRICK_SIZE,
+                height: BRICK_SIZE * BRICK_SIZE,
+                depth: 1,
+            },
+            BrickLayout::Texture3D => vk::Extent3D {
+                width: BRICK_SIZE,
+                height: BRICK_SIZE,
+                depth: BRICK_SIZE,
+            },
+        }
+    }
+
+    /// Number of bricks the image can hold.
+    pub fn capacity(&self) -> usize {
+        let extent = self.extent();
+        let brick_extent = self.brick_extent();
+
+        ((extent.width / brick_extent.width)
+            * (extent.height / brick_extent.height)
+            * (extent.depth / brick_extent.depth)) as usize
+    }
+
+    /// Texel of the first voxel of the brick in slot. Same as
+    /// slot_to_px in the shaders.
+    pub fn slot_to_offset(&self, slot: u32) -> vk::Offset3D {
+        match self {
+            BrickLayout::Atlas2D => {
+                let length = BRICK_SIZE * BRICK_SIZE * slot;
+
+                vk::Offset3D {
+                    x: ((length / BRICK_TEXTURE_RES) * BRICK_SIZE) as i32,
+                    y: (length % BRICK_TEXTURE_RES) as i32,
+                    z: 0,
+                }
+            }
+            BrickLayout::Texture3D => {
+                let grid = BRICK_TEXTURE_RES_3D / BRICK_SIZE;
+
+                vk::Offset3D {
+                    x: ((slot % grid) * BRICK_SIZE) as i32,
+                    y: (((slot / grid) % grid) * BRICK_SIZE) as i32,
+                    z: ((slot / (grid * grid)) * BRICK_SIZE) as i32,
+                }
+            }
+        }
+    }
+
+    /// Copy of one brick from the staging buffer into its slot.
+    /// Bricks are packed in the staging buffer by slot, the voxel
+    /// order of a brick matches the texel order of both layouts.
+    pub fn copy_region(&self, slot: u32) -> vk::BufferImageCopy {
+        let brick_extent = self.brick_extent();
+
+        vk::BufferImageCopy::builder()
+            .buffer_offset((slot as usize * BRICK_BYTES) as u64)
+            .buffer_row_length(brick_extent.width)
+            .buffer_image_height(brick_extent.height)
+            .image_subresource(
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .layer_count(1)
+                    .build(),
+            )
+            .image_offset(self.slot_to_offset(slot))
+            .image_extent(brick_extent)
+            .build()
+    }
+}
+
+impl BrickAtlas {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frame: 0,
+
+            // Reverse, so that slots are handed out in ascending order
+            free_list: (0..capacity as u32).rev().collect(),
+
+            slot_list: vec![None; capacity],
+            last_used: vec![0; capacity],
+            resident_map: HashMap::new(),
+
+            request_queue: VecDeque::new(),
+            queued_set: HashSet::new(),
+        }
+    }
+
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    pub fn slot_of(&self, brick_idx: usize) -> Option<u32> {
+        self.resident_map.get(&brick_idx).copied()
+    }
+
+    /// Mark brick as seen in this frame. Bricks which are
+    /// not resident are put into the request queue.
+    pub fn touch(&mut self, brick_idx: usize) {
+        match self.resident_map.get(&brick_idx) {
+            Some(&slot) => self.last_used[slot as usize] = self.frame,
+            None => {
+                if self.queued_set.insert(brick_idx) {
+                    self.request_queue.push_back(brick_idx);
+                }
+            }
+        }
+    }
+
+    /// Request every brick of the brick map, which lies in the
+    /// view cone of the camera.
+    pub fn request_visible(&mut self, brick_map: &BrickMap, uniform: &Uniform) {
+        let cam_pos = uniform.cam_pos.xyz();
+        let look_dir = normalize(&uniform.look_dir.xyz());
+
+        brick_map
+            .brick_list
+            .iter()
+            .enumerate()
+            .for_each(|(brick_idx, brick)| {
+                let center = (brick.coord.cast::<f32>() + Vec3::repeat(0.5)) * brick_map.brick_span;
+                let to_brick = center - cam_pos;
+                let dist = to_brick.norm();
+
+                let in_cone =
+                    dist < brick_map.brick_span || to_brick.dot(&look_dir) / dist > STREAM_CONE_COS;
+
+                if dist < STREAM_DISTANCE && in_cone {
+                    self.touch(brick_idx);
+                }
+            });
+    }
+
+    /// Get free slot or evict the least recently used brick,
+    /// which wasn't seen for EVICT_AFTER_FRAMES.
+    fn alloc_slot(&mut self) -> Option<u32> {
+        if let Some(slot) = self.free_list.pop() {
+            return Some(slot);
+        }
+
+        let (slot, last_used) = self
+            .last_used
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, &last_used)| last_used)?;
+
+        if last_used + EVICT_AFTER_FRAMES > self.frame {
+            return None;
+        }
+
+        if let Some(brick_idx) = self.slot_list[slot].take() {
+            self.resident_map.remove(&brick_idx);
+        }
+
+        Some(slot as u32)
+    }
+
+    /// Place up to max_count requested bricks into the atlas.
+    /// Returns (brick idx, atlas slot) of every placed brick,
+    /// which then has to be uploaded.
+    pub fn process_requests(&mut self, max_count: usize) -> Vec<(usize, u32)> {
+        let mut placed_list = vec![];
+
+        while placed_list.len() < max_count {
+            let brick_idx = match self.request_queue.front() {
+                Some(&brick_idx) => brick_idx,
+                None => break,
+            };
+
+            let slot = match self.alloc_slot() {
+                Some(slot) => slot,
+                // Atlas is full with recently used bricks, keep waiting
+                None => break,
+            };
+
+            self.request_queue.pop_front();
+            self.queued_set.remove(&brick_idx);
+
+            self.slot_list[slot as usize] = Some(brick_idx);
+            self.last_used[slot as usize] = self.frame;
+            self.resident_map.insert(brick_idx, slot);
+
+            placed_list.push((brick_idx, slot));
+        }
+
+        placed_list
+    }
+
+    pub fn resident_count(&self) -> usize {
+        self.resident_map.len()
+    }
+}
+
+impl Default for BrickAtlas {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
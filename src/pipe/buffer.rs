@@ -2,75 +2,94 @@ use std::ffi::c_void;
 
 use ash::{util::Align, vk, Device};
 
-use crate::interface::{interface::Interface, phydev::PhyDeviceGroup};
+use crate::interface::{
+    alloc::{Allocation, ResourceKind},
+    interface::Interface,
+};
 
 #[derive(Clone)]
 pub struct BufferSet {
     pub buffer: vk::Buffer,
+    // Size the buffer was created with, the memory can be larger
+    pub size: u64,
 
-    pub mem: vk::DeviceMemory,
+    pub alloc: Allocation,
     pub mem_req: vk::MemoryRequirements,
+    pub mem_flags: vk::MemoryPropertyFlags,
 
     pub usage: vk::BufferUsageFlags,
     pub sharing_mode: vk::SharingMode,
 }
 
 impl BufferSet {
-    pub fn create_memory<Type: Copy>(
+    /// Sub-allocate and bind memory with mem_flags, its content
+    /// is undefined until written.
+    pub fn allocate_memory(
         &self,
-        device: &Device,
-        phy_device: &PhyDeviceGroup,
-        alignment: u64,
-        size: u64,
-        data: &[Type],
+        interface: &Interface,
+        mem_flags: vk::MemoryPropertyFlags,
     ) -> Self {
         unsafe {
             let mut result = self.clone();
+            result.mem_flags = mem_flags;
 
             // Get MemoryRequirement
-            result.mem_req = device.get_buffer_memory_requirements(result.buffer);
-
-            let mem_idx = phy_device
-                .find_memorytype_index(
-                    &result.mem_req,
-                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-                )
-                .expect("ERR_INDEX_BUFFER_MEM_INDEX");
+            result.mem_req = interface
+                .device
+                .get_buffer_memory_requirements(result.buffer);
+
+            result.alloc = interface.allocator.borrow_mut().alloc(
+                &interface.device,
+                &interface.phy_device,
+                &result.mem_req,
+                mem_flags,
+                ResourceKind::Buffer,
+                None,
+            );
+
+            interface
+                .device
+                .bind_buffer_memory(result.buffer, result.alloc.mem, result.alloc.offset)
+                .unwrap();
 
-            // Prepare Allocation
-            let allocate_info = vk::MemoryAllocateInfo {
-                allocation_size: result.mem_req.size,
-                memory_type_index: mem_idx,
+            result
+        }
+    }
 
-                ..Default::default()
-            };
+    /// Host visible memory, filled with data right away. Used for
+    /// staging, readback and data rewritten every frame.
+    pub fn create_memory<Type: Copy>(
+        &self,
+        interface: &Interface,
+        alignment: u64,
+        size: u64,
+        data: &[Type],
+    ) -> Self {
+        let result = self.allocate_memory(
+            interface,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        );
 
-            // Create MemoryObject
-            result.mem = device.allocate_memory(&allocate_info, None).unwrap();
-
-            // Prepare MemoryCopy
-            let buffer_ptr: *mut c_void = device
-                .map_memory(
-                    result.mem,
-                    0,
-                    result.mem_req.size,
-                    vk::MemoryMapFlags::empty(),
-                )
-                .unwrap();
+        // Align memory
+        let mut aligned_slice =
+            unsafe { Align::new(result.alloc.ptr as *mut c_void, alignment, size) };
 
-            // Align memory
-            let mut aligned_slice = Align::new(buffer_ptr, alignment, size);
+        // Copy and finish Memory
+        aligned_slice.copy_from_slice(data);
 
-            // Copy and finish Memory
-            aligned_slice.copy_from_slice(&data);
-            device.unmap_memory(result.mem);
+        result
+    }
 
-            device
-                .bind_buffer_memory(result.buffer, result.mem, 0)
-                .unwrap();
+    /// Device local memory, which can't be mapped. It is filled
+    /// through the staging ring of the upload queue, so usage has
+    /// to include TRANSFER_DST.
+    pub fn create_device_memory(&self, interface: &Interface) -> Self {
+        self.allocate_memory(interface, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+    }
 
-            result
-        }
+    pub fn is_host_visible(&self) -> bool {
+        self.mem_flags
+            .contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
     }
 
     /// Create new buffer set object with alignment, size in storage,
@@ -86,6 +105,7 @@ impl BufferSet {
         unsafe {
             let mut result = Self::default();
 
+            result.size = buffer_size;
             result.usage = usage;
             result.sharing_mode = sharing_mode;
 
@@ -105,36 +125,40 @@ impl BufferSet {
         }
     }
 
-    pub fn rewrite_mem<Type: Copy>(
-        &self,
-        interface: &Interface,
-        alignment: u64,
-        size: u64,
-        data: &[Type],
-    ) {
-        unsafe {
-            let buffer_ptr = interface
-                .device
-                .map_memory(
-                    self.mem,
-                    0,
-                    std::mem::size_of_val(data) as u64,
-                    vk::MemoryMapFlags::empty(),
-                )
-                .unwrap();
+    /// Memory has to be host visible, it stays mapped.
+    pub fn rewrite_mem<Type: Copy>(&self, alignment: u64, size: u64, data: &[Type]) {
+        // Align memory
+        let mut aligned_slice =
+            unsafe { Align::new(self.alloc.ptr as *mut c_void, alignment, size) };
 
-            // Align memory
-            let mut aligned_slice = Align::new(buffer_ptr, alignment, size);
+        aligned_slice.copy_from_slice(data);
+    }
 
-            aligned_slice.copy_from_slice(&data);
-            interface.device.unmap_memory(self.mem);
+    /// Write data at byte offset and leave the rest of the
+    /// buffer untouched. Memory has to be host visible.
+    pub fn rewrite_mem_range<Type: Copy>(&self, offset: u64, data: &[Type]) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr() as *const u8,
+                self.alloc.ptr.add(offset as usize),
+                std::mem::size_of_val(data),
+            );
         }
     }
 
-    pub fn destroy(&self, device: &Device) {
+    /// Copy len elements out of the buffer, e.g. after a
+    /// readback of an image.
+    pub fn read_mem<Type: Copy>(&self, len: usize) -> Vec<Type> {
+        unsafe { std::slice::from_raw_parts(self.alloc.ptr as *const Type, len).to_vec() }
+    }
+
+    pub fn destroy(&self, interface: &Interface) {
         unsafe {
-            device.free_memory(self.mem, None);
-            device.destroy_buffer(self.buffer, None);
+            interface.device.destroy_buffer(self.buffer, None);
+            interface
+                .allocator
+                .borrow_mut()
+                .free(&interface.device, &self.alloc);
         }
     }
 }
@@ -143,8 +167,10 @@ impl Default for BufferSet {
     fn default() -> Self {
         Self {
             buffer: Default::default(),
-            mem: Default::default(),
+            size: Default::default(),
+            alloc: Default::default(),
             mem_req: Default::default(),
+            mem_flags: Default::default(),
             usage: Default::default(),
             sharing_mode: Default::default(),
         }
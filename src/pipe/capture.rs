@@ -0,0 +1,72 @@
+use std::{error::Error, path::Path};
+
+use ash::vk;
+use image::{DynamicImage, RgbaImage};
+
+/// Pixels read back from a color image, rows tightly packed
+/// in the format of the image.
+#[derive(Clone)]
+pub struct Capture {
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+
+    pub data: Vec<u8>,
+}
+
+impl Capture {
+    /// Convert to rgba, swapchain images are often bgra. Alpha is
+    /// not written by every render mode, so it is forced opaque.
+    pub fn to_rgba(&self) -> RgbaImage {
+        let mut data = self.data.clone();
+
+        if matches!(
+            self.format,
+            vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB
+        ) {
+            data.chunks_exact_mut(4).for_each(|px| px.swap(0, 2));
+        }
+        data.chunks_exact_mut(4).for_each(|px| px[3] = 255);
+
+        RgbaImage::from_raw(self.extent.width, self.extent.height, data).expect("ERR_CAPTURE_SIZE")
+    }
+
+    /// Write the capture, the format follows the extension of
+    /// path. EXR is written as 32 bit float, everything else
+    /// like PNG as 8 bit rgba.
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let rgba = DynamicImage::ImageRgba8(self.to_rgba());
+
+        let is_exr = Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("exr"));
+
+        if is_exr {
+            DynamicImage::ImageRgba32F(rgba.to_rgba32f()).save(path)?;
+        } else {
+            rgba.save(path)?;
+        }
+
+        log::info!(
+            "Saved capture [ {} x {} ] to {} ...",
+            self.extent.width,
+            self.extent.height,
+            path
+        );
+
+        Ok(())
+    }
+
+    /// Save as png named after the local time, so captures of
+    /// the same session never overwrite each other.
+    pub fn save_timestamped(&self, prefix: &str) -> Result<String, Box<dyn Error>> {
+        let path = format!(
+            "{}_{}.png",
+            prefix,
+            chrono::Local::now().format("%Y-%m-%d_%H-%M-%S%.3f")
+        );
+
+        self.save(&path)?;
+
+        Ok(path)
+    }
+}
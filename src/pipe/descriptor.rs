@@ -1,10 +1,14 @@
+use std::error::Error;
+
 use ash::{vk, Device};
 
-use super::{buffer::BufferSet, image::ImageTarget};
+use super::{buffer::BufferSet, image::ImageTarget, reflect::ShaderReflection};
 
 #[derive(Clone)]
 pub struct DescriptorPool {
     pub layout_list: Vec<vk::DescriptorSetLayout>,
+    // Bindings of every set layout, checked against the shaders
+    pub binding_list: Vec<Vec<vk::DescriptorSetLayoutBinding>>,
 
     pub size_list: Vec<vk::DescriptorPoolSize>,
     pub pool: vk::DescriptorPool,
@@ -35,15 +39,15 @@ impl DescriptorPool {
             });
 
             log::info!("Creating DescriptorSet ...");
-            let set_binding_info = vec![
-                vk::DescriptorSetLayoutBinding {
+            let set_binding_info: Vec<vk::DescriptorSetLayoutBinding> = (0..desc_count)
+                .map(|binding| vk::DescriptorSetLayoutBinding {
+                    binding,
                     descriptor_type: desc_type,
                     descriptor_count: 1,
                     stage_flags: shader_stage,
                     ..Default::default()
-                };
-                desc_count as usize
-            ];
+                })
+                .collect();
 
             result.layout_list.push(
                 device
@@ -53,11 +57,138 @@ impl DescriptorPool {
                     )
                     .unwrap(),
             );
+            result.binding_list.push(set_binding_info);
+
+            result
+        }
+    }
+
+    /// Create a set layout for every set the shaders of a pipeline
+    /// declare, holding all of its bindings. Buffers of the sets in
+    /// dynamic_set_list are bound with a dynamic offset.
+    pub fn create_reflected_layout(
+        &self,
+        reflection: &ShaderReflection,
+        dynamic_set_list: &[u32],
+        device: &Device,
+    ) -> Self {
+        unsafe {
+            let mut result = self.clone();
+
+            for set in 0..reflection.set_count() {
+                let set_binding_info: Vec<vk::DescriptorSetLayoutBinding> = reflection
+                    .binding_list
+                    .iter()
+                    .filter(|binding| binding.set == set)
+                    .map(|binding| vk::DescriptorSetLayoutBinding {
+                        binding: binding.binding,
+                        descriptor_type: match binding.desc_type {
+                            vk::DescriptorType::UNIFORM_BUFFER
+                                if dynamic_set_list.contains(&set) =>
+                            {
+                                vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC
+                            }
+                            vk::DescriptorType::STORAGE_BUFFER
+                                if dynamic_set_list.contains(&set) =>
+                            {
+                                vk::DescriptorType::STORAGE_BUFFER_DYNAMIC
+                            }
+                            desc_type => desc_type,
+                        },
+                        descriptor_count: binding.count,
+                        stage_flags: binding.stage,
+                        ..Default::default()
+                    })
+                    .collect();
+
+                log::info!(
+                    "Creating DescriptorSet [ {} ] with [ {} ] reflected bindings ...",
+                    set,
+                    set_binding_info.len()
+                );
+                result
+                    .size_list
+                    .extend(
+                        set_binding_info
+                            .iter()
+                            .map(|binding| vk::DescriptorPoolSize {
+                                ty: binding.descriptor_type,
+                                descriptor_count: binding.descriptor_count,
+                            }),
+                    );
+
+                result.layout_list.push(
+                    device
+                        .create_descriptor_set_layout(
+                            &vk::DescriptorSetLayoutCreateInfo::builder()
+                                .bindings(&set_binding_info),
+                            None,
+                        )
+                        .unwrap(),
+                );
+                result.binding_list.push(set_binding_info);
+            }
 
             result
         }
     }
 
+    /// Every resource of the shaders which the set layouts don't
+    /// provide, with its set, binding and type.
+    pub fn check_reflection(&self, reflection: &ShaderReflection) -> Result<(), Box<dyn Error>> {
+        let error_list: Vec<String> = reflection
+            .binding_list
+            .iter()
+            .filter_map(|binding| {
+                let layout_binding = match self
+                    .binding_list
+                    .get(binding.set as usize)
+                    .and_then(|set| set.iter().find(|other| other.binding == binding.binding))
+                {
+                    Some(layout_binding) => layout_binding,
+                    None => {
+                        return Some(format!(
+                            "Set [ {} ] binding [ {} ] ({:?}) used by {:?} is missing in the layout",
+                            binding.set, binding.binding, binding.desc_type, binding.stage
+                        ))
+                    }
+                };
+
+                // Dynamic buffers are declared like normal ones in the shader
+                let layout_type = match layout_binding.descriptor_type {
+                    vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC => vk::DescriptorType::UNIFORM_BUFFER,
+                    vk::DescriptorType::STORAGE_BUFFER_DYNAMIC => vk::DescriptorType::STORAGE_BUFFER,
+                    desc_type => desc_type,
+                };
+
+                if layout_type != binding.desc_type {
+                    Some(format!(
+                        "Set [ {} ] binding [ {} ] is {:?} in the shader but {:?} in the layout",
+                        binding.set, binding.binding, binding.desc_type, layout_binding.descriptor_type
+                    ))
+                } else if layout_binding.descriptor_count < binding.count {
+                    Some(format!(
+                        "Set [ {} ] binding [ {} ] has [ {} ] descriptors in the shader but [ {} ] in the layout",
+                        binding.set, binding.binding, binding.count, layout_binding.descriptor_count
+                    ))
+                } else if !layout_binding.stage_flags.contains(binding.stage) {
+                    Some(format!(
+                        "Set [ {} ] binding [ {} ] is used by {:?} but only visible to {:?}",
+                        binding.set, binding.binding, binding.stage, layout_binding.stage_flags
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        if error_list.is_empty() {
+            Ok(())
+        } else {
+            Err(error_list.join("\n").into())
+        }
+    }
+
     /// Desciptor describe some sort buffer like storage buffer.
     /// Descriptor set is group of descriptor.
     /// Specify the descriptor count for each storage type here.
@@ -167,6 +298,7 @@ impl Default for DescriptorPool {
     fn default() -> Self {
         Self {
             layout_list: Default::default(),
+            binding_list: Default::default(),
             size_list: Default::default(),
             pool: Default::default(),
             set_list: Default::default(),
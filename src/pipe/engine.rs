@@ -1,40 +1,95 @@
 use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeSet,
     error::Error,
     mem::{self, align_of},
+    ops::Range,
     path::Path,
+    time::Duration,
 };
 
 use ash::vk;
 use cgmath::Vector3;
-use nalgebra_glm::Vec2;
+use nalgebra_glm::{UVec3, Vec3};
 
 use crate::{
     interface::interface::Interface,
     pipe::{
+        atlas::{BrickAtlas, BrickLayout, MAX_STREAM_PER_FRAME, MISSING_SLOT},
         descriptor::DescriptorPool,
+        obj::{BASE_CUBE_IDX, BASE_CUBE_VERT},
         pipe::{JFAPush, LocInfo, Pipe, Vertex},
     },
     tree::{
+        brick::{Brick, BrickMap, Voxel, BRICK_SIZE, BRICK_VOXEL_COUNT, EMPTY_VOXEL},
+        edt::DistanceMethod,
+        jfa::{jfa_step_list, JfaDiff, JfaVariant},
         octant::Octant,
-        octree::{Octree, MAX_DEPTH},
+        octree::{idx_to_ranges, Octree, MAX_DEPTH},
+        sdf::GlobalDistanceField,
         trace::{BranchInfo, PosInfo},
     },
     uniform::Uniform,
     vector::Vector,
-    Pref, DEFAULT_STORAGE_BUFFER_SIZE, DEFAULT_UNIFORM_BUFFER_SIZE,
+    Pref,
 };
 
-use super::{buffer::BufferSet, image::ImageTarget};
+use super::{
+    buffer::BufferSet,
+    capture::Capture,
+    graph::{Access, GraphImage, PassDesc, RenderGraph, TransientDesc, TransientPool},
+    image::ImageTarget,
+    profiler::GpuProfiler,
+    reflect::ShaderReflection,
+    shader::{
+        self, ShaderSource, COMP_SHADER, EDT_SHADER, JFA_SHADER, SDF_MIP_SHADER, TEST_SHADER,
+        TRAVERSE_SHADER, VERT_SHADER,
+    },
+    upload::{UploadQueue, UploadTarget},
+};
+
+// Scene buffers have room for this many times their data
+pub const SCENE_BUFFER_HEADROOM: u64 = 2;
+
+/// Renderer drawing the frame, switched at runtime to compare them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    // Proxy cubes rasterized, bricks traced by texture_traverse.frag
+    #[default]
+    Traverse,
+    // Full screen tracer of shader.comp
+    Compute,
+    // Proxy cubes shaded with their world position by test.frag
+    Debug,
+}
 
 #[derive(Clone)]
 pub struct Engine {
-    pub image_target_list: Vec<ImageTarget>,
-    pub depth_image: ImageTarget,
-    pub img_buffer: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+    // Render targets of the graphs, recreated with the swapchain
+    pub transient_pool: RefCell<TransientPool>,
+    // GPU time of the graph passes, one query slot per frame in flight and one for compute
+    pub profiler: RefCell<GpuProfiler>,
     pub vk_img_buffer: BufferSet,
+    // Readback of the present image for screenshots
+    pub capture_buffer: BufferSet,
     pub brick_texture: ImageTarget,
+    // Second image for the ping-pong of the jump flood
+    pub brick_texture_swap: ImageTarget,
+    pub brick_layout: BrickLayout,
+    pub brick_map: BrickMap,
+    pub brick_atlas: BrickAtlas,
+
+    // Coarse distance field across brick borders, for empty space skipping
+    pub global_sdf: GlobalDistanceField,
+    pub global_sdf_texture: ImageTarget,
+    pub global_sdf_buffer: BufferSet,
+
+    // Brick of every proxy, indexed like location info
+    pub proxy_brick_list: Vec<Option<usize>>,
+    pub loc_info: Vec<LocInfo>,
 
     pub index_data: Vec<u32>,
+    pub vertex_data: Vec<Vertex>,
 
     pub index_buffer: BufferSet,
     pub vertex_buffer: BufferSet,
@@ -43,161 +98,260 @@ pub struct Engine {
     pub octree_buffer: BufferSet,
     pub loc_info_buffer: BufferSet,
 
+    // Edits and streamed bricks, copied at the start of the next frame
+    pub upload_queue: UploadQueue,
+
+    // Slot of the next frame in interface.frame_list
+    pub frame_idx: usize,
+    pub last_frame_idx: usize,
+    // Bytes between the uniform slices of two frames
+    pub uniform_stride: u64,
+
     pub pool_comp: DescriptorPool,
     pub pipe_comp: Pipe,
     pub vk_pipe_comp: vk::Pipeline,
+    // Target the storage image descriptor of pool_comp points to
+    pub comp_target_view: Cell<vk::ImageView>,
 
-    pub jfa_pool: DescriptorPool,
+    // Reads one brick texture and writes the other, one pool per direction
+    pub jfa_pool_list: Vec<DescriptorPool>,
     pub jfa_pipe: Pipe,
     pub vk_jfa_comp: vk::Pipeline,
 
+    pub edt_pool: DescriptorPool,
+    pub edt_pipe: Pipe,
+
+    // One pool per level of the distance pyramid, reading the level above
+    pub sdf_mip_pool_list: Vec<DescriptorPool>,
+    pub sdf_mip_pipe: Pipe,
+
     pub pool_graphic: DescriptorPool,
     pub pipe_graphic: Pipe,
+
+    // Same proxy draw as pipe_graphic, with test.frag
+    pub pool_debug: DescriptorPool,
+    pub pipe_debug: Pipe,
+}
+
+impl RenderMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "traverse" => Some(Self::Traverse),
+            "compute" => Some(Self::Compute),
+            "debug" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+
+    /// Mode after this one, the hotkey cycles through all of them.
+    pub fn next(&self) -> Self {
+        match self {
+            Self::Traverse => Self::Compute,
+            Self::Compute => Self::Debug,
+            Self::Debug => Self::Traverse,
+        }
+    }
 }
 
 impl Engine {
-    pub fn create_base(interface: &Interface, uniform: &Uniform, octree: &Octree) -> Self {
+    pub fn create_base(
+        interface: &Interface,
+        pref: &Pref,
+        uniform: &Uniform,
+        octree: &Octree,
+    ) -> Self {
         unsafe {
             let mut result = Self::default();
+            result.brick_layout = pref.brick_layout;
 
-            result.image_target_list = interface
-                .swapchain
-                .img_list
-                .iter()
-                .map(|_| ImageTarget::attachment_img(interface, interface.surface.render_res))
-                .collect();
-
-            result.depth_image =
-                ImageTarget::depth_img(interface, interface.surface.render_res.into());
+            result.capture_buffer = Self::create_capture_buffer(interface);
+            result.profiler =
+                RefCell::new(GpuProfiler::new(interface, interface.frame_list.len() + 1));
 
+            log::info!(
+                "Creating brick texture with {:?} layout ...",
+                result.brick_layout
+            );
             result.brick_texture = ImageTarget::storage_texture(
                 interface,
                 vk::Format::R8G8B8A8_UNORM,
-                vk::Extent3D {
-                    width: 4096,
-                    height: 4096,
-                    depth: 1,
-                },
-                vk::ImageType::TYPE_2D,
-                vk::ImageViewType::TYPE_2D,
+                result.brick_layout.extent(),
+                result.brick_layout.image_type(),
+                result.brick_layout.view_type(),
+                1,
+                1,
+            );
+
+            result.brick_texture_swap = ImageTarget::storage_texture(
+                interface,
+                vk::Format::R8G8B8A8_UNORM,
+                result.brick_layout.extent(),
+                result.brick_layout.image_type(),
+                result.brick_layout.view_type(),
+                1,
                 1,
             );
 
-            result.img_buffer = image::ImageBuffer::<image::Rgba<u8>, Vec<u8>>::from_pixel(
-                4096,
-                4096,
-                image::Rgba([0, 0, 0, 255]),
+            result.brick_map = BrickMap::new(octree);
+
+            let step_list = jfa_step_list(BRICK_SIZE, pref.jfa_variant);
+
+            if pref.distance_report {
+                log::info!(
+                    "JFA against exact distance: {}",
+                    result.brick_map.distance_error_report(&step_list)
+                );
+            }
+
+            if pref.cpu_distance_field {
+                match pref.distance_method {
+                    DistanceMethod::Jfa => result.brick_map.jump_flood(&step_list),
+                    DistanceMethod::Exact => result.brick_map.exact_distance(),
+                }
+            }
+
+            result.global_sdf = GlobalDistanceField::new(octree);
+            let sdf_extent = vk::Extent3D {
+                width: result.global_sdf.res,
+                height: result.global_sdf.res,
+                depth: result.global_sdf.res,
+            };
+
+            result.global_sdf_texture = ImageTarget::storage_texture(
+                interface,
+                vk::Format::R32_SFLOAT,
+                sdf_extent,
+                vk::ImageType::TYPE_3D,
+                vk::ImageViewType::TYPE_3D,
+                1,
+                result.global_sdf.mip_count(),
             );
 
-            //image.put_pixel(0, 0, image::Rgb([0, 0, 0]));
+            // Coarser levels are built by SDF_MIP.comp otherwise
+            if pref.cpu_distance_field {
+                result.global_sdf.build_pyramid();
+            }
 
-            let (vertex_data, index_data, loc_info) =
-                Pipe::get_octree_vert_data(octree, &mut result.img_buffer);
+            let pyramid_size = (result.global_sdf.pyramid_len() * mem::size_of::<f32>()) as u64;
 
-            result.img_buffer = image::ImageBuffer::<image::Rgba<u8>, Vec<u8>>::from_pixel(
-                4096,
-                4096,
-                image::Rgba([0, 0, 0, 255]),
+            result.global_sdf_buffer = BufferSet::new(
+                pyramid_size,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                vk::SharingMode::EXCLUSIVE,
+                &interface.device,
+            )
+            .create_memory(
+                interface,
+                align_of::<f32>() as u64,
+                pyramid_size,
+                &vec![0.0f32; result.global_sdf.pyramid_len()],
             );
 
-            result.img_buffer.put_pixel(0, 0, image::Rgba([255, 255, 255, 0]));
-            result.img_buffer.put_pixel(7, 123, image::Rgba([255, 255, 255, 0]));
-            result.img_buffer.put_pixel(5, 453, image::Rgba([255, 255, 255, 0]));
-            result.img_buffer.put_pixel(12, 367, image::Rgba([255, 255, 255, 0]));
+            result.upload_global_sdf(interface);
+
+            let (vertex_data, index_data, mut loc_info) = Pipe::get_octree_vert_data(octree);
+            result.proxy_brick_list =
+                Self::get_proxy_brick_list(&result.brick_map, &vertex_data, &mut loc_info);
 
-            let mut img_data = result.img_buffer.clone().into_raw();
+            // Place as many bricks as fit, the rest is streamed in
+            // once the camera gets close to them
+            let capacity = result.brick_layout.capacity();
+            result.brick_atlas = BrickAtlas::new(capacity);
+            (0..result.brick_map.brick_list.len())
+                .for_each(|brick_idx| result.brick_atlas.touch(brick_idx));
+
+            // Staging holds one brick per slot, free slots stay empty.
+            // Whole texture is uploaded below, so nothing is dirty anymore
+            let mut staging_data = vec![EMPTY_VOXEL; capacity * BRICK_VOXEL_COUNT];
+            result
+                .brick_atlas
+                .process_requests(capacity)
+                .iter()
+                .for_each(|&(brick_idx, slot)| {
+                    let start = slot as usize * BRICK_VOXEL_COUNT;
+                    staging_data[start..start + BRICK_VOXEL_COUNT]
+                        .copy_from_slice(&result.brick_map.brick_list[brick_idx].voxel_data);
+                });
+            result.brick_map.take_dirty();
 
-            // result.img_buffer.save("out.png").unwrap();
+            result.loc_info = loc_info;
+            result.assign_brick_slots();
 
             log::info!("Creating ImageBuffer ...");
             result.vk_img_buffer = BufferSet::new(
-                (std::mem::size_of::<u8>() * img_data.len()) as u64,
+                mem::size_of_val(&staging_data[..]) as u64,
                 vk::BufferUsageFlags::TRANSFER_SRC,
                 vk::SharingMode::EXCLUSIVE,
                 &interface.device,
             )
             .create_memory(
-                &interface.device,
-                &interface.phy_device,
-                align_of::<u8>() as u64,
-                (std::mem::size_of::<u8>() * img_data.len()) as u64,
-                &img_data,
+                interface,
+                align_of::<Voxel>() as u64,
+                mem::size_of_val(&staging_data[..]) as u64,
+                &staging_data,
             );
 
+            // Device local buffers are filled through the staging ring
+            result.upload_queue = UploadQueue::new(interface);
+
             log::info!("Creating IndexBuffer ...");
             result.index_data = index_data;
-            result.index_buffer = BufferSet::new(
-                mem::size_of_val(&result.index_data[..]) as u64,
+            result.index_buffer = Self::create_scene_buffer(
+                interface,
+                &mut result.upload_queue,
                 vk::BufferUsageFlags::INDEX_BUFFER,
-                vk::SharingMode::EXCLUSIVE,
-                &interface.device,
-            )
-            .create_memory(
-                &interface.device,
-                &interface.phy_device,
-                align_of::<u32>() as u64,
-                mem::size_of_val(&result.index_data[..]) as u64,
                 &result.index_data,
             );
 
             log::info!("Creating VertexBuffer ...");
-            result.vertex_buffer = BufferSet::new(
-                mem::size_of_val(&vertex_data[..]) as u64,
+            result.vertex_data = vertex_data;
+            result.vertex_buffer = Self::create_scene_buffer(
+                interface,
+                &mut result.upload_queue,
                 vk::BufferUsageFlags::VERTEX_BUFFER,
-                vk::SharingMode::EXCLUSIVE,
-                &interface.device,
-            )
-            .create_memory(
-                &interface.device,
-                &interface.phy_device,
-                align_of::<Vertex>() as u64,
-                mem::size_of_val(&vertex_data[..]) as u64,
-                &vertex_data,
+                &result.vertex_data,
             );
 
             log::info!("Creating UniformBuffer ...");
+            // One slice per frame in flight, bound with a dynamic offset
+            result.uniform_stride = (mem::size_of::<Uniform>() as u64).next_multiple_of(
+                interface
+                    .phy_device
+                    .device_prop
+                    .limits
+                    .min_uniform_buffer_offset_alignment
+                    .max(1),
+            );
+            let uniform_size = result.uniform_stride * interface.frame_list.len() as u64;
             result.uniform_buffer = BufferSet::new(
-                mem::size_of::<Uniform>() as u64,
+                uniform_size,
                 vk::BufferUsageFlags::UNIFORM_BUFFER,
                 vk::SharingMode::EXCLUSIVE,
                 &interface.device,
             )
             .create_memory(
-                &interface.device,
-                &interface.phy_device,
+                interface,
                 align_of::<Uniform>() as u64,
                 mem::size_of::<Uniform>() as u64,
                 &[uniform.clone()],
             );
+            (1..interface.frame_list.len())
+                .for_each(|frame_idx| result.write_uniform(frame_idx, uniform));
 
             log::info!("Creating OctreeBuffer ...");
-            result.octree_buffer = BufferSet::new(
-                DEFAULT_STORAGE_BUFFER_SIZE,
+            result.octree_buffer = Self::create_scene_buffer(
+                interface,
+                &mut result.upload_queue,
                 vk::BufferUsageFlags::STORAGE_BUFFER,
-                vk::SharingMode::EXCLUSIVE,
-                &interface.device,
-            )
-            .create_memory(
-                &interface.device,
-                &interface.phy_device,
-                align_of::<u32>() as u64,
-                DEFAULT_STORAGE_BUFFER_SIZE,
                 &octree.octant_data,
             );
 
             log::info!("Creating Location Info Buffer ...");
-            result.loc_info_buffer = BufferSet::new(
-                (mem::size_of::<LocInfo>() * loc_info.len()) as u64,
+            result.loc_info_buffer = Self::create_scene_buffer(
+                interface,
+                &mut result.upload_queue,
                 vk::BufferUsageFlags::STORAGE_BUFFER,
-                vk::SharingMode::EXCLUSIVE,
-                &interface.device,
-            )
-            .create_memory(
-                &interface.device,
-                &interface.phy_device,
-                align_of::<LocInfo>() as u64,
-                (mem::size_of::<LocInfo>() * loc_info.len()) as u64,
-                &loc_info,
+                &result.loc_info,
             );
 
             interface.record_submit_cmd(
@@ -206,75 +360,46 @@ impl Engine {
                 &[],
                 &[],
                 |cmd_buffer| {
-                    let texture_barrier = vk::ImageMemoryBarrier {
-                        dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-                        new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                        image: result.brick_texture.img,
-                        subresource_range: vk::ImageSubresourceRange {
-                            aspect_mask: vk::ImageAspectFlags::COLOR,
-                            level_count: 1,
-                            layer_count: 1,
-                            ..Default::default()
-                        },
-                        ..Default::default()
-                    };
-                    interface.device.cmd_pipeline_barrier(
-                        cmd_buffer,
-                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                        vk::PipelineStageFlags::TRANSFER,
-                        vk::DependencyFlags::empty(),
-                        &[],
-                        &[],
-                        &[texture_barrier],
-                    );
+                    let mut graph = RenderGraph::new();
 
-                    let buffer_copy = vk::BufferImageCopy::builder()
-                        .image_subresource(
-                            vk::ImageSubresourceLayers::builder()
-                                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                                .layer_count(1)
-                                .build(),
-                        )
-                        .image_extent(vk::Extent3D {
-                            width: 4096,
-                            height: 4096,
-                            depth: 1,
-                        })
-                        .build();
-
-                    interface.device.cmd_copy_buffer_to_image(
-                        cmd_buffer,
-                        result.vk_img_buffer.buffer,
+                    // Texture was just created, it has no content to keep
+                    let brick_texture = graph.import_image(
                         result.brick_texture.img,
-                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                        &[buffer_copy],
+                        result.brick_texture.view,
+                        vk::ImageAspectFlags::COLOR,
+                        Access::UNDEFINED,
                     );
-                    let texture_barrier_end = vk::ImageMemoryBarrier {
-                        src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-                        dst_access_mask: vk::AccessFlags::SHADER_READ,
-                        old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                        new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                        image: result.brick_texture.img,
-                        subresource_range: vk::ImageSubresourceRange {
-                            aspect_mask: vk::ImageAspectFlags::COLOR,
-                            level_count: 1,
-                            layer_count: 1,
-                            ..Default::default()
-                        },
-                        ..Default::default()
-                    };
-                    interface.device.cmd_pipeline_barrier(
+
+                    let pass =
+                        PassDesc::new("brick_upload").image(brick_texture, Access::TRANSFER_DST);
+                    graph.add_pass(pass, |context, cmd_buffer| {
+                        let region_list: Vec<vk::BufferImageCopy> = (0..capacity as u32)
+                            .map(|slot| result.brick_layout.copy_region(slot))
+                            .collect();
+
+                        interface.device.cmd_copy_buffer_to_image(
+                            cmd_buffer,
+                            result.vk_img_buffer.buffer,
+                            context.image(brick_texture).img,
+                            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                            &region_list,
+                        );
+                    });
+
+                    graph.export_image(brick_texture, Access::FRAGMENT_SAMPLED);
+                    graph.execute(
+                        interface,
                         cmd_buffer,
-                        vk::PipelineStageFlags::TRANSFER,
-                        vk::PipelineStageFlags::FRAGMENT_SHADER,
-                        vk::DependencyFlags::empty(),
-                        &[],
-                        &[],
-                        &[texture_barrier_end],
+                        &mut result.transient_pool.borrow_mut(),
                     );
                 },
             );
 
+            // Everything has to be on the gpu before the first frame
+            result.upload_queue.flush(interface);
+
+            log::info!("{}", interface.allocator.borrow().stats());
+
             result
         }
     }
@@ -297,9 +422,9 @@ impl Engine {
                     vk::ShaderStageFlags::COMPUTE,
                     &interface.device,
                 )
-                // Uniform Set
+                // Uniform Set, bound with the offset of the frame slice
                 .create_descriptor_set_layout(
-                    vk::DescriptorType::UNIFORM_BUFFER,
+                    vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
                     1,
                     vk::ShaderStageFlags::COMPUTE,
                     &interface.device,
@@ -320,10 +445,10 @@ impl Engine {
             log::info!("Writing descriptor list ...");
             result.pool_comp.write_buffer_desc(
                 &self.uniform_buffer,
-                vk::WHOLE_SIZE,
+                mem::size_of::<Uniform>() as u64,
                 1,
                 0,
-                vk::DescriptorType::UNIFORM_BUFFER,
+                vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
                 &interface.device,
             );
 
@@ -336,7 +461,13 @@ impl Engine {
                 &interface.device,
             );
 
-            result.pipe_comp = Pipe::create_comp_pipe(&interface.device, &result.pool_comp, &[]);
+            result.pipe_comp = Pipe::create_comp_pipe(
+                &interface.device,
+                interface.pipe_cache.cache,
+                &result.pool_comp,
+                &COMP_SHADER.code(&[]),
+            )
+            .expect("ERR_CREATE_PIPE");
 
             result
         }
@@ -351,55 +482,138 @@ impl Engine {
         unsafe {
             let mut result = self.clone();
 
+            let jfa_code = JFA_SHADER.code(&shader::define_list(self.brick_layout));
+            let reflection = ShaderReflection::new(&jfa_code, vk::ShaderStageFlags::COMPUTE)
+                .expect("ERR_REFLECT_SHADER");
+
+            // Pass i reads the texture written by pass i - 1
             log::info!("Creating descriptor set layout list ...");
-            result.jfa_pool = DescriptorPool::default()
-                .create_descriptor_set_layout(
-                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                    1,
-                    vk::ShaderStageFlags::COMPUTE,
-                    &interface.device,
-                )
-                .create_descriptor_set_layout(
+            result.jfa_pool_list = [
+                (&self.brick_texture, &self.brick_texture_swap),
+                (&self.brick_texture_swap, &self.brick_texture),
+            ]
+            .iter()
+            .map(|(read_texture, write_texture)| {
+                let pool = DescriptorPool::default()
+                    .create_reflected_layout(&reflection, &[], &interface.device)
+                    .create_descriptor_pool(&interface.device)
+                    .write_descriptor_pool(&interface.device);
+
+                log::info!("Writing descriptor list ...");
+                pool.write_img_desc(
+                    read_texture,
+                    vk::ImageLayout::GENERAL,
+                    0,
+                    0,
                     vk::DescriptorType::STORAGE_IMAGE,
+                    &interface.device,
+                );
+
+                pool.write_img_desc(
+                    write_texture,
+                    vk::ImageLayout::GENERAL,
                     1,
-                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    vk::DescriptorType::STORAGE_IMAGE,
                     &interface.device,
                 );
 
-            result.jfa_pool = result
-                .jfa_pool
-                .create_descriptor_pool(&interface.device)
-                .write_descriptor_pool(&interface.device);
+                pool
+            })
+            .collect();
 
-            log::info!("Writing descriptor list ...");
-            result.jfa_pool.write_img_desc(
-                &self.brick_texture,
-                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                0,
-                0,
-                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            result.jfa_pipe = Pipe::create_comp_pipe(
                 &interface.device,
-            );
+                interface.pipe_cache.cache,
+                &result.jfa_pool_list[0],
+                &jfa_code,
+            )
+            .expect("ERR_CREATE_PIPE");
 
-            result.jfa_pool.write_img_desc(
-                &self.brick_texture,
-                vk::ImageLayout::GENERAL,
-                1,
-                0,
-                vk::DescriptorType::STORAGE_IMAGE,
-                &interface.device,
-            );
+            result
+        }
+    }
 
-            let push_constant = vk::PushConstantRange::builder()
-                .size(mem::size_of::<JFAPush>() as u32)
-                .stage_flags(vk::ShaderStageFlags::COMPUTE)
-                .build();
+    pub fn create_edt_comp(&self, interface: &Interface) -> Self {
+        let mut result = self.clone();
+
+        let edt_code = EDT_SHADER.code(&shader::define_list(self.brick_layout));
+        let reflection = ShaderReflection::new(&edt_code, vk::ShaderStageFlags::COMPUTE)
+            .expect("ERR_REFLECT_SHADER");
+
+        log::info!("Creating descriptor set layout list ...");
+        result.edt_pool = DescriptorPool::default()
+            .create_reflected_layout(&reflection, &[], &interface.device)
+            .create_descriptor_pool(&interface.device)
+            .write_descriptor_pool(&interface.device);
+
+        log::info!("Writing descriptor list ...");
+        result.edt_pool.write_img_desc(
+            &self.brick_texture,
+            vk::ImageLayout::GENERAL,
+            0,
+            0,
+            vk::DescriptorType::STORAGE_IMAGE,
+            &interface.device,
+        );
+
+        result.edt_pipe = Pipe::create_comp_pipe(
+            &interface.device,
+            interface.pipe_cache.cache,
+            &result.edt_pool,
+            &edt_code,
+        )
+        .expect("ERR_CREATE_PIPE");
+
+        result
+    }
+
+    pub fn create_sdf_mip_comp(&self, interface: &Interface) -> Self {
+        let mut result = self.clone();
+
+        let sdf_mip_code = SDF_MIP_SHADER.code(&[]);
+        let reflection = ShaderReflection::new(&sdf_mip_code, vk::ShaderStageFlags::COMPUTE)
+            .expect("ERR_REFLECT_SHADER");
+
+        log::info!("Creating descriptor set layout list ...");
+        result.sdf_mip_pool_list = (1..self.global_sdf.mip_count())
+            .map(|level| {
+                let pool = DescriptorPool::default()
+                    .create_reflected_layout(&reflection, &[], &interface.device)
+                    .create_descriptor_pool(&interface.device)
+                    .write_descriptor_pool(&interface.device);
+
+                pool.write_img_desc(
+                    &self.global_sdf_texture.mip_img(level - 1),
+                    vk::ImageLayout::GENERAL,
+                    0,
+                    0,
+                    vk::DescriptorType::STORAGE_IMAGE,
+                    &interface.device,
+                );
 
-            result.jfa_pipe =
-                Pipe::create_comp_pipe(&interface.device, &result.jfa_pool, &[push_constant]);
+                pool.write_img_desc(
+                    &self.global_sdf_texture.mip_img(level),
+                    vk::ImageLayout::GENERAL,
+                    1,
+                    0,
+                    vk::DescriptorType::STORAGE_IMAGE,
+                    &interface.device,
+                );
 
-            result
-        }
+                pool
+            })
+            .collect();
+
+        result.sdf_mip_pipe = Pipe::create_comp_pipe(
+            &interface.device,
+            interface.pipe_cache.cache,
+            &result.sdf_mip_pool_list[0],
+            &sdf_mip_code,
+        )
+        .expect("ERR_CREATE_PIPE");
+
+        result
     }
 
     pub fn create_graphic(
@@ -411,35 +625,24 @@ impl Engine {
         unsafe {
             let mut result = self.clone();
 
+            let define_list = shader::define_list(self.brick_layout);
+            let vert_code = VERT_SHADER.code(&define_list);
+            let frag_code = TRAVERSE_SHADER.code(&define_list);
+            let reflection = ShaderReflection::merge(&[
+                ShaderReflection::new(&vert_code, vk::ShaderStageFlags::VERTEX)
+                    .expect("ERR_REFLECT_SHADER"),
+                ShaderReflection::new(&frag_code, vk::ShaderStageFlags::FRAGMENT)
+                    .expect("ERR_REFLECT_SHADER"),
+            ])
+            .expect("ERR_REFLECT_SHADER");
+
+            // Uniform set 0 is bound with the offset of the frame slice
             log::info!("Creating descriptor set layout list ...");
-            result.pool_graphic = DescriptorPool::default()
-                // Uniform Set
-                .create_descriptor_set_layout(
-                    vk::DescriptorType::UNIFORM_BUFFER,
-                    1,
-                    vk::ShaderStageFlags::ALL_GRAPHICS,
-                    &interface.device,
-                )
-                // Octree Set
-                .create_descriptor_set_layout(
-                    vk::DescriptorType::STORAGE_BUFFER,
-                    1,
-                    vk::ShaderStageFlags::FRAGMENT,
-                    &interface.device,
-                )
-                // location info set
-                .create_descriptor_set_layout(
-                    vk::DescriptorType::STORAGE_BUFFER,
-                    1,
-                    vk::ShaderStageFlags::FRAGMENT,
-                    &interface.device,
-                )
-                .create_descriptor_set_layout(
-                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                    1,
-                    vk::ShaderStageFlags::FRAGMENT,
-                    &interface.device,
-                );
+            result.pool_graphic = DescriptorPool::default().create_reflected_layout(
+                &reflection,
+                &[0],
+                &interface.device,
+            );
 
             result.pool_graphic = result
                 .pool_graphic
@@ -449,10 +652,10 @@ impl Engine {
             log::info!("Writing descriptor list ...");
             result.pool_graphic.write_buffer_desc(
                 &self.uniform_buffer,
-                vk::WHOLE_SIZE,
+                mem::size_of::<Uniform>() as u64,
                 0,
                 0,
-                vk::DescriptorType::UNIFORM_BUFFER,
+                vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
                 &interface.device,
             );
             result.pool_graphic.write_buffer_desc(
@@ -478,309 +681,1729 @@ impl Engine {
                 vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
                 3,
                 0,
-                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                vk::DescriptorType::SAMPLED_IMAGE,
+                &interface.device,
+            );
+            result.pool_graphic.write_img_desc(
+                &self.brick_texture,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                4,
+                0,
+                vk::DescriptorType::SAMPLER,
+                &interface.device,
+            );
+            result.pool_graphic.write_img_desc(
+                &self.global_sdf_texture,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                5,
+                0,
+                vk::DescriptorType::SAMPLED_IMAGE,
                 &interface.device,
             );
 
             result.pipe_graphic = Pipe::create_graphic_pipe(
                 &interface.device,
+                interface.pipe_cache.cache,
                 &interface.surface,
                 &result.pool_graphic,
-                &[],
-            );
+                &vert_code,
+                &frag_code,
+            )
+            .expect("ERR_CREATE_PIPE");
 
             result
         }
     }
 
-    /// Draw the next image onto the window
-    /// Get swapchain image, begin draw, render with
-    /// pipe onto image target and finally blit to swapchain
-    /// image. Then end draw.
-    ///
-    /// Originally used for drawing, now we use draw_graphic with
-    /// normal vertex pipeline, no quad just mesh until certain level and
-    /// then tracing further.
+    /// Proxy draw with test.frag instead of the traversal, which
+    /// only needs the uniform.
+    pub fn create_debug(&self, interface: &Interface) -> Self {
+        let mut result = self.clone();
+
+        let define_list = shader::define_list(self.brick_layout);
+        let vert_code = VERT_SHADER.code(&define_list);
+        let frag_code = TEST_SHADER.code(&define_list);
+        let reflection = ShaderReflection::merge(&[
+            ShaderReflection::new(&vert_code, vk::ShaderStageFlags::VERTEX)
+                .expect("ERR_REFLECT_SHADER"),
+            ShaderReflection::new(&frag_code, vk::ShaderStageFlags::FRAGMENT)
+                .expect("ERR_REFLECT_SHADER"),
+        ])
+        .expect("ERR_REFLECT_SHADER");
+
+        log::info!("Creating debug descriptor set layout list ...");
+        result.pool_debug = DescriptorPool::default()
+            .create_reflected_layout(&reflection, &[0], &interface.device)
+            .create_descriptor_pool(&interface.device)
+            .write_descriptor_pool(&interface.device);
+
+        result.pool_debug.write_buffer_desc(
+            &self.uniform_buffer,
+            mem::size_of::<Uniform>() as u64,
+            0,
+            0,
+            vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            &interface.device,
+        );
+
+        result.pipe_debug = Pipe::create_graphic_pipe(
+            &interface.device,
+            interface.pipe_cache.cache,
+            &interface.surface,
+            &result.pool_debug,
+            &vert_code,
+            &frag_code,
+        )
+        .expect("ERR_CREATE_PIPE");
+
+        result
+    }
+
+    pub fn is_created(&self, mode: RenderMode) -> bool {
+        let pipe = match mode {
+            RenderMode::Traverse => self.pipe_graphic.pipe,
+            RenderMode::Compute => self.pipe_comp.pipe,
+            RenderMode::Debug => self.pipe_debug.pipe,
+        };
+
+        pipe != vk::Pipeline::null()
+    }
 
-    pub fn draw_comp(
+    /// Create the pipelines of mode, every mode is only created
+    /// once it is first drawn with.
+    pub fn create_mode(
         &self,
         interface: &Interface,
-        pref: &Pref,
         uniform: &Uniform,
-    ) -> Result<bool, Box<dyn Error>> {
-        unsafe {
-            interface.swap_draw_next(|present_index| {
-                interface.record_submit_cmd(
-                    interface.draw_cmd_fence,
-                    interface.draw_cmd_buffer,
-                    &[interface.present_complete],
-                    &[interface.render_complete],
-                    |cmd_buffer| {
-                        self.pool_comp.write_img_desc(
-                            &self.image_target_list[present_index as usize],
-                            vk::ImageLayout::GENERAL,
-                            0,
-                            0,
-                            vk::DescriptorType::STORAGE_IMAGE,
-                            &interface.device,
-                        );
-                        self.pool_comp.write_buffer_desc(
-                            &self.uniform_buffer,
-                            vk::WHOLE_SIZE,
-                            1,
-                            0,
-                            vk::DescriptorType::UNIFORM_BUFFER,
-                            &interface.device,
-                        );
+        octree: &Octree,
+        mode: RenderMode,
+    ) -> Self {
+        log::info!("Creating {:?} render mode ...", mode);
+
+        match mode {
+            RenderMode::Traverse => self.create_graphic(interface, uniform, octree),
+            RenderMode::Compute => self.create_draw_compute(interface, uniform, octree),
+            RenderMode::Debug => self.create_debug(interface),
+        }
+    }
+
+    /// Jump flood over the whole brick texture. The steps are
+    /// derived from brick_size, every pass reads the image the
+    /// last one wrote. All passes are recorded into a single submit.
+    pub fn build_distance_field(
+        &self,
+        interface: &Interface,
+        extent: vk::Extent3D,
+        brick_size: u32,
+        variant: JfaVariant,
+    ) {
+        let step_list = jfa_step_list(brick_size, variant);
+
+        log::info!("Running JFA on the gpu with steps {:?} ...", step_list);
+
+        interface.record_submit_cmd(
+            interface.comp_cmd_fence,
+            interface.comp_cmd_buffer,
+            &[],
+            &[],
+            |cmd_buffer| {
+                let mut graph = RenderGraph::new();
+                graph.profile(
+                    &self.profiler,
+                    self.comp_profile_slot(interface),
+                    "jfa_build",
+                );
+
+                let brick_texture = graph.import_image(
+                    self.brick_texture.img,
+                    self.brick_texture.view,
+                    vk::ImageAspectFlags::COLOR,
+                    Access::FRAGMENT_SAMPLED,
+                );
+                // Content of the swap texture is overwritten by the first pass
+                let brick_texture_swap = graph.import_image(
+                    self.brick_texture_swap.img,
+                    self.brick_texture_swap.view,
+                    vk::ImageAspectFlags::COLOR,
+                    Access::UNDEFINED,
+                );
+                let texture_list = [brick_texture, brick_texture_swap];
+
+                // One invocation per voxel, 8² groups in 2D and 4³ groups in 3D
+                let (gcx, gcy, gcz) = match self.brick_layout {
+                    BrickLayout::Atlas2D => (extent.width / 8, extent.height / 8, 1),
+                    BrickLayout::Texture3D => {
+                        (extent.width / 4, extent.height / 4, extent.depth / 4)
+                    }
+                };
+
+                for (pass_idx, &step) in step_list.iter().enumerate() {
+                    // Next pass reads what the last one wrote
+                    let pass = PassDesc::new("jfa")
+                        .image(texture_list[pass_idx % 2], Access::COMPUTE_READ)
+                        .image(texture_list[(pass_idx + 1) % 2], Access::COMPUTE_WRITE);
+
+                    graph.add_pass(pass, move |_, cmd_buffer| unsafe {
+                        let push = JFAPush { step };
 
-                        // Dispatch Compute Pipe
                         interface.device.cmd_bind_pipeline(
                             cmd_buffer,
                             vk::PipelineBindPoint::COMPUTE,
-                            self.pipe_comp.pipe,
+                            self.jfa_pipe.pipe,
+                        );
+
+                        interface.device.cmd_push_constants(
+                            cmd_buffer,
+                            self.jfa_pipe.pipe_layout,
+                            vk::ShaderStageFlags::COMPUTE,
+                            0,
+                            &push.step.to_ne_bytes(),
                         );
+
                         interface.device.cmd_bind_descriptor_sets(
                             cmd_buffer,
                             vk::PipelineBindPoint::COMPUTE,
-                            self.pipe_comp.pipe_layout,
+                            self.jfa_pipe.pipe_layout,
                             0,
-                            &self.pool_comp.set_list[..],
+                            &self.jfa_pool_list[pass_idx % 2].set_list[..],
                             &[],
                         );
-                        interface.device.cmd_dispatch(
+
+                        interface.device.cmd_dispatch(cmd_buffer, gcx, gcy, gcz);
+                    });
+                }
+
+                // Odd pass count leaves the result in the swap texture
+                if step_list.len() % 2 == 1 {
+                    let pass = PassDesc::new("jfa_copy")
+                        .image(brick_texture_swap, Access::TRANSFER_SRC)
+                        .image(brick_texture, Access::TRANSFER_DST);
+
+                    graph.add_pass(pass, move |context, cmd_buffer| unsafe {
+                        let copy_region = vk::ImageCopy {
+                            src_subresource: vk::ImageSubresourceLayers {
+                                aspect_mask: vk::ImageAspectFlags::COLOR,
+                                layer_count: 1,
+                                ..Default::default()
+                            },
+                            dst_subresource: vk::ImageSubresourceLayers {
+                                aspect_mask: vk::ImageAspectFlags::COLOR,
+                                layer_count: 1,
+                                ..Default::default()
+                            },
+                            extent,
+                            ..Default::default()
+                        };
+                        interface.device.cmd_copy_image(
                             cmd_buffer,
-                            interface.surface.render_res.width / 16,
-                            interface.surface.render_res.height / 16,
-                            1,
+                            context.image(brick_texture_swap).img,
+                            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                            context.image(brick_texture).img,
+                            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                            &[copy_region],
                         );
+                    });
+                }
+
+                graph.export_image(brick_texture, Access::FRAGMENT_SAMPLED);
+                graph.execute(interface, cmd_buffer, &mut self.transient_pool.borrow_mut());
+            },
+        )
+    }
+
+    /// Exact distance transform of every brick slot, one pass per
+    /// axis. All passes are recorded into a single submit.
+    pub fn run_edt(&self, interface: &Interface) {
+        log::info!("Running exact distance transform on the gpu ...");
+
+        interface.record_submit_cmd(
+            interface.comp_cmd_fence,
+            interface.comp_cmd_buffer,
+            &[],
+            &[],
+            |cmd_buffer| {
+                let mut graph = RenderGraph::new();
+                graph.profile(
+                    &self.profiler,
+                    self.comp_profile_slot(interface),
+                    "edt_build",
+                );
+
+                let brick_texture = graph.import_image(
+                    self.brick_texture.img,
+                    self.brick_texture.view,
+                    vk::ImageAspectFlags::COLOR,
+                    Access::FRAGMENT_SAMPLED,
+                );
+
+                // Next axis reads what the last one wrote
+                for axis in 0..3u32 {
+                    let pass =
+                        PassDesc::new("edt").image(brick_texture, Access::COMPUTE_READ_WRITE);
 
-                        // First Image Barrier
-                        self.pipe_comp.first_img_barrier(
-                            &self.image_target_list[present_index as usize],
-                            interface.swapchain.img_list[present_index as usize],
-                            &interface.device,
+                    graph.add_pass(pass, move |_, cmd_buffer| unsafe {
+                        interface.device.cmd_bind_pipeline(
                             cmd_buffer,
+                            vk::PipelineBindPoint::COMPUTE,
+                            self.edt_pipe.pipe,
                         );
-                        // Copy image memory
-                        self.pipe_comp.copy_image(
-                            &interface.device,
+                        interface.device.cmd_bind_descriptor_sets(
                             cmd_buffer,
-                            pref,
-                            self.image_target_list[present_index as usize].img,
-                            interface.swapchain.img_list[present_index as usize],
-                            interface.surface.render_res,
-                            interface.surface.surface_res,
+                            vk::PipelineBindPoint::COMPUTE,
+                            self.edt_pipe.pipe_layout,
+                            0,
+                            &self.edt_pool.set_list[..],
+                            &[],
                         );
-                        self.pipe_comp.sec_img_barrier(
-                            interface.swapchain.img_list[present_index as usize],
-                            &interface.device,
+
+                        interface.device.cmd_push_constants(
                             cmd_buffer,
+                            self.edt_pipe.pipe_layout,
+                            vk::ShaderStageFlags::COMPUTE,
+                            0,
+                            &axis.to_ne_bytes(),
                         );
-                    },
-                );
-            })
+
+                        // One work group of 16² lines per brick slot
+                        interface.device.cmd_dispatch(
+                            cmd_buffer,
+                            1,
+                            1,
+                            self.brick_layout.capacity() as u32,
+                        );
+                    });
+                }
+
+                graph.export_image(brick_texture, Access::FRAGMENT_SAMPLED);
+                graph.execute(interface, cmd_buffer, &mut self.transient_pool.borrow_mut());
+            },
+        );
+    }
+
+    /// Write the atlas slot of every proxy into location info.
+    /// Returns the location indices which changed.
+    pub fn assign_brick_slots(&mut self) -> Vec<usize> {
+        let mut changed_list = vec![];
+
+        for (loc_idx, brick_idx) in self.proxy_brick_list.iter().enumerate() {
+            let slot = brick_idx
+                .and_then(|brick_idx| self.brick_atlas.slot_of(brick_idx))
+                .unwrap_or(MISSING_SLOT);
+
+            if self.loc_info[loc_idx].brick_slot != slot {
+                self.loc_info[loc_idx].brick_slot = slot;
+                changed_list.push(loc_idx);
+            }
         }
+
+        changed_list
     }
 
-    pub fn run_jfa_iteration(
-        &self,
-        interface: &Interface,
-        tex_extent: vk::Extent3D,
-        dist_between: u32,
-    ) {
+    /// Called once per frame. Requests the bricks the camera sees,
+    /// places missing ones into the atlas and uploads them together
+    /// with edited bricks that are already resident.
+    pub fn stream_bricks(&mut self, uniform: &Uniform) {
+        self.brick_atlas.begin_frame();
+        self.brick_atlas.request_visible(&self.brick_map, uniform);
+
+        // Edited bricks which are not resident get uploaded
+        // anyway when they are placed
+        let mut upload_list: Vec<(usize, u32)> = self
+            .brick_map
+            .take_dirty()
+            .into_iter()
+            .filter_map(|brick_idx| {
+                self.brick_atlas
+                    .slot_of(brick_idx)
+                    .map(|slot| (brick_idx, slot))
+            })
+            .collect();
+
+        let placed_list = self.brick_atlas.process_requests(MAX_STREAM_PER_FRAME);
+        if !placed_list.is_empty() {
+            log::info!(
+                "Streaming [ {} ] bricks into atlas, [ {} / {} ] resident ...",
+                placed_list.len(),
+                self.brick_atlas.resident_count(),
+                self.brick_atlas.capacity
+            );
+        }
+        upload_list.extend(placed_list.iter());
+
+        self.upload_bricks(&upload_list);
+
+        if !placed_list.is_empty() {
+            for loc_idx in self.assign_brick_slots() {
+                self.upload_queue.stage_buffer(
+                    self.loc_info_buffer.buffer,
+                    (loc_idx * mem::size_of::<LocInfo>()) as u64,
+                    &self.loc_info[loc_idx..loc_idx + 1],
+                );
+            }
+        }
+    }
+
+    /// Queue only the texture regions of the given (brick, slot) list.
+    pub fn upload_bricks(&mut self, brick_list: &[(usize, u32)]) {
+        brick_list.iter().for_each(|&(brick_idx, slot)| {
+            self.upload_queue.stage_image(
+                self.brick_texture.img,
+                self.brick_layout.copy_region(slot),
+                &self.brick_map.brick_list[brick_idx].voxel_data,
+            );
+        });
+    }
+
+    /// Apply the edits of the octree since the last call. Dirty
+    /// nodes, edited bricks, the global distance field and the
+    /// proxies which changed are queued for the next frame.
+    pub fn update_octree(&mut self, interface: &Interface, octree: &mut Octree, pref: &Pref) {
+        let (node_range_list, edit_pos_list) = octree.take_dirty();
+        if node_range_list.is_empty() && edit_pos_list.is_empty() {
+            return;
+        }
+
+        if mem::size_of_val(&octree.octant_data[..]) as u64 <= self.octree_buffer.size {
+            node_range_list.iter().for_each(|range| {
+                self.upload_queue.stage_buffer(
+                    self.octree_buffer.buffer,
+                    (range.start * mem::size_of::<u32>()) as u64,
+                    &octree.octant_data[range.clone()],
+                );
+            });
+        } else {
+            self.octree_buffer = self.recreate_scene_buffer(
+                interface,
+                self.octree_buffer.clone(),
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                &octree.octant_data,
+            );
+            self.write_scene_desc(interface);
+        }
+
+        // Uploaded by stream_bricks, new bricks once they are placed
+        let step_list = jfa_step_list(BRICK_SIZE, pref.jfa_variant);
+        let brick_set: BTreeSet<usize> = edit_pos_list
+            .iter()
+            .filter_map(|pos| self.brick_map.rebuild_brick(octree, pos.xyz()))
+            .collect();
+        brick_set.into_iter().for_each(|brick_idx| {
+            let brick = &mut self.brick_map.brick_list[brick_idx];
+            match pref.distance_method {
+                DistanceMethod::Jfa => brick.jump_flood(&step_list),
+                DistanceMethod::Exact => brick.exact_distance(),
+            }
+        });
+
+        // Levels built on the gpu are not known to the cpu yet
+        let pyramid_built = !self.global_sdf.mip_list.is_empty();
+        if let Some((min, max)) = self.global_sdf.update(octree, &edit_pos_list) {
+            match pyramid_built {
+                true => self.stage_global_sdf(min, max),
+                false => self.stage_global_sdf(UVec3::zeros(), UVec3::repeat(self.global_sdf.res)),
+            }
+        }
+
+        let proxy_count = self.loc_info.len();
+        let splice_list = Pipe::update_octree_vert_data(
+            octree,
+            &node_range_list,
+            &edit_pos_list,
+            &mut self.vertex_data,
+            &mut self.loc_info,
+        );
+
+        let mut proxy_set = BTreeSet::new();
+        for (range, count) in &splice_list {
+            let new_range = range.start..range.start + count;
+            let brick_list = Self::get_proxy_brick_list(
+                &self.brick_map,
+                &self.vertex_data
+                    [new_range.start * BASE_CUBE_VERT.len()..new_range.end * BASE_CUBE_VERT.len()],
+                &mut self.loc_info[new_range.clone()],
+            );
+
+            self.proxy_brick_list.splice(range.clone(), brick_list);
+            proxy_set.extend(new_range);
+        }
+
+        // Proxies behind a splice which changed the count moved
+        if let Some(first_moved) = splice_list
+            .iter()
+            .find(|(range, count)| range.len() != *count)
+            .map(|(range, count)| range.start + count)
+        {
+            proxy_set.extend(first_moved..self.loc_info.len());
+        }
+
+        let proxy_range_list = idx_to_ranges(proxy_set.clone());
+        let mut loc_set = proxy_set;
+        loc_set.extend(self.assign_brick_slots());
+
+        let vertex_range_list: Vec<Range<usize>> = proxy_range_list
+            .iter()
+            .map(|range| range.start * BASE_CUBE_VERT.len()..range.end * BASE_CUBE_VERT.len())
+            .collect();
+        self.vertex_buffer = self.update_scene_range(
+            interface,
+            self.vertex_buffer.clone(),
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            &self.vertex_data.clone(),
+            &vertex_range_list,
+        );
+
+        // Indices only depend on the proxy count
+        if self.loc_info.len() != proxy_count {
+            let first_idx = self
+                .index_data
+                .len()
+                .min(self.loc_info.len() * BASE_CUBE_IDX.len());
+            self.index_data.truncate(first_idx);
+            self.index_data
+                .extend(Pipe::get_proxy_index_data(proxy_count..self.loc_info.len()));
+
+            self.index_buffer = self.update_scene_range(
+                interface,
+                self.index_buffer.clone(),
+                vk::BufferUsageFlags::INDEX_BUFFER,
+                &self.index_data.clone(),
+                &idx_to_ranges(first_idx..self.index_data.len()),
+            );
+        }
+
+        let loc_info_buffer = self.loc_info_buffer.clone();
+        self.loc_info_buffer = self.update_scene_range(
+            interface,
+            loc_info_buffer.clone(),
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            &self.loc_info.clone(),
+            &idx_to_ranges(loc_set),
+        );
+        if self.loc_info_buffer.buffer != loc_info_buffer.buffer {
+            self.write_scene_desc(interface);
+        }
+
+        log::info!(
+            "Applied octree edit, [ {} / {} ] proxies rebuilt and [ {} ] bricks ...",
+            proxy_range_list
+                .iter()
+                .map(|range| range.len())
+                .sum::<usize>(),
+            self.loc_info.len(),
+            self.brick_map.brick_list.len()
+        );
+    }
+
+    /// Point the descriptors of every created render mode at the
+    /// current octree and location info buffers, after one of them
+    /// was recreated. Modes which were not created yet have no sets.
+    fn write_scene_desc(&self, interface: &Interface) {
+        if self.is_created(RenderMode::Traverse) {
+            self.pool_graphic.write_buffer_desc(
+                &self.octree_buffer,
+                vk::WHOLE_SIZE,
+                1,
+                0,
+                vk::DescriptorType::STORAGE_BUFFER,
+                &interface.device,
+            );
+            self.pool_graphic.write_buffer_desc(
+                &self.loc_info_buffer,
+                vk::WHOLE_SIZE,
+                2,
+                0,
+                vk::DescriptorType::STORAGE_BUFFER,
+                &interface.device,
+            );
+        }
+
+        if self.is_created(RenderMode::Compute) {
+            self.pool_comp.write_buffer_desc(
+                &self.octree_buffer,
+                vk::WHOLE_SIZE,
+                2,
+                0,
+                vk::DescriptorType::STORAGE_BUFFER,
+                &interface.device,
+            );
+        }
+    }
+
+    /// Brick of every proxy, the corner of the brick is written into
+    /// the location info of the proxy. See BrickMap::proxy_brick.
+    fn get_proxy_brick_list(
+        brick_map: &BrickMap,
+        vertex_data: &[Vertex],
+        loc_info: &mut [LocInfo],
+    ) -> Vec<Option<usize>> {
+        vertex_data
+            .chunks(BASE_CUBE_VERT.len())
+            .zip(loc_info.iter_mut())
+            .map(|(vert_list, loc_info)| {
+                let pos_on_edge = vert_list[0].pos_on_edge;
+                let (brick_idx, brick_origin) = brick_map.proxy_brick(
+                    Vec3::new(pos_on_edge[0], pos_on_edge[1], pos_on_edge[2]),
+                    loc_info.span,
+                );
+
+                loc_info.brick_origin = [brick_origin.x, brick_origin.y, brick_origin.z, 0.0];
+                brick_idx
+            })
+            .collect()
+    }
+
+    /// Device local buffer with room for twice the data, so that
+    /// edits rarely have to recreate it. Data is staged in upload_queue.
+    fn create_scene_buffer<Type: Copy>(
+        interface: &Interface,
+        upload_queue: &mut UploadQueue,
+        usage: vk::BufferUsageFlags,
+        data: &[Type],
+    ) -> BufferSet {
+        let size = mem::size_of_val(data) as u64;
+
+        let result = BufferSet::new(
+            (size * SCENE_BUFFER_HEADROOM).max(mem::size_of::<Type>() as u64),
+            usage | vk::BufferUsageFlags::TRANSFER_DST,
+            vk::SharingMode::EXCLUSIVE,
+            &interface.device,
+        )
+        .create_device_memory(interface);
+
+        upload_queue.stage_buffer(result.buffer, 0, data);
+
+        result
+    }
+
+    /// Queue the element ranges of data which changed, or recreate
+    /// the buffer with all of data if it doesn't fit anymore.
+    fn update_scene_range<Type: Copy>(
+        &mut self,
+        interface: &Interface,
+        buffer: BufferSet,
+        usage: vk::BufferUsageFlags,
+        data: &[Type],
+        range_list: &[Range<usize>],
+    ) -> BufferSet {
+        if (mem::size_of_val(data) as u64) <= buffer.size {
+            range_list.iter().for_each(|range| {
+                self.upload_queue.stage_buffer(
+                    buffer.buffer,
+                    (range.start * mem::size_of::<Type>()) as u64,
+                    &data[range.clone()],
+                );
+            });
+            return buffer;
+        }
+
+        self.recreate_scene_buffer(interface, buffer, usage, data)
+    }
+
+    /// Replace buffer with a larger one holding data, once the
+    /// gpu is done with it.
+    fn recreate_scene_buffer<Type: Copy>(
+        &mut self,
+        interface: &Interface,
+        buffer: BufferSet,
+        usage: vk::BufferUsageFlags,
+        new_data: &[Type],
+    ) -> BufferSet {
+        log::info!(
+            "Recreating scene buffer for [ {} ] elements ...",
+            new_data.len()
+        );
+
+        // Queued copies could still target the old buffer
+        self.upload_queue
+            .pending_list
+            .retain(|upload| !matches!(upload.target, UploadTarget::Buffer { buffer: other, .. } if other == buffer.buffer));
+
+        interface.wait_for_gpu().expect("DEVICE_LOST");
+        buffer.destroy(interface);
+
+        let result = Self::create_scene_buffer(interface, &mut self.upload_queue, usage, new_data);
+        // Drawing from a partly filled buffer would show holes
+        self.upload_queue.flush(interface);
+
+        result
+    }
+
+    /// Copy the global distance field and every built level of
+    /// its pyramid into the texture, the old content is discarded.
+    pub fn upload_global_sdf(&self, interface: &Interface) {
         unsafe {
+            // Last upload could still read from the staging buffer
+            interface
+                .device
+                .wait_for_fences(&[interface.setup_cmd_fence], true, u64::MAX)
+                .expect("DEVICE_LOST");
+
+            let mip_count = self.global_sdf.mip_count();
+
+            let region_list: Vec<vk::BufferImageCopy> =
+                Self::global_sdf_region_list(&self.global_sdf)
+                    .into_iter()
+                    .map(|(region, level_data)| {
+                        self.global_sdf_buffer
+                            .rewrite_mem_range(region.buffer_offset, level_data);
+
+                        region
+                    })
+                    .collect();
+
             interface.record_submit_cmd(
-                interface.comp_cmd_fence,
-                interface.comp_cmd_buffer,
+                interface.setup_cmd_fence,
+                interface.setup_cmd_buffer,
                 &[],
                 &[],
                 |cmd_buffer| {
-                    let gcx = tex_extent.width / dist_between;
-                    let gcy = tex_extent.height / dist_between;
+                    let mut graph = RenderGraph::new();
+
+                    // Old content is overwritten, only the last reads are waited on
+                    let global_sdf_texture = graph.import_mip_image(
+                        self.global_sdf_texture.img,
+                        self.global_sdf_texture.view,
+                        vk::ImageAspectFlags::COLOR,
+                        mip_count,
+                        Access {
+                            layout: vk::ImageLayout::UNDEFINED,
+                            ..Access::FRAGMENT_SAMPLED
+                        },
+                    );
 
-                    let push = JFAPush {
-                        px_per_group: Vec2::new(dist_between as f32, dist_between as f32),
-                    };
+                    let pass = PassDesc::new("global_sdf_upload")
+                        .image(global_sdf_texture, Access::TRANSFER_DST);
+                    graph.add_pass(pass, |context, cmd_buffer| {
+                        interface.device.cmd_copy_buffer_to_image(
+                            cmd_buffer,
+                            self.global_sdf_buffer.buffer,
+                            context.image(global_sdf_texture).img,
+                            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                            &region_list,
+                        );
+                    });
 
-                    // Dispatch Compute Pipe
-                    interface.device.cmd_bind_pipeline(
-                        cmd_buffer,
-                        vk::PipelineBindPoint::COMPUTE,
-                        self.jfa_pipe.pipe,
+                    graph.export_image(global_sdf_texture, Access::FRAGMENT_SAMPLED);
+                    graph.execute(interface, cmd_buffer, &mut self.transient_pool.borrow_mut());
+                },
+            );
+        }
+    }
+
+    /// Queue the cells of every level which cover the box min..max
+    /// of level 0. Pending regions of a level which overlap it are
+    /// merged into it, the regions of one copy must not overlap.
+    pub fn stage_global_sdf(&mut self, min: UVec3, max: UVec3) {
+        let img = self.global_sdf_texture.img;
+
+        for level in 0..self.global_sdf.mip_count() {
+            let (mut level_min, mut level_max) = self.global_sdf.mip_box(min, max, level);
+
+            loop {
+                let pending_count = self.upload_queue.pending_list.len();
+
+                self.upload_queue
+                    .pending_list
+                    .retain(|upload| match upload.target {
+                        UploadTarget::Image { img: other, region }
+                            if other == img && region.image_subresource.mip_level == level =>
+                        {
+                            let other_min = UVec3::new(
+                                region.image_offset.x as u32,
+                                region.image_offset.y as u32,
+                                region.image_offset.z as u32,
+                            );
+                            let other_max = other_min
+                                + UVec3::new(
+                                    region.image_extent.width,
+                                    region.image_extent.height,
+                                    region.image_extent.depth,
+                                );
+
+                            let overlap = (0..3).all(|axis| {
+                                other_min[axis] < level_max[axis]
+                                    && level_min[axis] < other_max[axis]
+                            });
+                            if overlap {
+                                level_min = level_min.inf(&other_min);
+                                level_max = level_max.sup(&other_max);
+                            }
+
+                            !overlap
+                        }
+                        _ => true,
+                    });
+
+                // A grown box can overlap regions checked before
+                if self.upload_queue.pending_list.len() == pending_count {
+                    break;
+                }
+            }
+
+            let extent = level_max - level_min;
+            let region = vk::BufferImageCopy {
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: level,
+                    layer_count: 1,
+                    ..Default::default()
+                },
+                image_offset: vk::Offset3D {
+                    x: level_min.x as i32,
+                    y: level_min.y as i32,
+                    z: level_min.z as i32,
+                },
+                image_extent: vk::Extent3D {
+                    width: extent.x,
+                    height: extent.y,
+                    depth: extent.z,
+                },
+                ..Default::default()
+            };
+
+            let box_data = self.global_sdf.box_data(level, level_min, level_max);
+            self.upload_queue.stage_image(img, region, &box_data);
+        }
+    }
+
+    /// Copy region and data of every built level, packed one
+    /// after another like in the staging buffer.
+    fn global_sdf_region_list(
+        global_sdf: &GlobalDistanceField,
+    ) -> Vec<(vk::BufferImageCopy, &[f32])> {
+        let mut offset = 0;
+
+        std::iter::once(&global_sdf.dist_data)
+            .chain(global_sdf.mip_list.iter())
+            .enumerate()
+            .map(|(level, level_data)| {
+                let res = global_sdf.mip_res(level as u32);
+                let region = vk::BufferImageCopy {
+                    buffer_offset: offset,
+                    image_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: level as u32,
+                        layer_count: 1,
+                        ..Default::default()
+                    },
+                    image_extent: vk::Extent3D {
+                        width: res,
+                        height: res,
+                        depth: res,
+                    },
+                    ..Default::default()
+                };
+
+                offset += mem::size_of_val(&level_data[..]) as u64;
+
+                (region, &level_data[..])
+            })
+            .collect()
+    }
+
+    /// Build every coarser level of the distance pyramid from the
+    /// one above it, all levels are recorded into a single submit.
+    pub fn build_sdf_pyramid(&self, interface: &Interface) {
+        unsafe {
+            let mip_count = self.global_sdf.mip_count();
+
+            log::info!(
+                "Building distance pyramid with [ {} ] levels on the gpu ...",
+                mip_count
+            );
+
+            interface.record_submit_cmd(
+                interface.comp_cmd_fence,
+                interface.comp_cmd_buffer,
+                &[],
+                &[],
+                |cmd_buffer| {
+                    let mut graph = RenderGraph::new();
+                    graph.profile(
+                        &self.profiler,
+                        self.comp_profile_slot(interface),
+                        "sdf_pyramid",
                     );
 
-                    interface.device.cmd_push_constants(
-                        cmd_buffer,
-                        self.jfa_pipe.pipe_layout,
-                        vk::ShaderStageFlags::COMPUTE,
-                        0,
-                        &[dist_between as u8],
+                    let global_sdf_texture = graph.import_mip_image(
+                        self.global_sdf_texture.img,
+                        self.global_sdf_texture.view,
+                        vk::ImageAspectFlags::COLOR,
+                        mip_count,
+                        Access::FRAGMENT_SAMPLED,
                     );
 
-                    interface.device.cmd_bind_descriptor_sets(
+                    for (pool_idx, pool) in self.sdf_mip_pool_list.iter().enumerate() {
+                        let level = pool_idx as u32 + 1;
+
+                        // Next level reads what the last one wrote
+                        let pass = PassDesc::new("sdf_mip")
+                            .image(global_sdf_texture, Access::COMPUTE_READ.mip(level - 1, 1))
+                            .image(global_sdf_texture, Access::COMPUTE_WRITE.mip(level, 1));
+
+                        graph.add_pass(pass, move |_, cmd_buffer| {
+                            interface.device.cmd_bind_pipeline(
+                                cmd_buffer,
+                                vk::PipelineBindPoint::COMPUTE,
+                                self.sdf_mip_pipe.pipe,
+                            );
+                            interface.device.cmd_bind_descriptor_sets(
+                                cmd_buffer,
+                                vk::PipelineBindPoint::COMPUTE,
+                                self.sdf_mip_pipe.pipe_layout,
+                                0,
+                                &pool.set_list[..],
+                                &[],
+                            );
+
+                            // 4³ groups, rounded up for the last levels
+                            let group_count = self.global_sdf.mip_res(level).div_ceil(4);
+                            interface.device.cmd_dispatch(
+                                cmd_buffer,
+                                group_count,
+                                group_count,
+                                group_count,
+                            );
+                        });
+                    }
+
+                    graph.export_image(global_sdf_texture, Access::FRAGMENT_SAMPLED);
+                    graph.execute(interface, cmd_buffer, &mut self.transient_pool.borrow_mut());
+                },
+            );
+        }
+    }
+
+    /// Copy region_list of target into a host visible buffer and
+    /// wait for it, len elements of Type are read back.
+    fn read_image<Type: Copy>(
+        &self,
+        interface: &Interface,
+        target: &ImageTarget,
+        mip_count: u32,
+        region_list: &[vk::BufferImageCopy],
+        len: usize,
+    ) -> Vec<Type> {
+        let size = (len * mem::size_of::<Type>()) as u64;
+
+        let readback_buffer = BufferSet::new(
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            vk::SharingMode::EXCLUSIVE,
+            &interface.device,
+        )
+        .create_memory(
+            interface,
+            align_of::<u8>() as u64,
+            size,
+            &vec![0u8; size as usize],
+        );
+
+        interface.record_submit_cmd(
+            interface.comp_cmd_fence,
+            interface.comp_cmd_buffer,
+            &[],
+            &[],
+            |cmd_buffer| {
+                let mut graph = RenderGraph::new();
+
+                let image = graph.import_mip_image(
+                    target.img,
+                    target.view,
+                    vk::ImageAspectFlags::COLOR,
+                    mip_count,
+                    Access::FRAGMENT_SAMPLED,
+                );
+                let readback = graph.import_buffer(readback_buffer.buffer, Access::HOST_READ);
+
+                let pass = PassDesc::new("readback")
+                    .image(image, Access::TRANSFER_SRC)
+                    .buffer(readback, Access::TRANSFER_DST);
+
+                graph.add_pass(pass, |context, cmd_buffer| unsafe {
+                    interface.device.cmd_copy_image_to_buffer(
                         cmd_buffer,
-                        vk::PipelineBindPoint::COMPUTE,
-                        self.jfa_pipe.pipe_layout,
-                        0,
-                        &self.jfa_pool.set_list[..],
-                        &[],
+                        context.image(image).img,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        context.buffer(readback),
+                        region_list,
                     );
+                });
+
+                graph.export_image(image, Access::FRAGMENT_SAMPLED);
+                graph.export_buffer(readback, Access::HOST_READ);
+                graph.execute(interface, cmd_buffer, &mut self.transient_pool.borrow_mut());
+            },
+        );
+
+        unsafe {
+            interface
+                .device
+                .wait_for_fences(&[interface.comp_cmd_fence], true, u64::MAX)
+                .expect("DEVICE_LOST");
+        }
+
+        let data = readback_buffer.read_mem(len);
+        readback_buffer.destroy(interface);
+
+        data
+    }
+
+    /// Read the bricks and the distance pyramid built on the gpu
+    /// back and compare them against the cpu versions, for
+    /// --sdf-report. Only resident bricks are compared.
+    pub fn gpu_distance_report(&self, interface: &Interface, pref: &Pref) {
+        let capacity = self.brick_layout.capacity();
+        let region_list: Vec<vk::BufferImageCopy> = (0..capacity as u32)
+            .map(|slot| self.brick_layout.copy_region(slot))
+            .collect();
+        let voxel_list: Vec<Voxel> = self.read_image(
+            interface,
+            &self.brick_texture,
+            1,
+            &region_list,
+            capacity * BRICK_VOXEL_COUNT,
+        );
+
+        let step_list = jfa_step_list(BRICK_SIZE, pref.jfa_variant);
+        let brick_diff = self.brick_atlas.resident_map.iter().fold(
+            JfaDiff::default(),
+            |report, (&brick_idx, &slot)| {
+                let mut reference = self.brick_map.brick_list[brick_idx].clone();
+                match pref.distance_method {
+                    DistanceMethod::Jfa => reference.jump_flood(&step_list),
+                    DistanceMethod::Exact => reference.exact_distance(),
+                }
+
+                let start = slot as usize * BRICK_VOXEL_COUNT;
+                let gpu_brick = Brick {
+                    coord: reference.coord,
+                    voxel_data: voxel_list[start..start + BRICK_VOXEL_COUNT].to_vec(),
+                };
+
+                report.merge(&JfaDiff::new(&reference, &gpu_brick))
+            },
+        );
+        log::info!("GPU {:?} against cpu: {}", pref.distance_method, brick_diff);
+
+        let mut reference = self.global_sdf.clone();
+        reference.build_pyramid();
+
+        let level_list = Self::global_sdf_region_list(&reference);
+        let region_list: Vec<vk::BufferImageCopy> =
+            level_list.iter().map(|&(region, _)| region).collect();
+        let cell_list: Vec<f32> = self.read_image(
+            interface,
+            &self.global_sdf_texture,
+            reference.mip_count(),
+            &region_list,
+            reference.pyramid_len(),
+        );
+
+        for (level, (region, level_data)) in level_list.iter().enumerate() {
+            let start = region.buffer_offset as usize / mem::size_of::<f32>();
+            let max_error = level_data
+                .iter()
+                .zip(&cell_list[start..start + level_data.len()])
+                .map(|(expected, dist)| (dist - expected).abs())
+                .fold(0.0, f32::max);
+
+            log::info!(
+                "GPU distance pyramid level [ {} ] against cpu: max error {:.4}",
+                level,
+                max_error
+            );
+        }
+    }
 
-                    interface.device.cmd_dispatch(cmd_buffer, gcx, gcy, 1);
+    /// Record the proxy draw of mode into target_view and
+    /// depth_view, with the render resolution of the surface and the
+    /// uniform slice of frame_idx. Shared by the window and the
+    /// offscreen path.
+    pub fn record_draw(
+        &self,
+        interface: &Interface,
+        cmd_buffer: vk::CommandBuffer,
+        mode: RenderMode,
+        target_view: vk::ImageView,
+        depth_view: vk::ImageView,
+        frame_idx: usize,
+    ) {
+        let (pipe, pool) = self.draw_pipe(mode);
+
+        unsafe {
+            let color_attachment_info = vk::RenderingAttachmentInfoKHR::builder()
+                .image_view(target_view)
+                .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .clear_value(vk::ClearValue {
+                    color: vk::ClearColorValue {
+                        float32: [1.0, 1.0, 1.0, 0.0],
+                    },
+                })
+                .build();
+
+            let color_attachment_list = [color_attachment_info];
+
+            let depth_attachment_info = vk::RenderingAttachmentInfo::builder()
+                .image_view(depth_view)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .resolve_image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .clear_value(vk::ClearValue {
+                    depth_stencil: vk::ClearDepthStencilValue {
+                        depth: 1.0,
+                        stencil: 0,
+                    },
+                })
+                .build();
+
+            let rendering_info = vk::RenderingInfoKHR::builder()
+                .render_area(vk::Rect2D {
+                    offset: vk::Offset2D { x: 0, y: 0 },
+                    extent: interface.surface.render_res,
+                })
+                .layer_count(1)
+                .color_attachments(&color_attachment_list)
+                .depth_attachment(&depth_attachment_info)
+                .build();
+
+            // Dispatch Compute Pipe
+            interface
+                .device
+                .cmd_begin_rendering(cmd_buffer, &rendering_info);
+
+            interface.device.cmd_bind_descriptor_sets(
+                cmd_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipe.pipe_layout,
+                0,
+                &pool.set_list[..],
+                &[self.uniform_offset(frame_idx) as u32],
+            );
+
+            interface.device.cmd_bind_pipeline(
+                cmd_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipe.pipe,
+            );
+            interface
+                .device
+                .cmd_set_viewport(cmd_buffer, 0, &pipe.viewport);
+
+            interface
+                .device
+                .cmd_set_scissor(cmd_buffer, 0, &pipe.scissor);
+
+            interface.device.cmd_bind_vertex_buffers(
+                cmd_buffer,
+                0,
+                &[self.vertex_buffer.buffer],
+                &[0],
+            );
+
+            interface.device.cmd_bind_index_buffer(
+                cmd_buffer,
+                self.index_buffer.buffer,
+                0,
+                vk::IndexType::UINT32,
+            );
+
+            interface
+                .device
+                .cmd_draw_indexed(cmd_buffer, self.index_data.len() as u32, 1, 0, 0, 1);
+
+            interface.device.cmd_end_rendering(cmd_buffer);
+        }
+    }
+
+    /// Pass drawing the frame with mode, returns the image it
+    /// draws into.
+    fn add_mode_pass<'a>(
+        &'a self,
+        interface: &'a Interface,
+        graph: &mut RenderGraph<'a>,
+        mode: RenderMode,
+        frame_idx: usize,
+    ) -> GraphImage {
+        match mode {
+            RenderMode::Traverse | RenderMode::Debug => {
+                self.add_draw_pass(interface, graph, mode, frame_idx)
+            }
+            RenderMode::Compute => self.add_trace_pass(interface, graph, frame_idx),
+        }
+    }
+
+    /// Graphics pipe and descriptors of a mode drawing the proxy.
+    fn draw_pipe(&self, mode: RenderMode) -> (&Pipe, &DescriptorPool) {
+        match mode {
+            RenderMode::Debug => (&self.pipe_debug, &self.pool_debug),
+            _ => (&self.pipe_graphic, &self.pool_graphic),
+        }
+    }
+
+    /// Proxy draw of mode into a transient color and depth image
+    /// at the render resolution, returns the color image.
+    fn add_draw_pass<'a>(
+        &'a self,
+        interface: &'a Interface,
+        graph: &mut RenderGraph<'a>,
+        mode: RenderMode,
+        frame_idx: usize,
+    ) -> GraphImage {
+        let extent = interface.surface.render_res;
+
+        let target = graph.create_image(TransientDesc {
+            extent,
+            format: interface.surface.format.format,
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+            aspect: vk::ImageAspectFlags::COLOR,
+        });
+        let depth = graph.create_image(TransientDesc {
+            extent,
+            format: vk::Format::D16_UNORM,
+            usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            aspect: vk::ImageAspectFlags::DEPTH,
+        });
+
+        let pass = PassDesc::new("draw")
+            .image(target, Access::COLOR_ATTACHMENT)
+            .image(depth, Access::DEPTH_ATTACHMENT);
+
+        graph.add_pass(pass, move |context, cmd_buffer| {
+            self.record_draw(
+                interface,
+                cmd_buffer,
+                mode,
+                context.image(target).view,
+                context.image(depth).view,
+                frame_idx,
+            );
+        });
+
+        target
+    }
+
+    /// Full screen trace of shader.comp into a transient storage
+    /// image at the render resolution, returns the image.
+    fn add_trace_pass<'a>(
+        &'a self,
+        interface: &'a Interface,
+        graph: &mut RenderGraph<'a>,
+        frame_idx: usize,
+    ) -> GraphImage {
+        // Matches the rgba8 image of the shader, the blit converts it
+        let target = graph.create_image(TransientDesc {
+            extent: interface.surface.render_res,
+            format: vk::Format::R8G8B8A8_UNORM,
+            usage: vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC,
+            aspect: vk::ImageAspectFlags::COLOR,
+        });
+
+        let pass = PassDesc::new("trace").image(target, Access::COMPUTE_WRITE);
+
+        graph.add_pass(pass, move |context, cmd_buffer| unsafe {
+            // The pool hands out the same image every frame, so the
+            // descriptor only changes on first use and after a resize
+            let target_img = context.image(target);
+            if self.comp_target_view.get() != target_img.view {
+                // Set could still be in use by a frame in flight
+                interface.wait_for_gpu().expect("DEVICE_LOST");
+
+                self.pool_comp.write_img_desc(
+                    target_img,
+                    vk::ImageLayout::GENERAL,
+                    0,
+                    0,
+                    vk::DescriptorType::STORAGE_IMAGE,
+                    &interface.device,
+                );
+                self.comp_target_view.set(target_img.view);
+            }
+
+            interface.device.cmd_bind_pipeline(
+                cmd_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipe_comp.pipe,
+            );
+            interface.device.cmd_bind_descriptor_sets(
+                cmd_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipe_comp.pipe_layout,
+                0,
+                &self.pool_comp.set_list[..],
+                &[self.uniform_offset(frame_idx) as u32],
+            );
+            interface.device.cmd_dispatch(
+                cmd_buffer,
+                interface.surface.render_res.width / 16,
+                interface.surface.render_res.height / 16,
+                1,
+            );
+        });
+
+        target
+    }
+
+    /// Blit src to the present image with present_index, which is
+    /// left ready for presentation. Returns the present image.
+    fn add_present_pass<'a>(
+        &'a self,
+        interface: &'a Interface,
+        pref: &'a Pref,
+        graph: &mut RenderGraph<'a>,
+        src: GraphImage,
+        present_index: u32,
+    ) -> GraphImage {
+        let present = graph.import_image(
+            interface.swapchain.img_list[present_index as usize],
+            interface.swapchain.view_list[present_index as usize],
+            vk::ImageAspectFlags::COLOR,
+            Access::ACQUIRED,
+        );
+        graph.export_image(present, Access::PRESENT);
+
+        let pass = PassDesc::new("blit")
+            .image(src, Access::TRANSFER_SRC)
+            .image(present, Access::TRANSFER_DST);
+
+        graph.add_pass(pass, move |context, cmd_buffer| {
+            self.pipe_comp.copy_image(
+                &interface.device,
+                cmd_buffer,
+                pref,
+                context.image(src).img,
+                context.image(present).img,
+                interface.surface.render_res,
+                interface.surface.surface_res,
+            );
+        });
+
+        present
+    }
+
+    /// Render one frame with mode into an offscreen target instead
+    /// of the swapchain and read it back, used by the headless mode.
+    pub fn render_offscreen(&self, interface: &Interface, mode: RenderMode) -> Capture {
+        let extent = interface.surface.render_res;
+        let size = (extent.width * extent.height * 4) as u64;
+
+        log::info!(
+            "Rendering offscreen [ {} x {} ] ...",
+            extent.width,
+            extent.height
+        );
+
+        let readback_buffer = BufferSet::new(
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            vk::SharingMode::EXCLUSIVE,
+            &interface.device,
+        )
+        .create_memory(
+            interface,
+            align_of::<u8>() as u64,
+            size,
+            &vec![0u8; size as usize],
+        );
+
+        let frame = interface.frame_list[0];
+
+        interface.record_submit_cmd(frame.fence, frame.cmd_buffer, &[], &[], |cmd_buffer| {
+            let mut graph = RenderGraph::new();
+
+            let target = self.add_mode_pass(interface, &mut graph, mode, 0);
+            let readback = graph.import_buffer(readback_buffer.buffer, Access::HOST_READ);
+
+            let pass = PassDesc::new("readback")
+                .image(target, Access::TRANSFER_SRC)
+                .buffer(readback, Access::TRANSFER_DST);
+
+            graph.add_pass(pass, move |context, cmd_buffer| unsafe {
+                let region = vk::BufferImageCopy {
+                    image_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        layer_count: 1,
+                        ..Default::default()
+                    },
+                    image_extent: extent.into(),
+                    ..Default::default()
+                };
+                interface.device.cmd_copy_image_to_buffer(
+                    cmd_buffer,
+                    context.image(target).img,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    context.buffer(readback),
+                    &[region],
+                );
+            });
+
+            graph.execute(interface, cmd_buffer, &mut self.transient_pool.borrow_mut());
+        });
+
+        interface.wait_for_frame(&frame);
+
+        // Compute target is rgba8, the others have the surface format
+        let capture = Capture {
+            extent,
+            format: match mode {
+                RenderMode::Compute => vk::Format::R8G8B8A8_UNORM,
+                _ => interface.surface.format.format,
+            },
+            data: readback_buffer.read_mem(size as usize),
+        };
+
+        readback_buffer.destroy(interface);
+
+        capture
+    }
+
+    /// Host visible buffer with the size of the present image.
+    fn create_capture_buffer(interface: &Interface) -> BufferSet {
+        let res = interface.surface.surface_res;
+        let size = (res.width * res.height * 4) as u64;
+
+        BufferSet::new(
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            vk::SharingMode::EXCLUSIVE,
+            &interface.device,
+        )
+        .create_memory(
+            interface,
+            align_of::<u8>() as u64,
+            size,
+            &vec![0u8; size as usize],
+        )
+    }
+
+    /// Copy the present image into the capture buffer, after the
+    /// blit and before it is handed to the presentation engine.
+    fn add_capture_pass<'a>(
+        &'a self,
+        interface: &'a Interface,
+        graph: &mut RenderGraph<'a>,
+        present: GraphImage,
+    ) {
+        let capture = graph.import_buffer(self.capture_buffer.buffer, Access::HOST_READ);
+
+        let pass = PassDesc::new("capture")
+            .image(present, Access::TRANSFER_SRC)
+            .buffer(capture, Access::TRANSFER_DST);
+
+        graph.add_pass(pass, move |context, cmd_buffer| unsafe {
+            let region = vk::BufferImageCopy {
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    layer_count: 1,
+                    ..Default::default()
                 },
-            )
+                image_extent: interface.surface.surface_res.into(),
+                ..Default::default()
+            };
+            interface.device.cmd_copy_image_to_buffer(
+                cmd_buffer,
+                context.image(present).img,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                context.buffer(capture),
+                &[region],
+            );
+        });
+    }
+
+    /// Pixels of the last frame drawn with capture, waits for it.
+    /// The data is in the surface format, see Capture::to_rgba.
+    pub fn read_capture(&self, interface: &Interface) -> Capture {
+        interface.wait_for_frame(&interface.frame_list[self.last_frame_idx]);
+
+        let extent = interface.surface.surface_res;
+
+        Capture {
+            extent,
+            format: interface.surface.format.format,
+            data: self
+                .capture_buffer
+                .read_mem((extent.width * extent.height * 4) as usize),
         }
     }
 
     pub fn draw_graphic(
-        &self,
+        &mut self,
         interface: &Interface,
         pref: &Pref,
         uniform: &Uniform,
+        capture: bool,
     ) -> Result<bool, Box<dyn Error>> {
-        unsafe {
-            interface.swap_draw_next(|present_index| {
-                interface.record_submit_cmd(
-                    interface.draw_cmd_fence,
-                    interface.draw_cmd_buffer,
-                    &[interface.present_complete],
-                    &[interface.render_complete],
-                    |cmd_buffer| {
-                        self.pool_graphic.write_buffer_desc(
-                            &self.uniform_buffer,
-                            vk::WHOLE_SIZE,
-                            0,
-                            0,
-                            vk::DescriptorType::UNIFORM_BUFFER,
-                            &interface.device,
-                        );
+        let frame_idx = self.frame_idx;
+        let frame = interface.frame_list[frame_idx];
 
-                        let color_attachment_info = vk::RenderingAttachmentInfoKHR::builder()
-                            .image_view(self.image_target_list[present_index as usize].view)
-                            .load_op(vk::AttachmentLoadOp::CLEAR)
-                            .store_op(vk::AttachmentStoreOp::STORE)
-                            .clear_value(vk::ClearValue {
-                                color: vk::ClearColorValue {
-                                    float32: [1.0, 1.0, 1.0, 0.0],
-                                },
-                            })
-                            .build();
-
-                        let color_attachment_list = [color_attachment_info];
-
-                        let depth_attachment_info = vk::RenderingAttachmentInfo::builder()
-                            .image_view(self.depth_image.view)
-                            .load_op(vk::AttachmentLoadOp::CLEAR)
-                            .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                            .resolve_image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                            .clear_value(vk::ClearValue {
-                                depth_stencil: vk::ClearDepthStencilValue {
-                                    depth: 1.0,
-                                    stencil: 0,
-                                },
-                            })
-                            .build();
-
-                        let rendering_info = vk::RenderingInfoKHR::builder()
-                            .render_area(vk::Rect2D {
-                                offset: vk::Offset2D { x: 0, y: 0 },
-                                extent: interface.surface.render_res,
-                            })
-                            .layer_count(1)
-                            .color_attachments(&color_attachment_list)
-                            .depth_attachment(&depth_attachment_info)
-                            .build();
-
-                        // Dispatch Compute Pipe
-                        interface
-                            .device
-                            .cmd_begin_rendering(cmd_buffer, &rendering_info);
+        // Uniform slice and staging of this frame are free afterwards
+        interface.wait_for_frame(&frame);
+        self.write_uniform(frame_idx, uniform);
+        self.profiler.borrow_mut().collect(&interface.device);
 
-                        interface.device.cmd_bind_descriptor_sets(
-                            cmd_buffer,
-                            vk::PipelineBindPoint::GRAPHICS,
-                            self.pipe_graphic.pipe_layout,
-                            0,
-                            &self.pool_graphic.set_list[..],
-                            &[],
-                        );
+        let result = interface.swap_draw_next(&frame, |present_index| {
+            interface.record_submit_cmd(
+                frame.fence,
+                frame.cmd_buffer,
+                &[frame.present_complete],
+                &[frame.render_complete],
+                |cmd_buffer| {
+                    // Leaves every uploaded resource ready for reading
+                    self.upload_queue.record(
+                        interface,
+                        cmd_buffer,
+                        &mut self.transient_pool.borrow_mut(),
+                        frame_idx,
+                    );
 
-                        interface.device.cmd_bind_pipeline(
-                            cmd_buffer,
-                            vk::PipelineBindPoint::GRAPHICS,
-                            self.pipe_graphic.pipe,
-                        );
-                        interface.device.cmd_set_viewport(
-                            cmd_buffer,
-                            0,
-                            &self.pipe_graphic.viewport,
-                        );
+                    let mut graph = RenderGraph::new();
+                    graph.profile(&self.profiler, frame_idx, "frame");
 
-                        interface
-                            .device
-                            .cmd_set_scissor(cmd_buffer, 0, &self.pipe_graphic.scissor);
+                    let target =
+                        self.add_mode_pass(interface, &mut graph, pref.render_mode, frame_idx);
+                    let present =
+                        self.add_present_pass(interface, pref, &mut graph, target, present_index);
+                    if capture {
+                        self.add_capture_pass(interface, &mut graph, present);
+                    }
 
-                        interface.device.cmd_bind_vertex_buffers(
-                            cmd_buffer,
-                            0,
-                            &[self.vertex_buffer.buffer],
-                            &[0],
-                        );
+                    graph.execute(interface, cmd_buffer, &mut self.transient_pool.borrow_mut());
+                },
+            );
+        });
 
-                        interface.device.cmd_bind_index_buffer(
-                            cmd_buffer,
-                            self.index_buffer.buffer,
-                            0,
-                            vk::IndexType::UINT32,
-                        );
+        self.last_frame_idx = frame_idx;
+        self.frame_idx = (frame_idx + 1) % interface.frame_list.len();
 
-                        interface.device.cmd_draw_indexed(
-                            cmd_buffer,
-                            self.index_data.len() as u32,
-                            1,
-                            0,
-                            0,
-                            1,
-                        );
+        result
+    }
 
-                        interface.device.cmd_end_rendering(cmd_buffer);
+    /// Query slot of the compute command buffer, after the slots of
+    /// the frames in flight.
+    pub fn comp_profile_slot(&self, interface: &Interface) -> usize {
+        interface.frame_list.len()
+    }
 
-                        // First Image Barrier
-                        /*
-                        self.pipe_comp.first_img_barrier(
-                            &self.image_target_list[present_index as usize],
-                            interface.swapchain.img_list[present_index as usize],
-                            &interface.device,
-                            cmd_buffer,
-                        );
-                        */
-                        // Copy image memory
-                        self.pipe_comp.copy_image(
-                            &interface.device,
-                            cmd_buffer,
-                            pref,
-                            self.image_target_list[present_index as usize].img,
-                            interface.swapchain.img_list[present_index as usize],
-                            interface.surface.render_res,
-                            interface.surface.surface_res,
-                        );
-                        /*
-                        self.pipe_comp.sec_img_barrier(
-                            interface.swapchain.img_list[present_index as usize],
-                            &interface.device,
-                            cmd_buffer,
-                        );
-                        */
-                    },
-                );
-            })
+    /// Average GPU time of the frame graph, zero until the first
+    /// results are read or without timestamp support.
+    pub fn gpu_frame_time(&self) -> Duration {
+        self.profiler.borrow().timing("frame").unwrap_or_default()
+    }
+
+    /// (pass, average GPU time) of every profiled pass so far.
+    pub fn gpu_timing_list(&self) -> Vec<(String, Duration)> {
+        self.profiler.borrow().average_list()
+    }
+
+    /// Byte offset of the uniform slice of frame_idx.
+    pub fn uniform_offset(&self, frame_idx: usize) -> u64 {
+        frame_idx as u64 * self.uniform_stride
+    }
+
+    /// Frame frame_idx must not be in flight.
+    pub fn write_uniform(&self, frame_idx: usize, uniform: &Uniform) {
+        self.uniform_buffer
+            .rewrite_mem_range(self.uniform_offset(frame_idx), &[*uniform]);
+    }
+
+    /// Recreate the pipelines whose shaders are in name_list, called
+    /// between frames. A shader which fails to compile or is refused
+    /// by the driver keeps its old pipeline, the error is logged.
+    pub fn reload_shaders(&mut self, interface: &Interface, pref: &Pref, name_list: &[String]) {
+        let define_list = shader::define_list(self.brick_layout);
+        let is_changed = |shader_list: &[ShaderSource]| {
+            shader_list
+                .iter()
+                .any(|shader| name_list.iter().any(|name| name == shader.file_name()))
+        };
+
+        // Old pipelines could still be in use by frames in flight
+        interface.wait_for_gpu().expect("DEVICE_LOST");
+
+        // Modes which were never drawn with have no pipeline yet
+        if is_changed(&[VERT_SHADER, TRAVERSE_SHADER]) && self.is_created(RenderMode::Traverse) {
+            Self::reload_pipe(
+                interface,
+                &mut self.pipe_graphic,
+                &[VERT_SHADER, TRAVERSE_SHADER],
+                &define_list,
+                |code_list| {
+                    Pipe::create_graphic_pipe(
+                        &interface.device,
+                        interface.pipe_cache.cache,
+                        &interface.surface,
+                        &self.pool_graphic,
+                        &code_list[0],
+                        &code_list[1],
+                    )
+                },
+            );
+        }
+
+        if is_changed(&[VERT_SHADER, TEST_SHADER]) && self.is_created(RenderMode::Debug) {
+            Self::reload_pipe(
+                interface,
+                &mut self.pipe_debug,
+                &[VERT_SHADER, TEST_SHADER],
+                &define_list,
+                |code_list| {
+                    Pipe::create_graphic_pipe(
+                        &interface.device,
+                        interface.pipe_cache.cache,
+                        &interface.surface,
+                        &self.pool_debug,
+                        &code_list[0],
+                        &code_list[1],
+                    )
+                },
+            );
+        }
+
+        if is_changed(&[COMP_SHADER]) && self.is_created(RenderMode::Compute) {
+            Self::reload_pipe(
+                interface,
+                &mut self.pipe_comp,
+                &[COMP_SHADER],
+                &[],
+                |code_list| {
+                    Pipe::create_comp_pipe(
+                        &interface.device,
+                        interface.pipe_cache.cache,
+                        &self.pool_comp,
+                        &code_list[0],
+                    )
+                },
+            );
+        }
+
+        let mut rebuild_distance_field = false;
+
+        if is_changed(&[JFA_SHADER]) {
+            rebuild_distance_field |= Self::reload_pipe(
+                interface,
+                &mut self.jfa_pipe,
+                &[JFA_SHADER],
+                &define_list,
+                |code_list| {
+                    Pipe::create_comp_pipe(
+                        &interface.device,
+                        interface.pipe_cache.cache,
+                        &self.jfa_pool_list[0],
+                        &code_list[0],
+                    )
+                },
+            ) && pref.distance_method == DistanceMethod::Jfa;
+        }
+
+        if is_changed(&[EDT_SHADER]) {
+            rebuild_distance_field |= Self::reload_pipe(
+                interface,
+                &mut self.edt_pipe,
+                &[EDT_SHADER],
+                &define_list,
+                |code_list| {
+                    Pipe::create_comp_pipe(
+                        &interface.device,
+                        interface.pipe_cache.cache,
+                        &self.edt_pool,
+                        &code_list[0],
+                    )
+                },
+            ) && pref.distance_method == DistanceMethod::Exact;
+        }
+
+        let rebuild_pyramid = is_changed(&[SDF_MIP_SHADER])
+            && Self::reload_pipe(
+                interface,
+                &mut self.sdf_mip_pipe,
+                &[SDF_MIP_SHADER],
+                &[],
+                |code_list| {
+                    Pipe::create_comp_pipe(
+                        &interface.device,
+                        interface.pipe_cache.cache,
+                        &self.sdf_mip_pool_list[0],
+                        &code_list[0],
+                    )
+                },
+            );
+
+        // Passes which only run at startup are run again, so the
+        // change shows up right away
+        if !pref.cpu_distance_field {
+            if rebuild_distance_field {
+                self.rebuild_distance_field(interface, pref);
+            }
+            if rebuild_pyramid {
+                self.build_sdf_pyramid(interface);
+            }
+        }
+    }
+
+    /// Compile shader_list from disk and swap the pipe created from
+    /// the code in for the old one, true if it was swapped. Shaders
+    /// which don't fit the descriptor layouts anymore are rejected,
+    /// the layouts are only created at startup.
+    fn reload_pipe(
+        interface: &Interface,
+        pipe: &mut Pipe,
+        shader_list: &[ShaderSource],
+        define_list: &[(String, String)],
+        create_pipe: impl FnOnce(&[Vec<u32>]) -> Result<Pipe, Box<dyn Error>>,
+    ) -> bool {
+        let code_list: Option<Vec<Vec<u32>>> = shader_list
+            .iter()
+            .map(|shader| shader.reload_code(define_list))
+            .collect();
+
+        match code_list.map(|code_list| create_pipe(&code_list)) {
+            Some(Ok(new_pipe)) => {
+                pipe.drop(&interface.device);
+                *pipe = new_pipe;
+
+                true
+            }
+            Some(Err(err)) => {
+                log::error!("{}", err);
+                log::info!("Keeping old pipeline ...");
+                false
+            }
+            None => {
+                log::info!("Keeping old pipeline ...");
+                false
+            }
+        }
+    }
+
+    /// Upload the seeds of every resident brick again and run the
+    /// distance passes over them, like at startup.
+    pub fn rebuild_distance_field(&mut self, interface: &Interface, pref: &Pref) {
+        let resident_list: Vec<(usize, u32)> = self
+            .brick_atlas
+            .resident_map
+            .iter()
+            .map(|(&brick_idx, &slot)| (brick_idx, slot))
+            .collect();
+
+        self.upload_bricks(&resident_list);
+        self.upload_queue.flush(interface);
+
+        match pref.distance_method {
+            DistanceMethod::Jfa => self.build_distance_field(
+                interface,
+                self.brick_layout.extent(),
+                BRICK_SIZE,
+                pref.jfa_variant,
+            ),
+            DistanceMethod::Exact => self.run_edt(interface),
         }
     }
 
     /// This function is called when the swapchain is outdated
     /// or has the wrong size basically whenever you change the window
     /// size or just minimize the window.
-
     pub fn recreate_swapchain(
         &mut self,
         interface: &mut Interface,
@@ -790,18 +2413,14 @@ impl Engine {
         interface.wait_for_gpu().expect("DEVICE_LOST");
 
         log::info!("Recreating Swapchain ...");
-        self.image_target_list.iter().for_each(|target| {
-            target.destroy(&interface.device);
-        });
-
-        self.depth_image.destroy(&interface.device);
+        self.transient_pool.borrow_mut().destroy(interface);
 
         interface.swapchain.destroy(&interface.device);
 
         interface.surface =
             interface
                 .surface
-                .get_surface_info(&interface.phy_device, &interface.window, pref);
+                .get_surface_info(&interface.phy_device, interface.window(), pref);
 
         uniform.apply_resolution(interface.surface.render_res);
 
@@ -810,59 +2429,78 @@ impl Engine {
             .create_swapchain(&interface.surface)
             .get_present_img(&interface.surface, &interface.device);
 
-        self.image_target_list = interface
-            .swapchain
-            .img_list
-            .iter()
-            .map(|_| ImageTarget::attachment_img(interface, interface.surface.render_res))
-            .collect();
+        self.capture_buffer.destroy(interface);
+        self.capture_buffer = Self::create_capture_buffer(interface);
 
-        self.depth_image = ImageTarget::depth_img(interface, interface.surface.render_res.into());
+        // Pool hands out new images, which may reuse the old handles
+        self.comp_target_view.set(vk::ImageView::null());
 
-        self.pipe_graphic.viewport = vec![vk::Viewport {
-            width: interface.surface.render_res.width as f32,
-            height: interface.surface.render_res.height as f32,
-            max_depth: 1.0,
+        for pipe in [&mut self.pipe_graphic, &mut self.pipe_debug] {
+            pipe.viewport = vec![vk::Viewport {
+                width: interface.surface.render_res.width as f32,
+                height: interface.surface.render_res.height as f32,
+                max_depth: 1.0,
 
-            ..Default::default()
-        }];
+                ..Default::default()
+            }];
 
-        self.pipe_graphic.scissor = vec![interface.surface.render_res.into()];
+            pipe.scissor = vec![interface.surface.render_res.into()];
+        }
     }
 
+    /// Destroy every resource of the engine once the gpu is idle.
+    /// Memory is only given back to the allocator, which frees its
+    /// blocks when the interface is dropped.
     pub fn drop_graphic(&self, interface: &Interface) {
-        unsafe {
-            self.pool_graphic
-                .layout_list
-                .iter()
-                .for_each(|&layout| interface.device.destroy_descriptor_set_layout(layout, None));
+        interface.wait_for_gpu().expect("DEVICE_LOST");
 
-            interface
-                .device
-                .free_descriptor_sets(self.pool_graphic.pool, &self.pool_graphic.set_list)
-                .unwrap();
+        unsafe {
+            // Pools of render modes which were never created are null
+            [
+                &self.pool_graphic,
+                &self.pool_debug,
+                &self.pool_comp,
+                &self.edt_pool,
+            ]
+            .into_iter()
+            .chain(self.jfa_pool_list.iter())
+            .chain(self.sdf_mip_pool_list.iter())
+            .filter(|pool| pool.pool != vk::DescriptorPool::null())
+            .for_each(|pool| {
+                pool.layout_list.iter().for_each(|&layout| {
+                    interface.device.destroy_descriptor_set_layout(layout, None)
+                });
+
+                // Frees the sets as well, the pools are created without FREE_DESCRIPTOR_SET
+                interface.device.destroy_descriptor_pool(pool.pool, None);
+            });
 
-            interface
-                .device
-                .destroy_descriptor_pool(self.pool_graphic.pool, None);
+            self.transient_pool.borrow_mut().destroy(interface);
+            self.profiler.borrow_mut().destroy(&interface.device);
 
-            self.image_target_list.iter().for_each(|target| {
-                target.destroy(&interface.device);
-            });
+            self.brick_texture.destroy(interface);
+            self.brick_texture_swap.destroy(interface);
+            self.global_sdf_texture.destroy(interface);
 
-            self.depth_image.destroy(&interface.device);
-            //self.brick_texture.destroy(&interface.device);
-            //self.vk_img_buffer.destroy(&interface.device);
+            self.vk_img_buffer.destroy(interface);
+            self.capture_buffer.destroy(interface);
+            self.global_sdf_buffer.destroy(interface);
 
-            self.index_buffer.destroy(&interface.device);
-            self.vertex_buffer.destroy(&interface.device);
+            self.index_buffer.destroy(interface);
+            self.vertex_buffer.destroy(interface);
 
-            self.uniform_buffer.destroy(&interface.device);
+            self.uniform_buffer.destroy(interface);
 
-            self.octree_buffer.destroy(&interface.device);
-            self.loc_info_buffer.destroy(&interface.device);
+            self.octree_buffer.destroy(interface);
+            self.loc_info_buffer.destroy(interface);
+            self.upload_queue.destroy(interface);
 
             self.pipe_graphic.drop(&interface.device);
+            self.pipe_debug.drop(&interface.device);
+            self.pipe_comp.drop(&interface.device);
+            self.jfa_pipe.drop(&interface.device);
+            self.edt_pipe.drop(&interface.device);
+            self.sdf_mip_pipe.drop(&interface.device);
         }
     }
 }
@@ -870,25 +2508,47 @@ impl Engine {
 impl Default for Engine {
     fn default() -> Self {
         Self {
-            image_target_list: Default::default(),
-            depth_image: Default::default(),
-            img_buffer: Default::default(),
+            transient_pool: Default::default(),
+            profiler: Default::default(),
             vk_img_buffer: Default::default(),
+            capture_buffer: Default::default(),
             brick_texture: Default::default(),
+            brick_texture_swap: Default::default(),
+            brick_layout: Default::default(),
+            brick_map: Default::default(),
+            global_sdf: Default::default(),
+            global_sdf_texture: Default::default(),
+            global_sdf_buffer: Default::default(),
+            brick_atlas: Default::default(),
+            proxy_brick_list: Default::default(),
+            loc_info: Default::default(),
             index_data: Default::default(),
+            vertex_data: Default::default(),
             index_buffer: Default::default(),
             vertex_buffer: Default::default(),
             uniform_buffer: Default::default(),
             octree_buffer: Default::default(),
             loc_info_buffer: Default::default(),
+            upload_queue: Default::default(),
+
+            frame_idx: 0,
+            last_frame_idx: 0,
+            uniform_stride: 0,
             pool_comp: Default::default(),
             pipe_comp: Default::default(),
             vk_pipe_comp: Default::default(),
-            jfa_pool: Default::default(),
+            comp_target_view: Default::default(),
+            jfa_pool_list: Default::default(),
             jfa_pipe: Default::default(),
             vk_jfa_comp: Default::default(),
+            edt_pool: Default::default(),
+            edt_pipe: Default::default(),
+            sdf_mip_pool_list: Default::default(),
+            sdf_mip_pipe: Default::default(),
             pool_graphic: Default::default(),
             pipe_graphic: Default::default(),
+            pool_debug: Default::default(),
+            pipe_debug: Default::default(),
         }
     }
 }
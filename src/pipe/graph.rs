@@ -0,0 +1,1241 @@
+use std::cell::RefCell;
+
+use ash::vk;
+
+use crate::interface::interface::Interface;
+
+use super::{
+    image::{ImageTarget, COMP_MAP, SUBRES_RANGE},
+    profiler::GpuProfiler,
+};
+
+const WRITE_ACCESS: vk::AccessFlags = vk::AccessFlags::from_raw(
+    vk::AccessFlags::SHADER_WRITE.as_raw()
+        | vk::AccessFlags::COLOR_ATTACHMENT_WRITE.as_raw()
+        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE.as_raw()
+        | vk::AccessFlags::TRANSFER_WRITE.as_raw()
+        | vk::AccessFlags::HOST_WRITE.as_raw()
+        | vk::AccessFlags::MEMORY_WRITE.as_raw(),
+);
+
+/// How a pass uses an image or buffer. Writes are told apart from
+/// reads by the access flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub stage: vk::PipelineStageFlags,
+    pub access: vk::AccessFlags,
+    // Ignored for buffers
+    pub layout: vk::ImageLayout,
+    pub mip_range: MipRange,
+}
+
+/// Mip levels of an image an access covers, count can be
+/// vk::REMAINING_MIP_LEVELS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MipRange {
+    pub base: u32,
+    pub count: u32,
+}
+
+/// Layout and last uses of a resource, barriers are derived from
+/// it and the next access.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResourceState {
+    pub layout: vk::ImageLayout,
+
+    // Last write or layout transition, every later access waits on it
+    pub write_stage: vk::PipelineStageFlags,
+    pub write_access: vk::AccessFlags,
+    // Reads since then, the next write waits on them
+    pub read_stage: vk::PipelineStageFlags,
+    // Reads the last write is made visible to already
+    pub visible_stage: vk::PipelineStageFlags,
+    pub visible_access: vk::AccessFlags,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GraphImage(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GraphBuffer(usize);
+
+/// Image only living inside one graph. Transients with the same
+/// desc and without overlapping passes share one image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransientDesc {
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+    pub usage: vk::ImageUsageFlags,
+    pub aspect: vk::ImageAspectFlags,
+}
+
+#[derive(Clone)]
+pub struct TransientImage {
+    pub desc: TransientDesc,
+    pub target: ImageTarget,
+    // Kept across graphs, the next one waits on the last use
+    pub state: ResourceState,
+}
+
+/// Images backing the transients, created on first need and kept
+/// for the next frames. Destroyed when the render resolution changes.
+#[derive(Clone, Default)]
+pub struct TransientPool {
+    pub image_list: Vec<TransientImage>,
+}
+
+/// Name and resource accesses of a pass.
+#[derive(Clone, Debug, Default)]
+pub struct PassDesc {
+    pub name: &'static str,
+
+    pub image_list: Vec<(GraphImage, Access)>,
+    pub buffer_list: Vec<(GraphBuffer, Access)>,
+}
+
+/// Resolved resources handed to the passes while recording.
+#[derive(Clone, Default)]
+pub struct GraphContext {
+    pub image_list: Vec<ImageTarget>,
+    pub buffer_list: Vec<vk::Buffer>,
+}
+
+type RecordFn<'a> = Box<dyn FnOnce(&GraphContext, vk::CommandBuffer) + 'a>;
+
+/// Pipeline barrier derived from the resource states, before it
+/// is recorded.
+#[derive(Clone, Default)]
+struct Barrier {
+    src_stage: vk::PipelineStageFlags,
+    dst_stage: vk::PipelineStageFlags,
+    image_barrier_list: Vec<vk::ImageMemoryBarrier>,
+    buffer_barrier_list: Vec<vk::BufferMemoryBarrier>,
+}
+
+#[derive(Clone)]
+struct ImageNode {
+    target: ImageTarget,
+    aspect: vk::ImageAspectFlags,
+
+    // Pool image of a transient, None for imported images
+    transient: Option<TransientDesc>,
+    pool_idx: Option<usize>,
+
+    // One per tracked mip level, the last one covers the remaining levels
+    state_list: Vec<ResourceState>,
+    // Access the image is left in after the last pass
+    final_access: Option<Access>,
+}
+
+#[derive(Clone, Copy)]
+struct BufferNode {
+    buffer: vk::Buffer,
+    state: ResourceState,
+    final_access: Option<Access>,
+}
+
+/// Passes of one command buffer with the resources they read and
+/// write. Passes are ordered by those accesses, the pipeline
+/// barriers and layout transitions between them are inserted when
+/// the graph is executed, passes only declare what they use.
+#[derive(Default)]
+pub struct RenderGraph<'a> {
+    image_list: Vec<ImageNode>,
+    buffer_list: Vec<BufferNode>,
+    pass_list: Vec<(PassDesc, Option<RecordFn<'a>>)>,
+
+    // Profiler, its slot of the command buffer and the name of the graph
+    profile: Option<(&'a RefCell<GpuProfiler>, usize, &'static str)>,
+}
+
+impl Access {
+    // Content is not needed, the image starts in an undefined layout
+    pub const UNDEFINED: Self = Self {
+        stage: vk::PipelineStageFlags::TOP_OF_PIPE,
+        access: vk::AccessFlags::empty(),
+        layout: vk::ImageLayout::UNDEFINED,
+        mip_range: MipRange::ALL,
+    };
+    pub const COLOR_ATTACHMENT: Self = Self {
+        stage: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        access: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        mip_range: MipRange::ALL,
+    };
+    pub const DEPTH_ATTACHMENT: Self = Self {
+        stage: vk::PipelineStageFlags::from_raw(
+            vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS.as_raw()
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS.as_raw(),
+        ),
+        access: vk::AccessFlags::from_raw(
+            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ.as_raw()
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE.as_raw(),
+        ),
+        layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        mip_range: MipRange::ALL,
+    };
+    pub const FRAGMENT_SAMPLED: Self = Self {
+        stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+        layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        mip_range: MipRange::ALL,
+    };
+    pub const COMPUTE_READ: Self = Self {
+        stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+        layout: vk::ImageLayout::GENERAL,
+        mip_range: MipRange::ALL,
+    };
+    pub const COMPUTE_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+        access: vk::AccessFlags::SHADER_WRITE,
+        layout: vk::ImageLayout::GENERAL,
+        mip_range: MipRange::ALL,
+    };
+    pub const COMPUTE_READ_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+        access: vk::AccessFlags::from_raw(
+            vk::AccessFlags::SHADER_READ.as_raw() | vk::AccessFlags::SHADER_WRITE.as_raw(),
+        ),
+        layout: vk::ImageLayout::GENERAL,
+        mip_range: MipRange::ALL,
+    };
+    pub const TRANSFER_SRC: Self = Self {
+        stage: vk::PipelineStageFlags::TRANSFER,
+        access: vk::AccessFlags::TRANSFER_READ,
+        layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        mip_range: MipRange::ALL,
+    };
+    pub const TRANSFER_DST: Self = Self {
+        stage: vk::PipelineStageFlags::TRANSFER,
+        access: vk::AccessFlags::TRANSFER_WRITE,
+        layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        mip_range: MipRange::ALL,
+    };
+    pub const HOST_READ: Self = Self {
+        stage: vk::PipelineStageFlags::HOST,
+        access: vk::AccessFlags::HOST_READ,
+        layout: vk::ImageLayout::UNDEFINED,
+        mip_range: MipRange::ALL,
+    };
+    // Scene data, read by the draw and the compute passes of a frame
+    pub const SCENE_READ: Self = Self {
+        stage: vk::PipelineStageFlags::from_raw(
+            vk::PipelineStageFlags::VERTEX_INPUT.as_raw()
+                | vk::PipelineStageFlags::VERTEX_SHADER.as_raw()
+                | vk::PipelineStageFlags::FRAGMENT_SHADER.as_raw()
+                | vk::PipelineStageFlags::COMPUTE_SHADER.as_raw(),
+        ),
+        access: vk::AccessFlags::from_raw(
+            vk::AccessFlags::VERTEX_ATTRIBUTE_READ.as_raw()
+                | vk::AccessFlags::INDEX_READ.as_raw()
+                | vk::AccessFlags::SHADER_READ.as_raw(),
+        ),
+        layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        mip_range: MipRange::ALL,
+    };
+    // Present image right after acquire, the submit waits for it at transfer
+    pub const ACQUIRED: Self = Self {
+        stage: vk::PipelineStageFlags::TRANSFER,
+        access: vk::AccessFlags::empty(),
+        layout: vk::ImageLayout::UNDEFINED,
+        mip_range: MipRange::ALL,
+    };
+    pub const PRESENT: Self = Self {
+        stage: vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        access: vk::AccessFlags::empty(),
+        layout: vk::ImageLayout::PRESENT_SRC_KHR,
+        mip_range: MipRange::ALL,
+    };
+
+    pub fn is_write(&self) -> bool {
+        self.access.intersects(WRITE_ACCESS)
+    }
+
+    /// Same access limited to count mip levels from base.
+    pub fn mip(&self, base: u32, count: u32) -> Self {
+        Self {
+            mip_range: MipRange { base, count },
+            ..*self
+        }
+    }
+
+    /// Two passes conflict if they share a mip level and one of them
+    /// writes or they need the image in different layouts.
+    fn conflicts(&self, other: &Access) -> bool {
+        self.mip_range.overlaps(&other.mip_range)
+            && (self.is_write() || other.is_write() || self.layout != other.layout)
+    }
+
+    fn merge(&self, other: &Access) -> Self {
+        Self {
+            stage: self.stage | other.stage,
+            access: self.access | other.access,
+            ..*self
+        }
+    }
+}
+
+impl MipRange {
+    pub const ALL: Self = Self {
+        base: 0,
+        count: vk::REMAINING_MIP_LEVELS,
+    };
+
+    fn end(&self) -> u32 {
+        self.base.saturating_add(self.count)
+    }
+
+    fn overlaps(&self, other: &MipRange) -> bool {
+        self.base < other.end() && other.base < self.end()
+    }
+}
+
+impl ResourceState {
+    /// State of a resource whose last use was access.
+    pub fn new(access: Access) -> Self {
+        if access.is_write() {
+            Self {
+                layout: access.layout,
+                write_stage: access.stage,
+                write_access: access.access & WRITE_ACCESS,
+                ..Default::default()
+            }
+        } else {
+            Self {
+                layout: access.layout,
+                read_stage: access.stage,
+                visible_stage: access.stage,
+                visible_access: access.access,
+                ..Default::default()
+            }
+        }
+    }
+
+    /// Move on to next, returns source stage, source access and old
+    /// layout of the barrier it needs. With discard the content is
+    /// not kept and the old layout is UNDEFINED.
+    fn transition(
+        &mut self,
+        next: Access,
+        discard: bool,
+    ) -> Option<(vk::PipelineStageFlags, vk::AccessFlags, vk::ImageLayout)> {
+        let old_layout = if discard {
+            vk::ImageLayout::UNDEFINED
+        } else {
+            self.layout
+        };
+        let layout_change = old_layout != next.layout;
+
+        if layout_change || next.is_write() {
+            // Waits on the last write and every read since
+            let src_stage = self.write_stage | self.read_stage;
+            // Also with discard, a late write must not land after the transition
+            let src_access = self.write_access;
+
+            *self = Self::new(next);
+            // Later reads in other stages wait on the transition
+            if layout_change && !next.is_write() {
+                self.write_stage = next.stage;
+            }
+
+            return (layout_change || !src_stage.is_empty())
+                .then_some((src_stage, src_access, old_layout));
+        }
+
+        // Read in the same layout, only waits on the last write
+        self.read_stage |= next.stage;
+        if self.write_stage.is_empty()
+            || (self.visible_stage.contains(next.stage)
+                && self.visible_access.contains(next.access))
+        {
+            return None;
+        }
+
+        self.visible_stage |= next.stage;
+        self.visible_access |= next.access;
+
+        Some((self.write_stage, self.write_access, self.layout))
+    }
+}
+
+impl TransientImage {
+    pub fn new(interface: &Interface, desc: TransientDesc) -> Self {
+        log::info!(
+            "Creating transient image [ {} x {} ] {:?} ...",
+            desc.extent.width,
+            desc.extent.height,
+            desc.format
+        );
+
+        let img_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(desc.format)
+            .extent(desc.extent.into())
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(desc.usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .build();
+
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(desc.format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: desc.aspect,
+                ..SUBRES_RANGE
+            })
+            .components(COMP_MAP)
+            .build();
+
+        let target = ImageTarget::default()
+            .create_img(img_info, &interface.device)
+            .create_img_memory(interface)
+            .create_view(view_info, &interface.device);
+
+        Self {
+            desc,
+            target,
+            state: ResourceState::default(),
+        }
+    }
+}
+
+impl TransientPool {
+    pub fn destroy(&mut self, interface: &Interface) {
+        self.image_list
+            .drain(..)
+            .for_each(|image| image.target.destroy(interface));
+    }
+}
+
+impl PassDesc {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+
+    pub fn image(&self, image: GraphImage, access: Access) -> Self {
+        let mut result = self.clone();
+        result.image_list.push((image, access));
+
+        result
+    }
+
+    pub fn buffer(&self, buffer: GraphBuffer, access: Access) -> Self {
+        let mut result = self.clone();
+        result.buffer_list.push((buffer, access));
+
+        result
+    }
+
+    fn writes(&self) -> bool {
+        self.image_list.iter().any(|(_, access)| access.is_write())
+            || self.buffer_list.iter().any(|(_, access)| access.is_write())
+    }
+
+    /// Passes which have to stay in declaration order.
+    fn conflicts(&self, other: &PassDesc) -> bool {
+        self.image_list.iter().any(|(image, access)| {
+            other.image_list.iter().any(|(other_image, other_access)| {
+                image == other_image && access.conflicts(other_access)
+            })
+        }) || self.buffer_list.iter().any(|(buffer, access)| {
+            other
+                .buffer_list
+                .iter()
+                .any(|(other_buffer, other_access)| {
+                    buffer == other_buffer && (access.is_write() || other_access.is_write())
+                })
+        })
+    }
+}
+
+impl GraphContext {
+    pub fn image(&self, image: GraphImage) -> &ImageTarget {
+        &self.image_list[image.0]
+    }
+
+    pub fn buffer(&self, buffer: GraphBuffer) -> vk::Buffer {
+        self.buffer_list[buffer.0]
+    }
+}
+
+impl<'a> RenderGraph<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Image living outside the graph, last used with access. Its
+    /// mip levels are tracked as a whole.
+    pub fn import_image(
+        &mut self,
+        img: vk::Image,
+        view: vk::ImageView,
+        aspect: vk::ImageAspectFlags,
+        access: Access,
+    ) -> GraphImage {
+        self.import_mip_image(img, view, aspect, 1, access)
+    }
+
+    /// Image living outside the graph whose mip_count levels are
+    /// tracked one by one, passes can use single levels of it.
+    pub fn import_mip_image(
+        &mut self,
+        img: vk::Image,
+        view: vk::ImageView,
+        aspect: vk::ImageAspectFlags,
+        mip_count: u32,
+        access: Access,
+    ) -> GraphImage {
+        self.image_list.push(ImageNode {
+            target: ImageTarget {
+                img,
+                view,
+                ..Default::default()
+            },
+            aspect,
+            transient: None,
+            pool_idx: None,
+            state_list: vec![ResourceState::new(access); mip_count.max(1) as usize],
+            final_access: None,
+        });
+
+        GraphImage(self.image_list.len() - 1)
+    }
+
+    /// Image whose content only lives from its first to its last
+    /// pass, it starts undefined.
+    pub fn create_image(&mut self, desc: TransientDesc) -> GraphImage {
+        self.image_list.push(ImageNode {
+            target: Default::default(),
+            aspect: desc.aspect,
+            transient: Some(desc),
+            pool_idx: None,
+            state_list: vec![Default::default()],
+            final_access: None,
+        });
+
+        GraphImage(self.image_list.len() - 1)
+    }
+
+    /// Leave the image in access after the last pass, for the
+    /// presentation engine or the next submit.
+    pub fn export_image(&mut self, image: GraphImage, access: Access) {
+        self.image_list[image.0].final_access = Some(access);
+    }
+
+    pub fn import_buffer(&mut self, buffer: vk::Buffer, access: Access) -> GraphBuffer {
+        self.buffer_list.push(BufferNode {
+            buffer,
+            state: ResourceState::new(access),
+            final_access: None,
+        });
+
+        GraphBuffer(self.buffer_list.len() - 1)
+    }
+
+    /// Make the writes of the graph to buffer visible to access,
+    /// for passes recorded after the graph.
+    pub fn export_buffer(&mut self, buffer: GraphBuffer, access: Access) {
+        self.buffer_list[buffer.0].final_access = Some(access);
+    }
+
+    /// Time the graph and every pass with the queries of slot, the
+    /// whole graph is labeled with name.
+    pub fn profile(&mut self, profiler: &'a RefCell<GpuProfiler>, slot: usize, name: &'static str) {
+        self.profile = Some((profiler, slot, name));
+    }
+
+    pub fn add_pass<Function: FnOnce(&GraphContext, vk::CommandBuffer) + 'a>(
+        &mut self,
+        desc: PassDesc,
+        record: Function,
+    ) {
+        self.pass_list.push((desc, Some(Box::new(record))));
+    }
+
+    /// Passes whose writes are never used are dropped. A pass writing
+    /// an imported resource or declaring no writes is always kept.
+    fn needed_list(&self) -> Vec<bool> {
+        let mut needed = vec![false; self.pass_list.len()];
+
+        for pass_idx in (0..self.pass_list.len()).rev() {
+            let (pass, _) = &self.pass_list[pass_idx];
+
+            needed[pass_idx] = !pass.writes()
+                || pass.buffer_list.iter().any(|(_, access)| access.is_write())
+                || pass.image_list.iter().any(|&(image, access)| {
+                    access.is_write()
+                        && (self.image_list[image.0].transient.is_none()
+                            || (pass_idx + 1..self.pass_list.len()).any(|other_idx| {
+                                needed[other_idx]
+                                    && self.pass_list[other_idx]
+                                        .0
+                                        .image_list
+                                        .iter()
+                                        .any(|&(other, _)| other == image)
+                            }))
+                });
+        }
+
+        needed
+    }
+
+    /// Group the needed passes into waves. A pass runs one wave after
+    /// the last earlier pass it conflicts with, so passes of one wave
+    /// are independent and share one barrier in front of them.
+    fn wave_list(&self, needed: &[bool]) -> Vec<Vec<usize>> {
+        let mut wave_list: Vec<Vec<usize>> = vec![];
+        let mut wave_of = vec![0; self.pass_list.len()];
+
+        for pass_idx in (0..self.pass_list.len()).filter(|&idx| needed[idx]) {
+            let wave = (0..pass_idx)
+                .filter(|&other_idx| {
+                    needed[other_idx]
+                        && self.pass_list[other_idx]
+                            .0
+                            .conflicts(&self.pass_list[pass_idx].0)
+                })
+                .map(|other_idx| wave_of[other_idx] + 1)
+                .max()
+                .unwrap_or(0);
+
+            wave_of[pass_idx] = wave;
+            if wave_list.len() <= wave {
+                wave_list.resize(wave + 1, vec![]);
+            }
+            wave_list[wave].push(pass_idx);
+        }
+
+        wave_list
+    }
+
+    /// Bind every transient to a pool image, taking one of the same
+    /// desc which is not in use during its waves or creating one.
+    fn assign_transients(
+        &mut self,
+        pool: &mut TransientPool,
+        wave_list: &[Vec<usize>],
+        mut create: impl FnMut(TransientDesc) -> TransientImage,
+    ) {
+        // (first, last) wave of every image, None if unused
+        let mut lifetime_list: Vec<Option<(usize, usize)>> = vec![None; self.image_list.len()];
+        for (wave, pass_list) in wave_list.iter().enumerate() {
+            for &pass_idx in pass_list {
+                for &(image, _) in &self.pass_list[pass_idx].0.image_list {
+                    let lifetime = &mut lifetime_list[image.0];
+                    *lifetime = Some(lifetime.map_or((wave, wave), |(first, _)| (first, wave)));
+                }
+            }
+        }
+
+        let mut order: Vec<usize> = (0..self.image_list.len())
+            .filter(|&idx| self.image_list[idx].transient.is_some() && lifetime_list[idx].is_some())
+            .collect();
+        order.sort_by_key(|&idx| lifetime_list[idx].unwrap().0);
+
+        // Last wave each pool image is used in by this graph
+        let mut busy_list: Vec<Option<usize>> = vec![None; pool.image_list.len()];
+        for image_idx in order {
+            let desc = self.image_list[image_idx].transient.unwrap();
+            let (first, last) = lifetime_list[image_idx].unwrap();
+
+            let pool_idx = match (0..pool.image_list.len()).find(|&pool_idx| {
+                pool.image_list[pool_idx].desc == desc
+                    && busy_list[pool_idx].is_none_or(|busy| busy < first)
+            }) {
+                Some(pool_idx) => pool_idx,
+                None => {
+                    pool.image_list.push(create(desc));
+                    busy_list.push(None);
+                    pool.image_list.len() - 1
+                }
+            };
+
+            busy_list[pool_idx] = Some(last);
+
+            let node = &mut self.image_list[image_idx];
+            node.pool_idx = Some(pool_idx);
+            node.target = pool.image_list[pool_idx].target.clone();
+        }
+    }
+
+    /// Order the passes, bind the transients and record everything
+    /// with the barriers in between into cmd_buffer.
+    pub fn execute(
+        mut self,
+        interface: &Interface,
+        cmd_buffer: vk::CommandBuffer,
+        pool: &mut TransientPool,
+    ) {
+        let needed = self.needed_list();
+        self.pass_list
+            .iter()
+            .zip(needed.iter())
+            .filter(|(_, &needed)| !needed)
+            .for_each(|((pass, _), _)| log::debug!("Skipping unused pass [ {} ] ...", pass.name));
+
+        let wave_list = self.wave_list(&needed);
+        self.assign_transients(pool, &wave_list, |desc| {
+            TransientImage::new(interface, desc)
+        });
+
+        let context = GraphContext {
+            image_list: self
+                .image_list
+                .iter()
+                .map(|node| node.target.clone())
+                .collect(),
+            buffer_list: self.buffer_list.iter().map(|node| node.buffer).collect(),
+        };
+
+        // Transients discard their content on first use
+        let mut started_list = vec![false; self.image_list.len()];
+
+        let mut profile = self
+            .profile
+            .map(|(profiler, slot, name)| (profiler.borrow_mut(), slot, name));
+        let graph_query = profile.as_mut().and_then(|(profiler, slot, name)| {
+            profiler.begin(&interface.device, cmd_buffer, *slot);
+            profiler.begin_pass(&interface.device, cmd_buffer, *slot, name)
+        });
+
+        for pass_list in wave_list.iter() {
+            self.wave_barrier(pool, pass_list, &mut started_list)
+                .record(interface, cmd_buffer);
+
+            for &pass_idx in pass_list {
+                let (pass, record) = &mut self.pass_list[pass_idx];
+                if let Some(record) = record.take() {
+                    let query = profile.as_mut().and_then(|(profiler, slot, _)| {
+                        profiler.begin_pass(&interface.device, cmd_buffer, *slot, pass.name)
+                    });
+                    record(&context, cmd_buffer);
+                    if let Some((profiler, slot, _)) = profile.as_ref() {
+                        profiler.end_pass(&interface.device, cmd_buffer, *slot, query);
+                    }
+                }
+            }
+        }
+
+        self.final_barrier(pool).record(interface, cmd_buffer);
+
+        if let Some((profiler, slot, _)) = profile.as_ref() {
+            profiler.end_pass(&interface.device, cmd_buffer, *slot, graph_query);
+        }
+    }
+
+    /// Barrier in front of the passes of one wave. Passes of a wave
+    /// never conflict, so the accesses of a resource in it are merged.
+    fn wave_barrier(
+        &mut self,
+        pool: &mut TransientPool,
+        pass_list: &[usize],
+        started_list: &mut [bool],
+    ) -> Barrier {
+        let mut image_access_list: Vec<(GraphImage, Access)> = vec![];
+        let mut buffer_access_list: Vec<(GraphBuffer, Access)> = vec![];
+        for &pass_idx in pass_list {
+            let (pass, _) = &self.pass_list[pass_idx];
+
+            pass.image_list.iter().for_each(|&(image, access)| {
+                match image_access_list.iter_mut().find(|(other, other_access)| {
+                    *other == image && other_access.mip_range == access.mip_range
+                }) {
+                    Some((_, other_access)) => *other_access = other_access.merge(&access),
+                    None => image_access_list.push((image, access)),
+                }
+            });
+            pass.buffer_list.iter().for_each(|&(buffer, access)| {
+                match buffer_access_list
+                    .iter_mut()
+                    .find(|(other, _)| *other == buffer)
+                {
+                    Some((_, other_access)) => *other_access = other_access.merge(&access),
+                    None => buffer_access_list.push((buffer, access)),
+                }
+            });
+        }
+
+        let image_access_list: Vec<(usize, Access, bool)> = image_access_list
+            .iter()
+            .map(|&(image, access)| {
+                let discard = !started_list[image.0];
+                started_list[image.0] = true;
+                (image.0, access, discard)
+            })
+            .collect();
+
+        self.barrier(pool, &image_access_list, &buffer_access_list)
+    }
+
+    /// Barrier leaving the exported resources in their final access.
+    fn final_barrier(&mut self, pool: &mut TransientPool) -> Barrier {
+        let final_access_list: Vec<(usize, Access, bool)> = self
+            .image_list
+            .iter()
+            .enumerate()
+            .filter_map(|(image_idx, node)| {
+                node.final_access.map(|access| (image_idx, access, false))
+            })
+            .collect();
+        let final_buffer_list: Vec<(GraphBuffer, Access)> = self
+            .buffer_list
+            .iter()
+            .enumerate()
+            .filter_map(|(buffer_idx, node)| {
+                node.final_access
+                    .map(|access| (GraphBuffer(buffer_idx), access))
+            })
+            .collect();
+
+        self.barrier(pool, &final_access_list, &final_buffer_list)
+    }
+
+    /// One barrier moving every listed resource to its access, it is
+    /// empty if none of them needs one.
+    fn barrier(
+        &mut self,
+        pool: &mut TransientPool,
+        image_access_list: &[(usize, Access, bool)],
+        buffer_access_list: &[(GraphBuffer, Access)],
+    ) -> Barrier {
+        let mut src_stage = vk::PipelineStageFlags::empty();
+        let mut dst_stage = vk::PipelineStageFlags::empty();
+
+        let mut image_barrier_list: Vec<vk::ImageMemoryBarrier> = vec![];
+        for &(image_idx, access, discard) in image_access_list {
+            let node = &mut self.image_list[image_idx];
+            let (img, aspect) = (node.target.img, node.aspect);
+            let state_list = match node.pool_idx {
+                Some(pool_idx) => std::slice::from_mut(&mut pool.image_list[pool_idx].state),
+                // Imported images keep their content
+                None if node.transient.is_none() => &mut node.state_list[..],
+                None => continue,
+            };
+
+            let discard = discard && node.transient.is_some();
+            let level_count = state_list.len() as u32;
+            let base = access.mip_range.base.min(level_count - 1);
+            let end = access.mip_range.end().clamp(base + 1, level_count);
+
+            // Neighbouring levels needing the same barrier share one
+            let first_barrier = image_barrier_list.len();
+            for level in base..end {
+                let Some((stage, src_access, old_layout)) =
+                    state_list[level as usize].transition(access, discard)
+                else {
+                    continue;
+                };
+                src_stage |= stage;
+                dst_stage |= access.stage;
+
+                match image_barrier_list[first_barrier..].last_mut() {
+                    Some(barrier)
+                        if barrier.src_access_mask == src_access
+                            && barrier.old_layout == old_layout
+                            && barrier.subresource_range.base_mip_level
+                                + barrier.subresource_range.level_count
+                                == level =>
+                    {
+                        barrier.subresource_range.level_count += 1
+                    }
+                    _ => image_barrier_list.push(vk::ImageMemoryBarrier {
+                        src_access_mask: src_access,
+                        dst_access_mask: access.access,
+                        old_layout,
+                        new_layout: access.layout,
+                        image: img,
+                        subresource_range: vk::ImageSubresourceRange {
+                            aspect_mask: aspect,
+                            base_mip_level: level,
+                            level_count: 1,
+                            layer_count: vk::REMAINING_ARRAY_LAYERS,
+                            ..SUBRES_RANGE
+                        },
+                        ..Default::default()
+                    }),
+                }
+            }
+
+            // Last tracked level stands for every level after it
+            if let Some(barrier) = image_barrier_list[first_barrier..].last_mut() {
+                let range = &mut barrier.subresource_range;
+                if range.base_mip_level + range.level_count == level_count {
+                    range.level_count = vk::REMAINING_MIP_LEVELS;
+                }
+            }
+        }
+
+        let mut buffer_barrier_list: Vec<vk::BufferMemoryBarrier> = vec![];
+        for &(buffer, access) in buffer_access_list {
+            let node = &mut self.buffer_list[buffer.0];
+
+            if let Some((stage, src_access, _)) = node.state.transition(access, false) {
+                src_stage |= stage;
+                dst_stage |= access.stage;
+
+                buffer_barrier_list.push(vk::BufferMemoryBarrier {
+                    src_access_mask: src_access,
+                    dst_access_mask: access.access,
+                    buffer: node.buffer,
+                    size: vk::WHOLE_SIZE,
+                    ..Default::default()
+                });
+            }
+        }
+
+        // A first use has nothing to wait on
+        if src_stage.is_empty() {
+            src_stage = vk::PipelineStageFlags::TOP_OF_PIPE;
+        }
+
+        Barrier {
+            src_stage,
+            dst_stage,
+            image_barrier_list,
+            buffer_barrier_list,
+        }
+    }
+}
+
+impl Barrier {
+    /// Nothing is recorded if no resource needs a barrier.
+    fn record(&self, interface: &Interface, cmd_buffer: vk::CommandBuffer) {
+        if self.image_barrier_list.is_empty() && self.buffer_barrier_list.is_empty() {
+            return;
+        }
+
+        unsafe {
+            interface.device.cmd_pipeline_barrier(
+                cmd_buffer,
+                self.src_stage,
+                self.dst_stage,
+                vk::DependencyFlags::empty(),
+                &[],
+                &self.buffer_barrier_list,
+                &self.image_barrier_list,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::Handle;
+
+    use super::*;
+
+    const DESC: TransientDesc = TransientDesc {
+        extent: vk::Extent2D {
+            width: 4,
+            height: 4,
+        },
+        format: vk::Format::R8G8B8A8_UNORM,
+        usage: vk::ImageUsageFlags::from_raw(
+            vk::ImageUsageFlags::COLOR_ATTACHMENT.as_raw() | vk::ImageUsageFlags::SAMPLED.as_raw(),
+        ),
+        aspect: vk::ImageAspectFlags::COLOR,
+    };
+
+    const VERTEX_SAMPLED: Access = Access {
+        stage: vk::PipelineStageFlags::VERTEX_SHADER,
+        ..Access::FRAGMENT_SAMPLED
+    };
+
+    /// Waves and the barrier in front of each plus the final one,
+    /// derived like execute does without a device.
+    fn plan(graph: &mut RenderGraph, pool: &mut TransientPool) -> (Vec<Vec<usize>>, Vec<Barrier>) {
+        let needed = graph.needed_list();
+        let wave_list = graph.wave_list(&needed);
+        graph.assign_transients(pool, &wave_list, |desc| TransientImage {
+            desc,
+            target: Default::default(),
+            state: Default::default(),
+        });
+
+        let mut started_list = vec![false; graph.image_list.len()];
+        let mut barrier_list: Vec<Barrier> = wave_list
+            .iter()
+            .map(|pass_list| graph.wave_barrier(pool, pass_list, &mut started_list))
+            .collect();
+        barrier_list.push(graph.final_barrier(pool));
+
+        (wave_list, barrier_list)
+    }
+
+    fn import(graph: &mut RenderGraph, raw: u64, access: Access) -> GraphImage {
+        graph.import_image(
+            vk::Image::from_raw(raw),
+            vk::ImageView::null(),
+            vk::ImageAspectFlags::COLOR,
+            access,
+        )
+    }
+
+    fn pass(graph: &mut RenderGraph, desc: PassDesc) {
+        graph.add_pass(desc, |_, _| {});
+    }
+
+    #[test]
+    fn read_then_write() {
+        let mut graph = RenderGraph::new();
+        let image = import(&mut graph, 1, Access::COMPUTE_READ);
+
+        pass(
+            &mut graph,
+            PassDesc::new("read").image(image, Access::COMPUTE_READ),
+        );
+        pass(
+            &mut graph,
+            PassDesc::new("write").image(image, Access::COMPUTE_WRITE),
+        );
+
+        let (wave_list, barrier_list) = plan(&mut graph, &mut TransientPool::default());
+        assert_eq!(wave_list, vec![vec![0], vec![1]]);
+
+        // Nothing was written, the read needs no barrier
+        assert!(barrier_list[0].image_barrier_list.is_empty());
+
+        // Write waits for the read to finish, nothing to make visible
+        let barrier = &barrier_list[1];
+        assert_eq!(barrier.src_stage, vk::PipelineStageFlags::COMPUTE_SHADER);
+        assert_eq!(barrier.dst_stage, vk::PipelineStageFlags::COMPUTE_SHADER);
+        assert_eq!(barrier.image_barrier_list.len(), 1);
+        let image_barrier = barrier.image_barrier_list[0];
+        assert_eq!(image_barrier.src_access_mask, vk::AccessFlags::empty());
+        assert_eq!(image_barrier.dst_access_mask, vk::AccessFlags::SHADER_WRITE);
+        assert_eq!(image_barrier.old_layout, vk::ImageLayout::GENERAL);
+        assert_eq!(image_barrier.new_layout, vk::ImageLayout::GENERAL);
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut graph = RenderGraph::new();
+        let image = import(&mut graph, 1, Access::COMPUTE_READ);
+
+        pass(
+            &mut graph,
+            PassDesc::new("write").image(image, Access::COMPUTE_WRITE),
+        );
+        pass(
+            &mut graph,
+            PassDesc::new("read").image(image, Access::FRAGMENT_SAMPLED),
+        );
+
+        let (wave_list, barrier_list) = plan(&mut graph, &mut TransientPool::default());
+        assert_eq!(wave_list, vec![vec![0], vec![1]]);
+
+        let barrier = &barrier_list[1];
+        assert_eq!(barrier.src_stage, vk::PipelineStageFlags::COMPUTE_SHADER);
+        assert_eq!(barrier.dst_stage, vk::PipelineStageFlags::FRAGMENT_SHADER);
+        let image_barrier = barrier.image_barrier_list[0];
+        assert_eq!(image_barrier.src_access_mask, vk::AccessFlags::SHADER_WRITE);
+        assert_eq!(image_barrier.dst_access_mask, vk::AccessFlags::SHADER_READ);
+        assert_eq!(image_barrier.old_layout, vk::ImageLayout::GENERAL);
+        assert_eq!(
+            image_barrier.new_layout,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+        );
+
+        // Already in the export access, no final barrier
+        graph.export_image(image, Access::FRAGMENT_SAMPLED);
+        assert!(graph
+            .final_barrier(&mut TransientPool::default())
+            .image_barrier_list
+            .is_empty());
+    }
+
+    #[test]
+    fn layout_change_with_discard() {
+        let mut pool = TransientPool::default();
+
+        for frame in 0..2 {
+            let mut graph = RenderGraph::new();
+            let image = graph.create_image(DESC);
+
+            pass(
+                &mut graph,
+                PassDesc::new("draw").image(image, Access::COLOR_ATTACHMENT),
+            );
+            pass(
+                &mut graph,
+                PassDesc::new("sample").image(image, Access::FRAGMENT_SAMPLED),
+            );
+
+            let (_, barrier_list) = plan(&mut graph, &mut pool);
+
+            // Content of the last frame is dropped, not transitioned
+            let image_barrier = barrier_list[0].image_barrier_list[0];
+            assert_eq!(image_barrier.old_layout, vk::ImageLayout::UNDEFINED);
+            assert_eq!(
+                image_barrier.new_layout,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+            );
+
+            // Second frame still waits for the sampling of the first
+            let src_stage = if frame == 0 {
+                vk::PipelineStageFlags::TOP_OF_PIPE
+            } else {
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+            };
+            assert_eq!(barrier_list[0].src_stage, src_stage);
+
+            let image_barrier = barrier_list[1].image_barrier_list[0];
+            assert_eq!(
+                image_barrier.old_layout,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+            );
+            assert_eq!(
+                image_barrier.src_access_mask,
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            );
+        }
+
+        assert_eq!(pool.image_list.len(), 1);
+    }
+
+    #[test]
+    fn same_wave_readers() {
+        let mut graph = RenderGraph::new();
+        let image = import(&mut graph, 1, Access::COMPUTE_READ);
+
+        pass(
+            &mut graph,
+            PassDesc::new("write").image(image, Access::COMPUTE_WRITE),
+        );
+        pass(
+            &mut graph,
+            PassDesc::new("fragment").image(image, Access::FRAGMENT_SAMPLED),
+        );
+        pass(
+            &mut graph,
+            PassDesc::new("vertex").image(image, VERTEX_SAMPLED),
+        );
+        pass(
+            &mut graph,
+            PassDesc::new("rewrite").image(image, Access::COMPUTE_WRITE),
+        );
+
+        let (wave_list, barrier_list) = plan(&mut graph, &mut TransientPool::default());
+        assert_eq!(wave_list, vec![vec![0], vec![1, 2], vec![3]]);
+
+        // Both readers share one transition
+        let barrier = &barrier_list[1];
+        assert_eq!(barrier.image_barrier_list.len(), 1);
+        assert_eq!(
+            barrier.dst_stage,
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::VERTEX_SHADER
+        );
+
+        // Next write waits on both of them
+        let barrier = &barrier_list[2];
+        assert!(barrier.src_stage.contains(
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::VERTEX_SHADER
+        ));
+        assert_eq!(
+            barrier.image_barrier_list[0].old_layout,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+        );
+    }
+
+    #[test]
+    fn transient_reuse_across_waves() {
+        let mut graph = RenderGraph::new();
+        let first = graph.create_image(DESC);
+        let second = graph.create_image(DESC);
+        let third = graph.create_image(DESC);
+        let output = import(&mut graph, 1, Access::UNDEFINED);
+
+        pass(
+            &mut graph,
+            PassDesc::new("first").image(first, Access::COLOR_ATTACHMENT),
+        );
+        pass(
+            &mut graph,
+            PassDesc::new("second")
+                .image(first, Access::FRAGMENT_SAMPLED)
+                .image(second, Access::COLOR_ATTACHMENT),
+        );
+        pass(
+            &mut graph,
+            PassDesc::new("third")
+                .image(second, Access::FRAGMENT_SAMPLED)
+                .image(third, Access::COLOR_ATTACHMENT),
+        );
+        pass(
+            &mut graph,
+            PassDesc::new("output")
+                .image(third, Access::FRAGMENT_SAMPLED)
+                .image(output, Access::COLOR_ATTACHMENT),
+        );
+
+        let mut pool = TransientPool::default();
+        let (wave_list, barrier_list) = plan(&mut graph, &mut pool);
+        assert_eq!(wave_list, vec![vec![0], vec![1], vec![2], vec![3]]);
+
+        // First is done after wave 1, third takes its image
+        assert_eq!(pool.image_list.len(), 2);
+        let pool_idx = |image: GraphImage| graph.image_list[image.0].pool_idx;
+        assert_eq!(pool_idx(first), pool_idx(third));
+        assert_ne!(pool_idx(first), pool_idx(second));
+
+        // Third starts undefined after the reads of first
+        let barrier = &barrier_list[2];
+        let image_barrier = barrier
+            .image_barrier_list
+            .iter()
+            .find(|barrier| barrier.new_layout == vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .unwrap();
+        assert_eq!(image_barrier.old_layout, vk::ImageLayout::UNDEFINED);
+        assert!(barrier
+            .src_stage
+            .contains(vk::PipelineStageFlags::FRAGMENT_SHADER));
+    }
+
+    #[test]
+    fn mip_levels() {
+        let mut graph = RenderGraph::new();
+        let image = graph.import_mip_image(
+            vk::Image::from_raw(1),
+            vk::ImageView::null(),
+            vk::ImageAspectFlags::COLOR,
+            3,
+            Access::FRAGMENT_SAMPLED,
+        );
+
+        for level in 1..3 {
+            let desc = PassDesc::new("mip")
+                .image(image, Access::COMPUTE_READ.mip(level - 1, 1))
+                .image(image, Access::COMPUTE_WRITE.mip(level, 1));
+            pass(&mut graph, desc);
+        }
+        graph.export_image(image, Access::FRAGMENT_SAMPLED);
+
+        let (wave_list, barrier_list) = plan(&mut graph, &mut TransientPool::default());
+        assert_eq!(wave_list, vec![vec![0], vec![1]]);
+
+        // Second level is written, then read by the next pass
+        let image_barrier = barrier_list[1].image_barrier_list[0];
+        assert_eq!(image_barrier.subresource_range.base_mip_level, 1);
+        assert_eq!(image_barrier.subresource_range.level_count, 1);
+        assert_eq!(image_barrier.src_access_mask, vk::AccessFlags::SHADER_WRITE);
+
+        // First level was only read, the others were written
+        let range_list: Vec<(u32, u32)> = barrier_list[2]
+            .image_barrier_list
+            .iter()
+            .map(|barrier| {
+                let range = barrier.subresource_range;
+                (range.base_mip_level, range.level_count)
+            })
+            .collect();
+        assert_eq!(range_list, vec![(0, 1), (1, vk::REMAINING_MIP_LEVELS)]);
+    }
+}
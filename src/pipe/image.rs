@@ -1,13 +1,18 @@
 use ash::{vk, Device};
 
-use crate::interface::{interface::Interface, phydev::PhyDeviceGroup};
+use crate::interface::{
+    alloc::{Allocation, ResourceKind},
+    interface::Interface,
+};
 
 #[derive(Clone)]
 pub struct ImageTarget {
     pub img: vk::Image,
     pub view: vk::ImageView,
+    // One view per mip level, for writing single levels as storage image
+    pub mip_view_list: Vec<vk::ImageView>,
 
-    pub mem: vk::DeviceMemory,
+    pub alloc: Allocation,
     pub mem_req: vk::MemoryRequirements,
     pub sampler: vk::Sampler,
 }
@@ -20,6 +25,15 @@ pub const SUBRES_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
     layer_count: 1,
 };
 
+/// Color range over level_count mip levels starting at base_level.
+pub fn mip_subres_range(base_level: u32, level_count: u32) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        base_mip_level: base_level,
+        level_count,
+        ..SUBRES_RANGE
+    }
+}
+
 pub const COMP_MAP: vk::ComponentMapping = vk::ComponentMapping {
     r: vk::ComponentSwizzle::R,
     g: vk::ComponentSwizzle::G,
@@ -46,27 +60,27 @@ impl ImageTarget {
         }
     }
 
-    pub fn create_img_memory(&self, device: &Device, phy_device: &PhyDeviceGroup) -> Self {
+    /// Sub-allocate memory for the image, large images get a
+    /// dedicated allocation.
+    pub fn create_img_memory(&self, interface: &Interface) -> Self {
         unsafe {
             let mut result = self.clone();
 
-            // Get Memory Requirement for Image and the MemoryTypeIndex
-            result.mem_req = device.get_image_memory_requirements(result.img);
-            let mem_index = phy_device
-                .find_memorytype_index(&result.mem_req, vk::MemoryPropertyFlags::DEVICE_LOCAL)
-                .expect("NO_SUITABLE_MEM_TYPE_INDEX");
-
-            // Prepare MemoryAllocation
-            let allocate_info = vk::MemoryAllocateInfo::builder()
-                .allocation_size(result.mem_req.size)
-                .memory_type_index(mem_index)
-                .build();
-
-            // Allocate Memory
-            result.mem = device.allocate_memory(&allocate_info, None).unwrap();
-
-            device
-                .bind_image_memory(result.img, result.mem, 0)
+            // Get Memory Requirement for Image
+            result.mem_req = interface.device.get_image_memory_requirements(result.img);
+
+            result.alloc = interface.allocator.borrow_mut().alloc(
+                &interface.device,
+                &interface.phy_device,
+                &result.mem_req,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                ResourceKind::Image,
+                Some(result.img),
+            );
+
+            interface
+                .device
+                .bind_image_memory(result.img, result.alloc.mem, result.alloc.offset)
                 .expect("UNABLE_TO_BIND_MEM");
 
             result
@@ -98,7 +112,7 @@ impl ImageTarget {
             result
         }
     }
-    
+
     /// Create new image target with image, image view, image memory and
     /// image sampler. It is only intended to be used as two dimensional image.
     /// Will return new image target object.
@@ -144,7 +158,7 @@ impl ImageTarget {
 
             result = result
                 .create_img(img_info, &interface.device)
-                .create_img_memory(&interface.device, &interface.phy_device)
+                .create_img_memory(interface)
                 .create_sampler(sampler_info, &interface.device)
                 .create_view(view_info, &interface.device);
 
@@ -159,6 +173,7 @@ impl ImageTarget {
         img_type: vk::ImageType,
         view_type: vk::ImageViewType,
         array_len: u32,
+        mip_levels: u32,
     ) -> Self {
         unsafe {
             let mut result = Self::default();
@@ -166,11 +181,16 @@ impl ImageTarget {
             let img_info = vk::ImageCreateInfo::builder()
                 .format(format)
                 .extent(extent)
-                .mip_levels(1)
+                .mip_levels(mip_levels)
                 .array_layers(array_len)
                 .samples(vk::SampleCountFlags::TYPE_1)
                 .tiling(vk::ImageTiling::OPTIMAL)
-                .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE)
+                .usage(
+                    vk::ImageUsageFlags::TRANSFER_SRC
+                        | vk::ImageUsageFlags::TRANSFER_DST
+                        | vk::ImageUsageFlags::SAMPLED
+                        | vk::ImageUsageFlags::STORAGE,
+                )
                 .sharing_mode(vk::SharingMode::EXCLUSIVE)
                 .initial_layout(vk::ImageLayout::UNDEFINED)
                 .image_type(img_type)
@@ -187,23 +207,35 @@ impl ImageTarget {
                 .max_anisotropy(1.0)
                 .compare_op(vk::CompareOp::NEVER)
                 .min_lod(0.0)
-                .max_lod(1.0)
+                .max_lod(mip_levels as f32)
                 .border_color(vk::BorderColor::FLOAT_OPAQUE_WHITE)
                 .build();
 
             let view_info = vk::ImageViewCreateInfo::builder()
                 .view_type(view_type)
                 .format(img_info.format)
-                .subresource_range(SUBRES_RANGE)
+                .subresource_range(mip_subres_range(0, mip_levels))
                 .components(COMP_MAP)
                 .build();
 
             result = result
                 .create_img(img_info, &interface.device)
-                .create_img_memory(&interface.device, &interface.phy_device)
+                .create_img_memory(interface)
                 .create_sampler(sampler_info, &interface.device)
                 .create_view(view_info, &interface.device);
 
+            if mip_levels > 1 {
+                result.mip_view_list = (0..mip_levels)
+                    .map(|level| {
+                        let mut info = view_info;
+                        info.image = result.img;
+                        info.subresource_range = mip_subres_range(level, 1);
+
+                        interface.device.create_image_view(&info, None).unwrap()
+                    })
+                    .collect();
+            }
+
             result
         }
     }
@@ -248,7 +280,7 @@ impl ImageTarget {
 
         result = result
             .create_img(img_info, &interface.device)
-            .create_img_memory(&interface.device, &interface.phy_device)
+            .create_img_memory(interface)
             .create_sampler(sampler_info, &interface.device)
             .create_view(view_info, &interface.device);
 
@@ -285,19 +317,36 @@ impl ImageTarget {
 
             result = result
                 .create_img(img_info, &interface.device)
-                .create_img_memory(&interface.device, &interface.phy_device)
+                .create_img_memory(interface)
                 .create_view(view_info, &interface.device);
 
             result
         }
     }
 
-    /// Destroy image and image view
+    /// Same image with the view of a single mip level, used to
+    /// bind one level as storage image.
+    pub fn mip_img(&self, level: u32) -> Self {
+        let mut result = self.clone();
+        result.view = self.mip_view_list[level as usize];
+
+        result
+    }
+
+    /// Destroy image, its views and sampler
 
-    pub fn destroy(&self, device: &Device) {
+    pub fn destroy(&self, interface: &Interface) {
         unsafe {
-            device.destroy_image_view(self.view, None);
-            device.destroy_image(self.img, None);
+            for &view in &self.mip_view_list {
+                interface.device.destroy_image_view(view, None);
+            }
+            interface.device.destroy_image_view(self.view, None);
+            interface.device.destroy_sampler(self.sampler, None);
+            interface.device.destroy_image(self.img, None);
+            interface
+                .allocator
+                .borrow_mut()
+                .free(&interface.device, &self.alloc);
         }
     }
 }
@@ -307,7 +356,8 @@ impl Default for ImageTarget {
         Self {
             img: Default::default(),
             view: Default::default(),
-            mem: Default::default(),
+            mip_view_list: Default::default(),
+            alloc: Default::default(),
             mem_req: Default::default(),
             sampler: Default::default(),
         }
@@ -1,6 +1,15 @@
+pub mod atlas;
 pub mod buffer;
+pub mod capture;
 pub mod descriptor;
 pub mod engine;
+pub mod graph;
 pub mod image;
+pub mod obj;
 pub mod pipe;
-pub mod obj;
\ No newline at end of file
+pub mod profiler;
+pub mod reflect;
+pub mod reload;
+pub mod shader;
+pub mod software;
+pub mod upload;
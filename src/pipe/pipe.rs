@@ -1,24 +1,27 @@
-use std::{ffi::CString, io::Cursor, mem};
+use std::{error::Error, ffi::CString, mem, ops::Range};
 
-use ash::{util::read_spv, vk::{self, PushConstantRange}, Device};
-use cgmath::num_traits::Pow;
-use nalgebra_glm::{Vec2, Vec3, Vec4};
+use ash::{
+    vk::{self, PushConstantRange},
+    Device,
+};
+use nalgebra_glm::{Vec3, Vec4};
 
 use crate::{
     interface::{interface::Interface, surface::SurfaceGroup},
-    offset_of,
+    mask_to_vec, offset_of,
     pipe::obj::{BASE_CUBE_IDX, BASE_CUBE_UV, BASE_CUBE_VERT},
     tree::{
         octant::Octant,
-        octree::{Octree, MAX_DEPTH, MAX_DEPTH_LIMIT, TEXTURE_ALIGN},
+        octree::{Octree, MAX_DEPTH, MAX_DEPTH_LIMIT, PROXY_DEPTH},
+        trace::{BranchInfo, PosInfo},
     },
     vector::Vector,
     Pref,
 };
 
-use super::{descriptor::DescriptorPool, image::ImageTarget};
+use super::{atlas::MISSING_SLOT, descriptor::DescriptorPool, reflect::ShaderReflection};
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq)]
 pub struct Vertex {
     pub pos: [f32; 4],
     pub pos_on_edge: [f32; 4],
@@ -26,14 +29,30 @@ pub struct Vertex {
     pub loc_idx: u32,
 }
 
-#[derive(Clone, Debug, Copy)]
+impl Vertex {
+    /// Offset of the field read by every vertex input location.
+    pub fn offset_list() -> [u32; 4] {
+        [
+            offset_of!(Vertex, pos) as u32,
+            offset_of!(Vertex, pos_on_edge) as u32,
+            offset_of!(Vertex, uv) as u32,
+            offset_of!(Vertex, loc_idx) as u32,
+        ]
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq)]
 pub struct LocInfo {
     pub parent_list: [u32; MAX_DEPTH_LIMIT],
     pub last_hit_idx: [u32; MAX_DEPTH_LIMIT],
     pub depth: u32,
     pub span: f32,
 
-    padding: [u32; 2],
+    // Atlas slot of the brick, MISSING_SLOT if not resident
+    pub brick_slot: u32,
+    padding: u32,
+    // Corner of the brick the proxy lies in, w unused
+    pub brick_origin: [f32; 4],
 }
 
 #[derive(Clone)]
@@ -56,14 +75,16 @@ pub struct Pipe {
 
 #[derive(Clone, Debug, Copy)]
 pub struct JFAPush {
-    pub px_per_group: Vec2,
+    pub step: u32,
 }
 
-// "../../shader/comp.spv"
-// include_bytes!("../../shader/comp.spv")
-
 impl Pipe {
-    pub fn create_layout(&self, descriptor_pool: &DescriptorPool, push_constant_list: &[PushConstantRange], device: &Device) -> Self {
+    pub fn create_layout(
+        &self,
+        descriptor_pool: &DescriptorPool,
+        push_constant_list: &[PushConstantRange],
+        device: &Device,
+    ) -> Result<Self, vk::Result> {
         unsafe {
             let mut result = self.clone();
 
@@ -73,25 +94,39 @@ impl Pipe {
                 .build();
 
             log::info!("Creating PipelineLayout ...");
-            result.pipe_layout = device.create_pipeline_layout(&info, None).unwrap();
+            result.pipe_layout = device.create_pipeline_layout(&info, None)?;
 
-            result
+            Ok(result)
         }
     }
 
-    pub fn create_comp_pipe(device: &Device, pool: &DescriptorPool, push_constant_list: &[PushConstantRange]) -> Self {
+    /// Compute pipe of comp_code, fails if the shader uses resources
+    /// the set layouts of pool don't provide.
+    pub fn create_comp_pipe(
+        device: &Device,
+        pipe_cache: vk::PipelineCache,
+        pool: &DescriptorPool,
+        comp_code: &[u32],
+    ) -> Result<Self, Box<dyn Error>> {
         unsafe {
             let mut result = Self::default();
 
-            log::info!("Getting ShaderCode ...");
-            let mut spv = Cursor::new(&include_bytes!("../../shader/JFA.spv")[..]);
+            let reflection = ShaderReflection::new(comp_code, vk::ShaderStageFlags::COMPUTE)?;
+            pool.check_reflection(&reflection)?;
 
-            let code = read_spv(&mut spv).expect("ERR_READ_COMP_SPV");
-            let shader_info = vk::ShaderModuleCreateInfo::builder().code(&code);
+            result = result.create_layout(pool, &reflection.push_constant_list(), device)?;
 
-            let shader_module = device
-                .create_shader_module(&shader_info, None)
-                .expect("ERR_COMP_MODULE");
+            let shader_info = vk::ShaderModuleCreateInfo::builder().code(comp_code);
+
+            // Driver errors are returned like reflection errors, the
+            // caller keeps its old pipe
+            let shader_module = match device.create_shader_module(&shader_info, None) {
+                Ok(module) => module,
+                Err(err) => {
+                    result.drop(device);
+                    return Err(err.into());
+                }
+            };
 
             log::info!("Stage Creation ...");
             let shader_entry_name = CString::new("main").unwrap();
@@ -102,143 +137,331 @@ impl Pipe {
                 ..Default::default()
             };
 
-            result = result.create_layout(pool, push_constant_list, device);
-
             let compute_pipe_info = vk::ComputePipelineCreateInfo::builder()
                 .stage(shader_stage)
                 .layout(result.pipe_layout)
                 .build();
 
-            result.pipe = device
-                .create_compute_pipelines(vk::PipelineCache::null(), &[compute_pipe_info], None)
-                .expect("ERROR_CREATE_PIPELINE")[0];
+            let pipe_list = device.create_compute_pipelines(pipe_cache, &[compute_pipe_info], None);
+
+            // Not needed once the pipeline exists, reloads would leak them otherwise
+            device.destroy_shader_module(shader_module, None);
 
-            result
+            result.pipe = match pipe_list {
+                Ok(pipe_list) => pipe_list[0],
+                Err((_, err)) => {
+                    result.drop(device);
+                    return Err(err.into());
+                }
+            };
+
+            Ok(result)
         }
     }
 
-    pub fn get_octree_vert_data(
+    pub fn get_octree_vert_data(octree: &Octree) -> (Vec<Vertex>, Vec<u32>, Vec<LocInfo>) {
+        let (branch_data, pos_info) = octree.get_new_root_info(Vec4::default());
+        let (vertex_data, loc_data) = Self::get_proxy_data(octree, &branch_data, &pos_info, 0);
+        let index_data = Self::get_proxy_index_data(0..loc_data.len());
+
+        (vertex_data, index_data, loc_data)
+    }
+
+    /// Indices of the proxies in loc_range, they only depend on
+    /// the position of a proxy in the list.
+    pub fn get_proxy_index_data(loc_range: Range<usize>) -> Vec<u32> {
+        loc_range
+            .flat_map(|leaf_idx| {
+                BASE_CUBE_IDX
+                    .iter()
+                    .map(move |idx| (idx + (leaf_idx as i32) * 24) as u32)
+            })
+            .collect()
+    }
+
+    /// Vertices and location info of the proxies below the node
+    /// of pos_info, numbered from first_loc_idx on.
+    fn get_proxy_data(
         octree: &Octree,
-        img: &mut image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
-    ) -> (Vec<Vertex>, Vec<u32>, Vec<LocInfo>) {
+        branch_data: &[BranchInfo; MAX_DEPTH],
+        pos_info: &PosInfo,
+        first_loc_idx: usize,
+    ) -> (Vec<Vertex>, Vec<LocInfo>) {
         let mut vertex_data = vec![];
-        let mut index_data = vec![];
         let mut loc_data = vec![];
 
-        let (branch_data, pos_info) = octree.get_new_root_info(Vec4::default());
         let mut leaf_data = vec![];
-        octree.collect_branch(&branch_data, &pos_info, &mut leaf_data, 6);
+        octree.collect_branch(branch_data, pos_info, &mut leaf_data, PROXY_DEPTH);
 
         // log::info!("{:#034b}", leaf_data[0].1.node.get_child_bitmask());
 
-        leaf_data
-            .iter()
-            .enumerate()
-            .for_each(|(leaf_idx, (pos_info, loc_branch_data))| {
-                let branch_info = loc_branch_data[pos_info.depth_idx()];
-                let center = pos_info.local_pos.xyz() * 2.0 + Vec3::ftv(branch_info.span / 2.0);
-
-                let length = TEXTURE_ALIGN.pow(2) as f32 * leaf_idx as f32;
-                let base_px = Vec2::new(
-                    (length / img.height() as f32).floor() * TEXTURE_ALIGN,
-                    length % img.height() as f32,
-                );
-
-                octree.write_branch_to_texture(
-                    loc_branch_data,
-                    pos_info,
-                    img,
-                    base_px,
-                    pos_info.local_pos,
-                    TEXTURE_ALIGN,
-                    MAX_DEPTH as u32,
-                );
-
-                BASE_CUBE_VERT
-                    .iter()
-                    .enumerate()
-                    .for_each(|(vert_idx, coord)| {
-                        vertex_data.push(Vertex {
-                            pos: [
-                                coord.0 * branch_info.span + center.x,
-                                coord.1 * branch_info.span + center.y,
-                                coord.2 * branch_info.span + center.z,
-                                1.0,
-                            ],
-                            pos_on_edge: [
-                                pos_info.local_pos.x,
-                                pos_info.local_pos.y,
-                                pos_info.local_pos.z,
-                                0.0,
-                            ],
-                            uv: [
-                                BASE_CUBE_UV[vert_idx].0 as f32,
-                                BASE_CUBE_UV[vert_idx].1 as f32,
-                            ],
-                            loc_idx: loc_data.len() as u32,
-                        });
+        leaf_data.iter().for_each(|(pos_info, loc_branch_data)| {
+            let branch_info = loc_branch_data[pos_info.depth_idx()];
+
+            // Corner of the proxy, accumulated from the child
+            // masks on the way down
+            let pos_on_edge = loc_branch_data[..=pos_info.depth_idx()]
+                .iter()
+                .fold(Vec3::zeros(), |pos, branch| {
+                    pos + mask_to_vec!(branch.mask).xyz() * branch.span
+                });
+            let center = pos_on_edge + Vec3::ftv(branch_info.span / 2.0);
+
+            BASE_CUBE_VERT
+                .iter()
+                .enumerate()
+                .for_each(|(vert_idx, coord)| {
+                    vertex_data.push(Vertex {
+                        pos: [
+                            coord.0 * branch_info.span + center.x,
+                            coord.1 * branch_info.span + center.y,
+                            coord.2 * branch_info.span + center.z,
+                            1.0,
+                        ],
+                        pos_on_edge: [pos_on_edge.x, pos_on_edge.y, pos_on_edge.z, 0.0],
+                        uv: [
+                            BASE_CUBE_UV[vert_idx].0 as f32,
+                            BASE_CUBE_UV[vert_idx].1 as f32,
+                        ],
+                        loc_idx: (first_loc_idx + loc_data.len()) as u32,
                     });
+                });
 
-                BASE_CUBE_IDX
-                    .iter()
-                    .for_each(|idx| index_data.push((idx + (leaf_idx as i32) * 24) as u32));
+            let mut parent_list = [0; MAX_DEPTH_LIMIT];
+
+            // set to something that is not an actual index to indicate
+            // wether there is an active index in use or not
+            let mut last_hit_idx = [8; MAX_DEPTH_LIMIT];
 
-                let mut parent_list = [0; MAX_DEPTH_LIMIT];
+            loc_branch_data
+                .iter()
+                .enumerate()
+                .for_each(|(idx, branch_info)| {
+                    parent_list[idx] = branch_info.node;
+                    last_hit_idx[idx] = branch_info.mask;
+                });
 
-                // set to something that is not an actual index to indicate
-                // wether there is an active index in use or not
-                let mut last_hit_idx = [8; MAX_DEPTH_LIMIT];
+            loc_data.push(LocInfo {
+                parent_list,
+                last_hit_idx,
+                depth: pos_info.depth,
+                span: branch_info.span,
+                brick_slot: MISSING_SLOT,
 
-                loc_branch_data
-                    .iter()
-                    .enumerate()
-                    .for_each(|(idx, branch_info)| {
-                        parent_list[idx] = branch_info.node;
-                        last_hit_idx[idx] = branch_info.mask;
-                    });
+                ..Default::default()
+            });
+        });
 
-                loc_data.push(LocInfo {
-                    parent_list,
-                    last_hit_idx,
-                    depth: pos_info.depth,
-                    span: branch_info.span,
+        (vertex_data, loc_data)
+    }
 
-                    ..Default::default()
+    /// Rebuild the proxies below the topmost node each edit changed
+    /// and splice them into vertex and location data. Proxies store
+    /// the nodes down to the proxy depth, edits below it change
+    /// none. Returns the replaced location range and the new proxy
+    /// count of every splice, in the order they were applied.
+    pub fn update_octree_vert_data(
+        octree: &Octree,
+        node_range_list: &[Range<usize>],
+        edit_pos_list: &[Vec4],
+        vertex_data: &mut Vec<Vertex>,
+        loc_data: &mut Vec<LocInfo>,
+    ) -> Vec<(Range<usize>, usize)> {
+        let is_dirty = |idx: usize| {
+            let range_idx = node_range_list.partition_point(|range| range.end <= idx);
+            node_range_list
+                .get(range_idx)
+                .is_some_and(|range| range.contains(&idx))
+        };
+
+        // (first key, key count, branch data, pos info) of every subtree
+        let mut root_list = vec![];
+
+        for pos in edit_pos_list {
+            let (mut branch_data, mut pos_info) = octree.get_new_root_info(*pos);
+            let mut parent = (branch_data, pos_info);
+
+            loop {
+                let branch = pos_info.branch(&branch_data);
+
+                if is_dirty(branch.idx()) {
+                    // Proxies of a node are built from its parent, the
+                    // root has none and rebuilds everything
+                    let (branch_data, pos_info) = match pos_info.depth {
+                        0 => (branch_data, pos_info),
+                        _ => parent,
+                    };
+                    let depth = pos_info.depth_idx();
+
+                    root_list.push((
+                        Self::proxy_key(octree, Self::branch_corner(&branch_data, depth)),
+                        8u32.pow((MAX_DEPTH - depth) as u32),
+                        branch_data,
+                        pos_info,
+                    ));
+                    break;
+                }
+
+                if pos_info.depth + 1 >= PROXY_DEPTH || !branch.node.is_subdiv() {
+                    break;
+                }
+
+                parent = (branch_data, pos_info);
+                pos_info.move_into_child(&mut branch_data, |mut branch| {
+                    (branch.idx, branch.node) = branch.get_child(&octree.octant_data, branch.mask);
+
+                    branch
                 });
-            });
+            }
+        }
 
-        (vertex_data, index_data, loc_data)
+        // Subtrees nested in an earlier one are rebuilt with it
+        root_list.sort_by_key(|root| (root.0, u32::MAX - root.1));
+        root_list.dedup_by(|root, last| root.0 < last.0 + last.1);
+
+        let mut splice_list = vec![];
+
+        for (first_key, key_count, branch_data, pos_info) in root_list {
+            let start = Self::proxy_partition(octree, vertex_data, loc_data, first_key);
+            let end = Self::proxy_partition(octree, vertex_data, loc_data, first_key + key_count);
+
+            let (proxy_vertex_data, proxy_loc_data) =
+                Self::get_proxy_data(octree, &branch_data, &pos_info, start);
+            let proxy_count = proxy_loc_data.len();
+
+            vertex_data.splice(
+                start * BASE_CUBE_VERT.len()..end * BASE_CUBE_VERT.len(),
+                proxy_vertex_data,
+            );
+            loc_data.splice(start..end, proxy_loc_data);
+
+            splice_list.push((start..end, proxy_count));
+        }
+
+        // Proxies behind a splice which changed the count moved
+        if let Some(first_moved) = splice_list
+            .iter()
+            .find(|(range, count)| range.len() != *count)
+            .map(|(range, count)| range.start + count)
+        {
+            vertex_data
+                .chunks_mut(BASE_CUBE_VERT.len())
+                .enumerate()
+                .skip(first_moved)
+                .for_each(|(loc_idx, vert_list)| {
+                    vert_list
+                        .iter_mut()
+                        .for_each(|vert| vert.loc_idx = loc_idx as u32)
+                });
+        }
+
+        splice_list
+    }
+
+    fn branch_corner(branch_data: &[BranchInfo; MAX_DEPTH], depth: usize) -> Vec3 {
+        branch_data[..=depth]
+            .iter()
+            .fold(Vec3::zeros(), |pos, branch| {
+                pos + mask_to_vec!(branch.mask).xyz() * branch.span
+            })
     }
 
+    /// Position of the cube at corner in depth first order, its
+    /// child masks from the root down, interleaved at leaf size.
+    /// Proxies are listed in this order by the node they were
+    /// built for.
+    fn proxy_key(octree: &Octree, corner: Vec3) -> u32 {
+        let cell = (corner / octree.root_span * (1 << MAX_DEPTH) as f32).map(|val| val as u32);
+
+        (0..MAX_DEPTH).rev().fold(0, |key, shift| {
+            let mask = ((cell.x >> shift) & 1)
+                | (((cell.y >> shift) & 1) << 1)
+                | (((cell.z >> shift) & 1) << 2);
+
+            (key << 3) | mask
+        })
+    }
+
+    /// First proxy whose key is not below key.
+    fn proxy_partition(
+        octree: &Octree,
+        vertex_data: &[Vertex],
+        loc_data: &[LocInfo],
+        key: u32,
+    ) -> usize {
+        let proxy_key = |loc_idx: usize| {
+            let loc_info = &loc_data[loc_idx];
+            let pos_on_edge = vertex_data[loc_idx * BASE_CUBE_VERT.len()].pos_on_edge;
+
+            // The node the proxy was built for is a child of its cube
+            let child_mask = loc_info.last_hit_idx[loc_info.depth as usize + 1];
+            let corner = Vec3::new(pos_on_edge[0], pos_on_edge[1], pos_on_edge[2])
+                + mask_to_vec!(child_mask).xyz() * loc_info.span * 0.5;
+
+            Self::proxy_key(octree, corner)
+        };
+
+        let (mut low, mut high) = (0, loc_data.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if proxy_key(mid) < key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        low
+    }
+
+    /// Graphic pipe drawing Vertex, fails if the shaders use resources
+    /// the set layouts of pool don't provide or their vertex inputs
+    /// don't fit the fields of Vertex.
     pub fn create_graphic_pipe(
         device: &Device,
+        pipe_cache: vk::PipelineCache,
         surface: &SurfaceGroup,
         pool: &DescriptorPool,
-        push_constant_list: &[PushConstantRange],
-    ) -> Self {
+        vert_code: &[u32],
+        frag_code: &[u32],
+    ) -> Result<Self, Box<dyn Error>> {
         unsafe {
             let mut result = Self::default();
 
-            log::info!("Getting ShaderCode ...");
-            let mut vert_spv = Cursor::new(&include_bytes!("../../shader/vert.spv")[..]);
-            let mut frag_spv = Cursor::new(&include_bytes!("../../shader/tex_frag.spv")[..]);
+            let reflection = ShaderReflection::merge(&[
+                ShaderReflection::new(vert_code, vk::ShaderStageFlags::VERTEX)?,
+                ShaderReflection::new(frag_code, vk::ShaderStageFlags::FRAGMENT)?,
+            ])?;
+            pool.check_reflection(&reflection)?;
+            let vertex_attrib_list = reflection
+                .vertex_attrib_list(&Vertex::offset_list(), mem::size_of::<Vertex>() as u32)?;
 
-            let vert_code = read_spv(&mut vert_spv).expect("ERR_READ_VERTEX_SPV");
-            let frag_code = read_spv(&mut frag_spv).expect("ERR_READ_FRAG_SPV");
+            result = result.create_layout(pool, &reflection.push_constant_list(), device)?;
 
             let vert_shader_info = vk::ShaderModuleCreateInfo::builder()
-                .code(&vert_code)
+                .code(vert_code)
                 .build();
             let frag_shader_info = vk::ShaderModuleCreateInfo::builder()
-                .code(&frag_code)
+                .code(frag_code)
                 .build();
 
-            let vert_shader_module = device
-                .create_shader_module(&vert_shader_info, None)
-                .expect("ERR_VERTEX_MODULE");
-            let frag_shader_module = device
-                .create_shader_module(&frag_shader_info, None)
-                .expect("ERR_VERTEX_MODULE");
+            // Driver errors are returned like reflection errors, the
+            // caller keeps its old pipe
+            let vert_shader_module = match device.create_shader_module(&vert_shader_info, None) {
+                Ok(module) => module,
+                Err(err) => {
+                    result.drop(device);
+                    return Err(err.into());
+                }
+            };
+            let frag_shader_module = match device.create_shader_module(&frag_shader_info, None) {
+                Ok(module) => module,
+                Err(err) => {
+                    device.destroy_shader_module(vert_shader_module, None);
+                    result.drop(device);
+                    return Err(err.into());
+                }
+            };
 
             log::info!("Stage Creation ...");
             let shader_entry_name = CString::new("main").unwrap();
@@ -258,41 +481,12 @@ impl Pipe {
                 },
             ];
 
-            result = result.create_layout(pool, push_constant_list, device);
-
             let vertex_binding_list = vec![vk::VertexInputBindingDescription {
                 binding: 0,
                 stride: mem::size_of::<Vertex>() as u32,
                 input_rate: vk::VertexInputRate::VERTEX,
             }];
 
-            let vertex_attrib_list = vec![
-                vk::VertexInputAttributeDescription {
-                    location: 0,
-                    binding: 0,
-                    format: vk::Format::R32G32B32A32_SFLOAT,
-                    offset: offset_of!(Vertex, pos) as u32,
-                },
-                vk::VertexInputAttributeDescription {
-                    location: 1,
-                    binding: 0,
-                    format: vk::Format::R32G32B32A32_SFLOAT,
-                    offset: offset_of!(Vertex, pos_on_edge) as u32,
-                },
-                vk::VertexInputAttributeDescription {
-                    location: 2,
-                    binding: 0,
-                    format: vk::Format::R32G32_SFLOAT,
-                    offset: offset_of!(Vertex, uv) as u32,
-                },
-                vk::VertexInputAttributeDescription {
-                    location: 3,
-                    binding: 0,
-                    format: vk::Format::R32_UINT,
-                    offset: offset_of!(Vertex, loc_idx) as u32,
-                },
-            ];
-
             let vertex_state = vk::PipelineVertexInputStateCreateInfo::builder()
                 .vertex_attribute_descriptions(&vertex_attrib_list)
                 .vertex_binding_descriptions(&vertex_binding_list)
@@ -392,64 +586,22 @@ impl Pipe {
                 .push_next(&mut rendering)
                 .build();
 
-            result.pipe = device
-                .create_graphics_pipelines(vk::PipelineCache::null(), &[graphic_pipe_info], None)
-                .expect("ERROR_CREATE_PIPELINE")[0];
+            let pipe_list =
+                device.create_graphics_pipelines(pipe_cache, &[graphic_pipe_info], None);
 
-            result
-        }
-    }
+            // Not needed once the pipeline exists, reloads would leak them otherwise
+            device.destroy_shader_module(vert_shader_module, None);
+            device.destroy_shader_module(frag_shader_module, None);
 
-    pub fn first_img_barrier(
-        &self,
-        image: &ImageTarget,
-        present_image: vk::Image,
-        device: &Device,
-        cmd_buffer: vk::CommandBuffer,
-    ) {
-        unsafe {
-            let basic_subresource_range = vk::ImageSubresourceRange {
-                aspect_mask: vk::ImageAspectFlags::COLOR,
-                base_mip_level: 0,
-                level_count: 1,
-                base_array_layer: 0,
-                layer_count: 1,
+            result.pipe = match pipe_list {
+                Ok(pipe_list) => pipe_list[0],
+                Err((_, err)) => {
+                    result.drop(device);
+                    return Err(err.into());
+                }
             };
 
-            let comp_write = vk::ImageMemoryBarrier::builder()
-                .image(image.img)
-                .old_layout(vk::ImageLayout::UNDEFINED)
-                .new_layout(vk::ImageLayout::GENERAL)
-                .subresource_range(basic_subresource_range.clone())
-                .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
-                .build();
-
-            let comp_transfer = vk::ImageMemoryBarrier::builder()
-                .image(image.img)
-                .old_layout(vk::ImageLayout::GENERAL)
-                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
-                .subresource_range(basic_subresource_range.clone())
-                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
-                .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
-                .build();
-
-            let swap_transfer = vk::ImageMemoryBarrier::builder()
-                .image(present_image)
-                .old_layout(vk::ImageLayout::UNDEFINED)
-                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-                .subresource_range(basic_subresource_range.clone())
-                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-                .build();
-
-            device.cmd_pipeline_barrier(
-                cmd_buffer,
-                vk::PipelineStageFlags::COMPUTE_SHADER,
-                vk::PipelineStageFlags::ALL_COMMANDS,
-                vk::DependencyFlags::empty(),
-                &[],
-                &[],
-                &[comp_write, comp_transfer, swap_transfer],
-            )
+            Ok(result)
         }
     }
 
@@ -514,42 +666,6 @@ impl Pipe {
         }
     }
 
-    pub fn sec_img_barrier(
-        &self,
-        present_image: vk::Image,
-        device: &Device,
-        cmd_buffer: vk::CommandBuffer,
-    ) {
-        unsafe {
-            let basic_subresource_range = vk::ImageSubresourceRange {
-                aspect_mask: vk::ImageAspectFlags::COLOR,
-                base_mip_level: 0,
-                level_count: 1,
-                base_array_layer: 0,
-                layer_count: 1,
-            };
-
-            let swap_present = vk::ImageMemoryBarrier::builder()
-                .image(present_image)
-                .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-                .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
-                .subresource_range(basic_subresource_range.clone())
-                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-                .dst_access_mask(vk::AccessFlags::MEMORY_READ)
-                .build();
-
-            device.cmd_pipeline_barrier(
-                cmd_buffer,
-                vk::PipelineStageFlags::ALL_COMMANDS,
-                vk::PipelineStageFlags::TOP_OF_PIPE,
-                vk::DependencyFlags::empty(),
-                &[],
-                &[],
-                &[swap_present],
-            )
-        }
-    }
-
     pub fn drop(&self, device: &Device) {
         unsafe {
             device.destroy_pipeline_layout(self.pipe_layout, None);
@@ -575,7 +691,9 @@ impl Default for LocInfo {
             last_hit_idx: Default::default(),
             depth: Default::default(),
             span: Default::default(),
+            brick_slot: MISSING_SLOT,
             padding: Default::default(),
+            brick_origin: Default::default(),
         }
     }
 }
@@ -590,3 +708,64 @@ impl Default for Pipe {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_matches_rebuild(octree: &Octree, vertex_data: &[Vertex], loc_data: &[LocInfo]) {
+        let (full_vertex_data, _, full_loc_data) = Pipe::get_octree_vert_data(octree);
+
+        assert_eq!(vertex_data, &full_vertex_data[..]);
+        assert_eq!(loc_data, &full_loc_data[..]);
+    }
+
+    #[test]
+    fn proxy_update_matches_rebuild() {
+        let mut octree = Octree::default();
+        octree.test_scene();
+        octree.take_dirty();
+
+        let (mut vertex_data, _, mut loc_data) = Pipe::get_octree_vert_data(&octree);
+
+        // (inserted, removed) per batch of edits
+        let batch_list: [(&[Vec4], &[Vec4]); 5] = [
+            // Below the proxy depth of an existing proxy
+            (&[Vec4::new(3.0, 3.0, 3.0, 0.0)], &[]),
+            // New child of the root
+            (&[Vec4::new(100.0, 20.0, 200.0, 0.0)], &[]),
+            (&[Vec4::new(30.0, 30.0, 30.0, 0.0)], &[]),
+            (&[], &[Vec4::new(78.0, 78.0, 78.0, 0.0)]),
+            (
+                &[
+                    Vec4::new(60.0, 5.0, 5.0, 0.0),
+                    Vec4::new(5.0, 60.0, 5.0, 0.0),
+                ],
+                &[Vec4::new(30.0, 30.0, 30.0, 0.0)],
+            ),
+        ];
+
+        for (batch_idx, (insert_list, remove_list)) in batch_list.iter().enumerate() {
+            insert_list.iter().for_each(|&pos| {
+                octree.insert_node(pos);
+            });
+            remove_list
+                .iter()
+                .for_each(|&pos| assert!(octree.remove_node(pos)));
+
+            let (node_range_list, edit_pos_list) = octree.take_dirty();
+            let splice_list = Pipe::update_octree_vert_data(
+                &octree,
+                &node_range_list,
+                &edit_pos_list,
+                &mut vertex_data,
+                &mut loc_data,
+            );
+
+            if batch_idx == 0 {
+                assert!(splice_list.is_empty());
+            }
+            assert_matches_rebuild(&octree, &vertex_data, &loc_data);
+        }
+    }
+}
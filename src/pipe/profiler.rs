@@ -0,0 +1,305 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use ash::{vk, Device};
+
+use crate::interface::interface::Interface;
+
+// Samples every timing is averaged over
+pub const PROFILE_WINDOW: usize = 120;
+// Timestamps of one slot, two per pass
+pub const MAX_PROFILE_QUERY: u32 = 128;
+// Time between two logs of the averages
+pub const PROFILE_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Timestamp queries of one command buffer. They are reset when it
+/// is recorded again, its fence has been waited on by then.
+#[derive(Clone, Debug, Default)]
+pub struct QuerySlot {
+    pub query_pool: vk::QueryPool,
+    // Name of every timestamp pair written since the last reset
+    pub name_list: Vec<&'static str>,
+    // Submitted, but the results were not read yet
+    pub pending: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PassTiming {
+    // Name of the pass, repeated passes of a graph get their index
+    pub label: String,
+    // Milliseconds of the last PROFILE_WINDOW runs, oldest first
+    pub sample_list: VecDeque<f64>,
+}
+
+/// GPU time of every render graph pass, measured with a timestamp
+/// before and after it. Passes of one wave overlap on the gpu, so
+/// their timings do too.
+#[derive(Clone, Debug)]
+pub struct GpuProfiler {
+    // Empty if the queue has no timestamp support
+    pub slot_list: Vec<QuerySlot>,
+
+    // Nanoseconds per timestamp tick
+    pub timestamp_period: f64,
+    // Bits of a timestamp the queue writes, the rest is garbage
+    pub valid_mask: u64,
+
+    // In order of the first run of every pass
+    pub timing_list: Vec<PassTiming>,
+    pub last_log: Instant,
+}
+
+impl GpuProfiler {
+    /// One slot per command buffer graphs are recorded into.
+    pub fn new(interface: &Interface, slot_count: usize) -> Self {
+        unsafe {
+            let valid_bits = interface
+                .instance
+                .get_physical_device_queue_family_properties(interface.phy_device.device)
+                [interface.phy_device.queue_family_index as usize]
+                .timestamp_valid_bits;
+
+            if valid_bits == 0 {
+                log::info!("Queue has no timestamp support, GPU profiling disabled ...");
+                return Self::default();
+            }
+
+            log::info!("Creating [ {} ] timestamp QueryPools ...", slot_count);
+            let query_pool_info = vk::QueryPoolCreateInfo::builder()
+                .query_type(vk::QueryType::TIMESTAMP)
+                .query_count(MAX_PROFILE_QUERY);
+
+            let slot_list = (0..slot_count)
+                .map(|_| QuerySlot {
+                    query_pool: interface
+                        .device
+                        .create_query_pool(&query_pool_info, None)
+                        .expect("ERR_CREATE_QUERY_POOL"),
+                    ..Default::default()
+                })
+                .collect();
+
+            Self {
+                slot_list,
+
+                timestamp_period: interface.phy_device.device_prop.limits.timestamp_period as f64,
+                valid_mask: u64::MAX >> (64 - valid_bits.min(64)),
+
+                ..Default::default()
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.slot_list.is_empty()
+    }
+
+    /// Start recording slot into cmd_buffer. Results of its last
+    /// submit are read first, the queries are reset afterwards.
+    pub fn begin(&mut self, device: &Device, cmd_buffer: vk::CommandBuffer, slot: usize) {
+        if slot >= self.slot_list.len() {
+            return;
+        }
+
+        self.read_slot(device, slot);
+
+        let slot = &mut self.slot_list[slot];
+        slot.name_list.clear();
+        slot.pending = false;
+
+        unsafe {
+            device.cmd_reset_query_pool(cmd_buffer, slot.query_pool, 0, MAX_PROFILE_QUERY);
+        }
+    }
+
+    /// Timestamp in front of the pass name, returns the query to end
+    /// it with. None once the slot is full or profiling is disabled.
+    pub fn begin_pass(
+        &mut self,
+        device: &Device,
+        cmd_buffer: vk::CommandBuffer,
+        slot: usize,
+        name: &'static str,
+    ) -> Option<u32> {
+        let slot = self.slot_list.get_mut(slot)?;
+
+        let query = slot.name_list.len() as u32 * 2;
+        if query + 2 > MAX_PROFILE_QUERY {
+            return None;
+        }
+
+        slot.name_list.push(name);
+        slot.pending = true;
+
+        unsafe {
+            device.cmd_write_timestamp(
+                cmd_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                slot.query_pool,
+                query,
+            );
+        }
+
+        Some(query)
+    }
+
+    pub fn end_pass(
+        &self,
+        device: &Device,
+        cmd_buffer: vk::CommandBuffer,
+        slot: usize,
+        query: Option<u32>,
+    ) {
+        if let Some(query) = query {
+            unsafe {
+                device.cmd_write_timestamp(
+                    cmd_buffer,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    self.slot_list[slot].query_pool,
+                    query + 1,
+                );
+            }
+        }
+    }
+
+    /// Read every slot the gpu is done with without waiting, and log
+    /// the averages once PROFILE_LOG_INTERVAL has passed.
+    pub fn collect(&mut self, device: &Device) {
+        (0..self.slot_list.len()).for_each(|slot| self.read_slot(device, slot));
+
+        if self.last_log.elapsed() >= PROFILE_LOG_INTERVAL && !self.timing_list.is_empty() {
+            self.log_timing();
+            self.last_log = Instant::now();
+        }
+    }
+
+    fn read_slot(&mut self, device: &Device, slot: usize) {
+        let slot = &mut self.slot_list[slot];
+        if !slot.pending {
+            return;
+        }
+
+        let mut data = vec![0u64; slot.name_list.len() * 2];
+        let result = unsafe {
+            device.get_query_pool_results(
+                slot.query_pool,
+                0,
+                data.len() as u32,
+                &mut data,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        };
+
+        match result {
+            Ok(_) => {}
+            // Still in flight, read by the next call
+            Err(vk::Result::NOT_READY) => return,
+            Err(err) => {
+                log::info!("Reading timestamps failed: {} ...", err);
+                slot.pending = false;
+                return;
+            }
+        }
+        slot.pending = false;
+
+        let name_list = slot.name_list.clone();
+        for (pass_idx, name) in name_list.iter().enumerate() {
+            let ticks = data[pass_idx * 2 + 1].wrapping_sub(data[pass_idx * 2]) & self.valid_mask;
+            let time = ticks as f64 * self.timestamp_period / 1_000_000.0;
+
+            let repeat = name_list[..pass_idx]
+                .iter()
+                .filter(|other| *other == name)
+                .count();
+            let label = if repeat == 0 {
+                name.to_string()
+            } else {
+                format!("{}[{}]", name, repeat)
+            };
+
+            self.push_sample(label, time);
+        }
+    }
+
+    fn push_sample(&mut self, label: String, time: f64) {
+        let timing = match self
+            .timing_list
+            .iter()
+            .position(|timing| timing.label == label)
+        {
+            Some(timing_idx) => &mut self.timing_list[timing_idx],
+            None => {
+                self.timing_list.push(PassTiming {
+                    label,
+                    ..Default::default()
+                });
+                self.timing_list.last_mut().unwrap()
+            }
+        };
+
+        if timing.sample_list.len() == PROFILE_WINDOW {
+            timing.sample_list.pop_front();
+        }
+        timing.sample_list.push_back(time);
+    }
+
+    /// Average GPU time of the pass with label over the window.
+    pub fn timing(&self, label: &str) -> Option<Duration> {
+        self.timing_list
+            .iter()
+            .find(|timing| timing.label == label)
+            .map(PassTiming::average)
+    }
+
+    /// (label, average GPU time) of every pass run so far.
+    pub fn average_list(&self) -> Vec<(String, Duration)> {
+        self.timing_list
+            .iter()
+            .map(|timing| (timing.label.clone(), timing.average()))
+            .collect()
+    }
+
+    pub fn log_timing(&self) {
+        for timing in self.timing_list.iter() {
+            log::info!(
+                "GPU pass [ {} ] took [ {:.3} ] ms over [ {} ] runs ...",
+                timing.label,
+                timing.average().as_secs_f64() * 1000.0,
+                timing.sample_list.len()
+            );
+        }
+    }
+
+    pub fn destroy(&mut self, device: &Device) {
+        self.slot_list
+            .drain(..)
+            .for_each(|slot| unsafe { device.destroy_query_pool(slot.query_pool, None) });
+    }
+}
+
+impl PassTiming {
+    pub fn average(&self) -> Duration {
+        if self.sample_list.is_empty() {
+            return Duration::ZERO;
+        }
+
+        let total: f64 = self.sample_list.iter().sum();
+        Duration::from_secs_f64(total / self.sample_list.len() as f64 / 1000.0)
+    }
+}
+
+impl Default for GpuProfiler {
+    fn default() -> Self {
+        Self {
+            slot_list: Default::default(),
+
+            timestamp_period: Default::default(),
+            valid_mask: Default::default(),
+
+            timing_list: Default::default(),
+            last_log: Instant::now(),
+        }
+    }
+}
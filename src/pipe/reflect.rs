@@ -0,0 +1,273 @@
+use std::error::Error;
+
+use ash::vk;
+use naga::{
+    front::spv, proc::Layouter, AddressSpace, ArraySize, Binding, ImageClass, ScalarKind, TypeInner,
+};
+
+/// One descriptor a shader declares with layout(set, binding).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReflectBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub desc_type: vk::DescriptorType,
+    pub count: u32,
+    pub stage: vk::ShaderStageFlags,
+}
+
+/// Resources of one or more shader stages, read back from their
+/// SPIR-V. Used to build descriptor set layouts, push constant
+/// ranges and vertex input, and to check hand written ones.
+#[derive(Clone, Debug, Default)]
+pub struct ShaderReflection {
+    pub stage: vk::ShaderStageFlags,
+
+    // Sorted by set and binding
+    pub binding_list: Vec<ReflectBinding>,
+    // 0 if the shader has no push constants
+    pub push_constant_size: u32,
+    // (location, format, size in bytes) of the vertex stage inputs
+    pub vertex_input_list: Vec<(u32, vk::Format, u32)>,
+}
+
+impl ShaderReflection {
+    pub fn new(code: &[u32], stage: vk::ShaderStageFlags) -> Result<Self, Box<dyn Error>> {
+        let options = spv::Options {
+            adjust_coordinate_space: false,
+            ..Default::default()
+        };
+        let module = spv::Frontend::new(code.iter().cloned(), &options).parse()?;
+
+        let mut layouter = Layouter::default();
+        layouter.update(module.to_ctx())?;
+
+        let mut result = Self {
+            stage,
+            ..Default::default()
+        };
+
+        for (_, global) in module.global_variables.iter() {
+            let (ty, count) = match module.types[global.ty].inner {
+                TypeInner::BindingArray { base, size } => match size {
+                    ArraySize::Constant(size) => (base, size.get()),
+                    ArraySize::Dynamic => {
+                        return Err(format!(
+                            "Runtime sized binding array [ {:?} ] is not supported",
+                            global.name
+                        )
+                        .into())
+                    }
+                },
+                _ => (global.ty, 1),
+            };
+
+            let desc_type = match (global.space, &module.types[ty].inner) {
+                (AddressSpace::PushConstant, _) => {
+                    result.push_constant_size = layouter[ty].size;
+                    continue;
+                }
+                (AddressSpace::Uniform, _) => vk::DescriptorType::UNIFORM_BUFFER,
+                (AddressSpace::Storage { .. }, _) => vk::DescriptorType::STORAGE_BUFFER,
+                (AddressSpace::Handle, TypeInner::Sampler { .. }) => vk::DescriptorType::SAMPLER,
+                (AddressSpace::Handle, TypeInner::Image { class, .. }) => match class {
+                    ImageClass::Storage { .. } => vk::DescriptorType::STORAGE_IMAGE,
+                    _ => vk::DescriptorType::SAMPLED_IMAGE,
+                },
+                // Private and workgroup variables
+                _ => continue,
+            };
+
+            let binding = global.binding.as_ref().ok_or_else(|| {
+                format!(
+                    "Resource [ {} ] has no layout(set, binding)",
+                    global.name.clone().unwrap_or_default()
+                )
+            })?;
+
+            result.binding_list.push(ReflectBinding {
+                set: binding.group,
+                binding: binding.binding,
+                desc_type,
+                count,
+                stage,
+            });
+        }
+        result
+            .binding_list
+            .sort_by_key(|binding| (binding.set, binding.binding));
+
+        if stage == vk::ShaderStageFlags::VERTEX {
+            let entry_point = module
+                .entry_points
+                .first()
+                .ok_or("Shader has no entry point")?;
+
+            for argument in entry_point.function.arguments.iter() {
+                let location = match argument.binding {
+                    Some(Binding::Location { location, .. }) => location,
+                    // gl_VertexIndex and other builtins are no vertex input
+                    _ => continue,
+                };
+
+                let (format, size) = Self::vertex_format(&module.types[argument.ty].inner)
+                    .ok_or_else(|| {
+                        format!(
+                            "Vertex input at location [ {} ] has no vertex format",
+                            location
+                        )
+                    })?;
+
+                result.vertex_input_list.push((location, format, size));
+            }
+            result
+                .vertex_input_list
+                .sort_by_key(|&(location, ..)| location);
+        }
+
+        Ok(result)
+    }
+
+    /// Format and size of 32 bit scalars and vectors, the only vertex
+    /// input types in use.
+    fn vertex_format(inner: &TypeInner) -> Option<(vk::Format, u32)> {
+        let (kind, width, component_count) = match *inner {
+            TypeInner::Scalar { kind, width } => (kind, width, 1),
+            TypeInner::Vector { size, kind, width } => (kind, width, size as u32),
+            _ => return None,
+        };
+
+        if width != 4 {
+            return None;
+        }
+
+        let format = match (kind, component_count) {
+            (ScalarKind::Float, 1) => vk::Format::R32_SFLOAT,
+            (ScalarKind::Float, 2) => vk::Format::R32G32_SFLOAT,
+            (ScalarKind::Float, 3) => vk::Format::R32G32B32_SFLOAT,
+            (ScalarKind::Float, 4) => vk::Format::R32G32B32A32_SFLOAT,
+            (ScalarKind::Uint, 1) => vk::Format::R32_UINT,
+            (ScalarKind::Uint, 2) => vk::Format::R32G32_UINT,
+            (ScalarKind::Uint, 3) => vk::Format::R32G32B32_UINT,
+            (ScalarKind::Uint, 4) => vk::Format::R32G32B32A32_UINT,
+            (ScalarKind::Sint, 1) => vk::Format::R32_SINT,
+            (ScalarKind::Sint, 2) => vk::Format::R32G32_SINT,
+            (ScalarKind::Sint, 3) => vk::Format::R32G32B32_SINT,
+            (ScalarKind::Sint, 4) => vk::Format::R32G32B32A32_SINT,
+            _ => return None,
+        };
+
+        Some((format, width as u32 * component_count))
+    }
+
+    /// Resources of every stage of a pipeline. A binding used by
+    /// several stages has to be declared the same in all of them.
+    pub fn merge(reflection_list: &[ShaderReflection]) -> Result<Self, Box<dyn Error>> {
+        let mut result = Self::default();
+
+        for reflection in reflection_list {
+            result.stage |= reflection.stage;
+            result.push_constant_size =
+                result.push_constant_size.max(reflection.push_constant_size);
+            result
+                .vertex_input_list
+                .extend(reflection.vertex_input_list.iter());
+
+            for binding in reflection.binding_list.iter() {
+                match result
+                    .binding_list
+                    .iter_mut()
+                    .find(|other| other.set == binding.set && other.binding == binding.binding)
+                {
+                    Some(other)
+                        if other.desc_type != binding.desc_type || other.count != binding.count =>
+                    {
+                        return Err(format!(
+                            "Set [ {} ] binding [ {} ] is {:?} x{} in {:?} but {:?} x{} in {:?}",
+                            binding.set,
+                            binding.binding,
+                            other.desc_type,
+                            other.count,
+                            other.stage,
+                            binding.desc_type,
+                            binding.count,
+                            binding.stage
+                        )
+                        .into())
+                    }
+                    Some(other) => other.stage |= binding.stage,
+                    None => result.binding_list.push(*binding),
+                }
+            }
+        }
+        result
+            .binding_list
+            .sort_by_key(|binding| (binding.set, binding.binding));
+
+        Ok(result)
+    }
+
+    pub fn set_count(&self) -> u32 {
+        self.binding_list
+            .iter()
+            .map(|binding| binding.set + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn push_constant_list(&self) -> Vec<vk::PushConstantRange> {
+        if self.push_constant_size == 0 {
+            return vec![];
+        }
+
+        vec![vk::PushConstantRange {
+            stage_flags: self.stage,
+            offset: 0,
+            size: self.push_constant_size,
+        }]
+    }
+
+    /// Attributes of the vertex inputs inside a vertex of stride bytes,
+    /// offset_list holds the offset of the field for every location.
+    pub fn vertex_attrib_list(
+        &self,
+        offset_list: &[u32],
+        stride: u32,
+    ) -> Result<Vec<vk::VertexInputAttributeDescription>, Box<dyn Error>> {
+        self.vertex_input_list
+            .iter()
+            .map(|&(location, format, size)| {
+                let offset = *offset_list.get(location as usize).ok_or_else(|| {
+                    format!(
+                        "Vertex input at location [ {} ] has no field in the vertex",
+                        location
+                    )
+                })?;
+
+                // Next field or the end of the vertex
+                let field_end = offset_list
+                    .iter()
+                    .filter(|&&other| other > offset)
+                    .min()
+                    .cloned()
+                    .unwrap_or(stride);
+
+                if offset + size > field_end {
+                    return Err(format!(
+                        "Vertex input at location [ {} ] is [ {} ] bytes, but its field only has [ {} ]",
+                        location,
+                        size,
+                        field_end - offset
+                    )
+                    .into());
+                }
+
+                Ok(vk::VertexInputAttributeDescription {
+                    location,
+                    binding: 0,
+                    format,
+                    offset,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+// Editors save a file in several writes, so don't look too often
+pub const RELOAD_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Watches the shader directory by polling the modification time of
+/// every file in it. Works the same on every platform and the
+/// directory only holds a handful of files.
+#[derive(Clone, Debug)]
+pub struct ShaderWatcher {
+    pub dir: PathBuf,
+    pub mtime_map: HashMap<PathBuf, SystemTime>,
+    pub last_poll: Instant,
+}
+
+impl ShaderWatcher {
+    pub fn new(dir: &Path) -> Self {
+        log::info!("Watching [ {} ] for shader changes ...", dir.display());
+
+        let mut result = Self {
+            dir: dir.to_path_buf(),
+            mtime_map: HashMap::new(),
+            last_poll: Instant::now(),
+        };
+        result.mtime_map = result.scan();
+
+        result
+    }
+
+    fn scan(&self) -> HashMap<PathBuf, SystemTime> {
+        let entry_list = match fs::read_dir(&self.dir) {
+            Ok(entry_list) => entry_list,
+            Err(_) => return HashMap::new(),
+        };
+
+        entry_list
+            .flatten()
+            .filter_map(|entry| {
+                let mtime = entry.metadata().ok()?.modified().ok()?;
+                Some((entry.path(), mtime))
+            })
+            .collect()
+    }
+
+    /// File names changed or added since the last call, empty until
+    /// RELOAD_POLL_INTERVAL has passed.
+    pub fn poll(&mut self) -> Vec<String> {
+        if self.last_poll.elapsed() < RELOAD_POLL_INTERVAL {
+            return vec![];
+        }
+        self.last_poll = Instant::now();
+
+        let mtime_map = self.scan();
+        let mut changed_list: Vec<String> = mtime_map
+            .iter()
+            .filter(|(path, mtime)| self.mtime_map.get(*path) != Some(*mtime))
+            .filter_map(|(path, _)| Some(path.file_name()?.to_string_lossy().to_string()))
+            .collect();
+        changed_list.sort();
+
+        self.mtime_map = mtime_map;
+
+        if !changed_list.is_empty() {
+            log::info!("Shader files changed {:?} ...", changed_list);
+        }
+
+        changed_list
+    }
+}
@@ -0,0 +1,229 @@
+use std::{error::Error, fmt, fs, path::Path};
+
+use naga::{
+    back::spv,
+    front::glsl,
+    valid::{Capabilities, ValidationFlags, Validator},
+    ShaderStage,
+};
+
+use crate::tree::{
+    edt::EDT_INF,
+    octree::{MAX_DEPTH, MAX_DEPTH_LIMIT, TEXTURE_ALIGN},
+    sdf::GLOBAL_SDF_RES,
+};
+
+use super::{
+    atlas::{BrickLayout, MISSING_SLOT},
+    software::MAX_STEP,
+};
+
+/// GLSL source of a shader, embedded into the binary and compiled
+/// to SPIR-V when its pipeline is created.
+#[derive(Clone, Copy, Debug)]
+pub struct ShaderSource {
+    // Relative to the crate root, used in error messages
+    pub path: &'static str,
+    pub source: &'static str,
+}
+
+// Sources on disk, read again by hot reload
+pub const SHADER_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/shader");
+
+macro_rules! shader_source {
+    ($name:literal) => {
+        ShaderSource {
+            path: concat!("shader/", $name),
+            source: include_str!(concat!("../../shader/", $name)),
+        }
+    };
+}
+
+pub const VERT_SHADER: ShaderSource = shader_source!("shader.vert");
+pub const TRAVERSE_SHADER: ShaderSource = shader_source!("texture_traverse.frag");
+pub const TEST_SHADER: ShaderSource = shader_source!("test.frag");
+pub const COMP_SHADER: ShaderSource = shader_source!("shader.comp");
+pub const JFA_SHADER: ShaderSource = shader_source!("JFA.comp");
+pub const EDT_SHADER: ShaderSource = shader_source!("EDT.comp");
+pub const SDF_MIP_SHADER: ShaderSource = shader_source!("SDF_MIP.comp");
+
+pub const SHADER_LIST: [ShaderSource; 7] = [
+    VERT_SHADER,
+    TRAVERSE_SHADER,
+    TEST_SHADER,
+    COMP_SHADER,
+    JFA_SHADER,
+    EDT_SHADER,
+    SDF_MIP_SHADER,
+];
+
+/// Every error of one compile, printed as path:line:column.
+#[derive(Clone, Debug, Default)]
+pub struct ShaderError {
+    pub path: String,
+    // (line, column, message), both 1-based
+    pub error_list: Vec<(u32, u32, String)>,
+}
+
+/// Values of the Rust constants the shaders share, passed as
+/// defines so they cannot drift apart.
+pub fn define_list(brick_layout: BrickLayout) -> Vec<(String, String)> {
+    let mut define_list = vec![
+        ("MAX_DEPTH".to_string(), MAX_DEPTH.to_string()),
+        ("MAX_DEPTH_LIMIT".to_string(), MAX_DEPTH_LIMIT.to_string()),
+        ("MAX_STEP".to_string(), MAX_STEP.to_string()),
+        (
+            "TEXTURE_ALIGN".to_string(),
+            (TEXTURE_ALIGN as u32).to_string(),
+        ),
+        ("GLOBAL_SDF_RES".to_string(), GLOBAL_SDF_RES.to_string()),
+        ("MISSING_SLOT".to_string(), format!("{}u", MISSING_SLOT)),
+        ("EDT_INF".to_string(), format!("{:e}", EDT_INF)),
+        (
+            "BRICK_TEXTURE_RES".to_string(),
+            brick_layout.extent().width.to_string(),
+        ),
+    ];
+
+    if brick_layout == BrickLayout::Texture3D {
+        define_list.push(("BRICK_TEXTURE_3D".to_string(), "1".to_string()));
+    }
+
+    define_list
+}
+
+/// Compile GLSL to SPIR-V words, the stage comes from the file
+/// extension of path.
+pub fn compile(
+    path: &str,
+    source: &str,
+    define_list: &[(String, String)],
+) -> Result<Vec<u32>, Box<dyn Error>> {
+    let stage = match path.rsplit('.').next() {
+        Some("vert") => ShaderStage::Vertex,
+        Some("frag") => ShaderStage::Fragment,
+        Some("comp") => ShaderStage::Compute,
+        _ => return Err(format!("Unknown shader stage of [ {} ]", path).into()),
+    };
+
+    let mut options = glsl::Options::from(stage);
+    options.defines.extend(define_list.iter().cloned());
+
+    let module = glsl::Frontend::default()
+        .parse(&options, source)
+        .map_err(|error_list| ShaderError {
+            path: path.to_string(),
+            error_list: error_list
+                .iter()
+                .map(|error| {
+                    let location = error.meta.location(source);
+                    (
+                        location.line_number,
+                        location.line_position,
+                        error.kind.to_string(),
+                    )
+                })
+                .collect(),
+        })?;
+
+    let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|error| {
+            let (line, column) = error
+                .location(source)
+                .map(|location| (location.line_number, location.line_position))
+                .unwrap_or((1, 1));
+
+            ShaderError {
+                path: path.to_string(),
+                error_list: vec![(line, column, error.as_inner().to_string())],
+            }
+        })?;
+
+    // The sources are written for Vulkan, so no coordinate flip
+    let spv_options = spv::Options {
+        flags: spv::WriterFlags::empty(),
+        ..Default::default()
+    };
+
+    Ok(spv::write_vec(&module, &info, &spv_options, None)?)
+}
+
+impl ShaderSource {
+    /// SPIR-V of the embedded source, a broken shader is a bug of
+    /// the build, so every error is logged before panicking.
+    pub fn code(&self, define_list: &[(String, String)]) -> Vec<u32> {
+        log::info!("Compiling Shader [ {} ] ...", self.path);
+
+        compile(self.path, self.source, define_list).unwrap_or_else(|err| {
+            log::error!("{}", err);
+            panic!("ERR_COMPILE_SHADER");
+        })
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        self.path.rsplit('/').next().unwrap_or(self.path)
+    }
+
+    /// SPIR-V of the source as it is on disk right now, None if it
+    /// could not be read or compiled. Errors are only logged, the
+    /// caller keeps using the old code then.
+    pub fn reload_code(&self, define_list: &[(String, String)]) -> Option<Vec<u32>> {
+        log::info!("Reloading Shader [ {} ] ...", self.path);
+
+        let result = fs::read_to_string(Path::new(SHADER_DIR).join(self.file_name()))
+            .map_err(|err| err.into())
+            .and_then(|source| compile(self.path, &source, define_list));
+
+        match result {
+            Ok(code) => Some(code),
+            Err(err) => {
+                log::error!("{}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Compile every shader in both brick layouts, true if all of them
+/// compiled. Needs no device, so it can run on CI.
+pub fn check_shaders() -> bool {
+    let mut passed = true;
+
+    for brick_layout in [BrickLayout::Atlas2D, BrickLayout::Texture3D] {
+        let define_list = define_list(brick_layout);
+
+        for shader in SHADER_LIST {
+            match compile(shader.path, shader.source, &define_list) {
+                Ok(code) => log::info!(
+                    "Compiled [ {} ] for {:?} to [ {} ] words ...",
+                    shader.path,
+                    brick_layout,
+                    code.len()
+                ),
+                Err(err) => {
+                    log::error!("{}", err);
+                    passed = false;
+                }
+            }
+        }
+    }
+
+    passed
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message_list: Vec<String> = self
+            .error_list
+            .iter()
+            .map(|(line, column, message)| {
+                format!("{}:{}:{}: {}", self.path, line, column, message)
+            })
+            .collect();
+
+        write!(f, "{}", message_list.join("\n"))
+    }
+}
+
+impl Error for ShaderError {}
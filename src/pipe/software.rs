@@ -0,0 +1,320 @@
+use std::thread;
+
+use ash::vk;
+use nalgebra_glm::{Mat4, Vec3, Vec4};
+
+use crate::{
+    tree::{
+        brick::BrickMap,
+        octree::{Octree, TEXTURE_ALIGN},
+        sdf::GlobalDistanceField,
+    },
+    uniform::Uniform,
+};
+
+use super::{capture::Capture, obj::BASE_CUBE_VERT, pipe::Pipe};
+
+// Same limit as MAX_STEP in texture_traverse.frag
+pub const MAX_STEP: u32 = 50;
+
+// Clear color of the color attachment in record_draw
+pub const CLEAR_COLOR: Vec4 = Vec4::new(1.0, 1.0, 1.0, 0.0);
+pub const MISSING_COLOR: Vec4 = Vec4::new(0.5, 0.5, 0.5, 1.0);
+pub const EMPTY_COLOR: Vec4 = Vec4::new(0.0, 1.0, 0.0, 0.0);
+
+/// Proxy cube as it is drawn by the graphic pipe.
+#[derive(Clone, Copy, Debug)]
+pub struct Proxy {
+    pub pos_on_edge: Vec3,
+    pub span: f32,
+
+    // Brick of the proxy, None is drawn like a missing atlas slot
+    pub brick_idx: Option<usize>,
+    pub brick_origin: Vec3,
+}
+
+/// Fragment which passed the depth test, the ray parameter
+/// between near (0) and far (1) plane stands in for depth.
+#[derive(Clone, Copy, Debug)]
+struct Fragment {
+    depth: f32,
+    world_pos: Vec3,
+    proxy_idx: usize,
+}
+
+/// Reference renderer on the cpu. Proxies are rasterized the
+/// same way as by the graphic pipe and every pixel is shaded
+/// like in texture_traverse.frag, so both images can be compared
+/// pixel by pixel. Every brick counts as resident in the atlas.
+#[derive(Clone)]
+pub struct SoftwareRenderer {
+    pub proxy_list: Vec<Proxy>,
+
+    pub brick_map: BrickMap,
+    pub global_sdf: GlobalDistanceField,
+
+    pub thread_count: usize,
+}
+
+impl SoftwareRenderer {
+    /// Bricks have to hold their distance field already.
+    pub fn new(octree: &Octree, brick_map: &BrickMap) -> Self {
+        log::info!("Creating SoftwareRenderer ...");
+
+        let (vertex_data, _, loc_info) = Pipe::get_octree_vert_data(octree);
+
+        let proxy_list = vertex_data
+            .chunks(BASE_CUBE_VERT.len())
+            .zip(loc_info.iter())
+            .map(|(vert_list, loc_info)| {
+                let pos_on_edge = vert_list[0].pos_on_edge;
+                let pos_on_edge = Vec3::new(pos_on_edge[0], pos_on_edge[1], pos_on_edge[2]);
+                let (brick_idx, brick_origin) = brick_map.proxy_brick(pos_on_edge, loc_info.span);
+
+                Proxy {
+                    pos_on_edge,
+                    span: loc_info.span,
+                    brick_idx,
+                    brick_origin,
+                }
+            })
+            .collect();
+
+        Self {
+            proxy_list,
+
+            brick_map: brick_map.clone(),
+            global_sdf: GlobalDistanceField::new(octree),
+
+            thread_count: thread::available_parallelism().map_or(1, |count| count.get()),
+        }
+    }
+
+    /// Render at the resolution of the uniform, rows are split
+    /// into one band per thread.
+    pub fn render(&self, uniform: &Uniform) -> Capture {
+        let extent = vk::Extent2D {
+            width: uniform.res.x as u32,
+            height: uniform.res.y as u32,
+        };
+
+        let inv_view_proj = uniform.view_proj.try_inverse().unwrap_or(Mat4::identity());
+
+        let mut data = vec![0u8; (extent.width * extent.height * 4) as usize];
+        let row_len = (extent.width * 4) as usize;
+        let band_height = extent.height.div_ceil(self.thread_count as u32).max(1) as usize;
+
+        thread::scope(|scope| {
+            data.chunks_mut(row_len * band_height)
+                .enumerate()
+                .for_each(|(band_idx, band)| {
+                    scope.spawn(move || {
+                        band.chunks_exact_mut(row_len)
+                            .enumerate()
+                            .for_each(|(row, row_data)| {
+                                let y = (band_idx * band_height + row) as u32;
+
+                                row_data
+                                    .chunks_exact_mut(4)
+                                    .enumerate()
+                                    .for_each(|(x, px)| {
+                                        let color = self.shade_px(
+                                            uniform,
+                                            &inv_view_proj,
+                                            extent,
+                                            x as u32,
+                                            y,
+                                        );
+                                        px.copy_from_slice(color.map(to_unorm).as_slice());
+                                    });
+                            });
+                    });
+                });
+        });
+
+        log::info!(
+            "Rendered [ {} x {} ] on [ {} ] threads ...",
+            extent.width,
+            extent.height,
+            self.thread_count
+        );
+
+        Capture {
+            extent,
+            format: vk::Format::R8G8B8A8_UNORM,
+            data,
+        }
+    }
+
+    fn shade_px(
+        &self,
+        uniform: &Uniform,
+        inv_view_proj: &Mat4,
+        extent: vk::Extent2D,
+        x: u32,
+        y: u32,
+    ) -> Vec4 {
+        match self.rasterize(inv_view_proj, extent, x, y) {
+            Some(fragment) => self.shade(uniform, &fragment),
+            None => CLEAR_COLOR,
+        }
+    }
+
+    /// Nearest proxy surface under the pixel center. There is no
+    /// culling, so back faces count too once the front face is
+    /// clipped by the near plane. Equal depth keeps the later
+    /// proxy, like LESS_OR_EQUAL.
+    fn rasterize(
+        &self,
+        inv_view_proj: &Mat4,
+        extent: vk::Extent2D,
+        x: u32,
+        y: u32,
+    ) -> Option<Fragment> {
+        let ndc_x = (x as f32 + 0.5) / extent.width as f32 * 2.0 - 1.0;
+        let ndc_y = (y as f32 + 0.5) / extent.height as f32 * 2.0 - 1.0;
+
+        let unproject = |depth: f32| {
+            let pos = inv_view_proj * Vec4::new(ndc_x, ndc_y, depth, 1.0);
+            pos.xyz() / pos.w
+        };
+
+        let origin = unproject(0.0);
+        let dir = unproject(1.0) - origin;
+
+        let mut result: Option<Fragment> = None;
+
+        for (proxy_idx, proxy) in self.proxy_list.iter().enumerate() {
+            let t0 = (proxy.pos_on_edge - origin).component_div(&dir);
+            let t1 = (proxy.pos_on_edge + Vec3::repeat(proxy.span) - origin).component_div(&dir);
+
+            let t_enter = t0.zip_map(&t1, f32::min).max();
+            let t_exit = t0.zip_map(&t1, f32::max).min();
+
+            if t_enter > t_exit {
+                continue;
+            }
+
+            let depth = match (0.0..=1.0).contains(&t_enter) {
+                true => t_enter,
+                false if (0.0..=1.0).contains(&t_exit) => t_exit,
+                false => continue,
+            };
+
+            if result.is_none_or(|fragment| depth <= fragment.depth) {
+                result = Some(Fragment {
+                    depth,
+                    world_pos: origin + dir * depth,
+                    proxy_idx,
+                });
+            }
+        }
+
+        result
+    }
+
+    /// Voxel at brick_pos inside the brick of the proxy, outside
+    /// counts as empty. Same as fetch_voxel.
+    fn fetch_voxel(&self, brick_idx: usize, brick_pos: Vec3) -> Vec4 {
+        if brick_pos.min() < 0.0 || brick_pos.max() >= TEXTURE_ALIGN {
+            return Vec4::new(0.0, 0.0, 0.0, 1.0);
+        }
+
+        let voxel = self.brick_map.brick_list[brick_idx].get(brick_pos.map(|val| val as u32));
+
+        Vec4::from(voxel.map(|val| val as f32 / 255.0))
+    }
+
+    /// Port of main in texture_traverse.frag.
+    fn shade(&self, uniform: &Uniform, fragment: &Fragment) -> Vec4 {
+        let proxy = self.proxy_list[fragment.proxy_idx];
+        let world_pos = fragment.world_pos;
+
+        // Mirrors the ray setup of texture_traverse.frag on purpose, including cam_front
+        // as origin and the 0.001 clamp, change both sides or the golden check breaks
+        let ray_dir = (world_pos - uniform.cam_front.xyz()).normalize();
+        let inv_ray_dir = ray_dir.map(|val| 1.0 / val.abs().max(0.001));
+
+        let brick_idx = match proxy.brick_idx {
+            Some(brick_idx) => brick_idx,
+            // Brick is still streamed in
+            None => return MISSING_COLOR,
+        };
+
+        // Proxies can be smaller than bricks, so voxels are fetched
+        // relative to the brick and not to the proxy
+        let brick_origin = proxy.brick_origin;
+
+        let mut voxel_pos = world_pos.map(f32::floor);
+        let mut local_pos = world_pos - voxel_pos;
+
+        let mut col = self.fetch_voxel(brick_idx, voxel_pos - brick_origin);
+
+        for _ in 0..MAX_STEP {
+            if col.w == 0.0 {
+                return EMPTY_COLOR;
+            }
+
+            // Jump over empty space, the field never crosses into geometry
+            let skip = self.global_sdf.distance(voxel_pos + local_pos);
+            if skip >= 1.0 {
+                let skip_pos = voxel_pos + local_pos + ray_dir * skip;
+
+                voxel_pos = skip_pos.map(f32::floor);
+                local_pos = skip_pos - voxel_pos;
+
+                col = self.fetch_voxel(brick_idx, voxel_pos - brick_origin);
+                continue;
+            }
+
+            let hit = ray_cube_intersect(local_pos, ray_dir, inv_ray_dir, 1.0);
+            let hit_mask_vec = min_axis_mask(hit);
+
+            let len = hit.dot(&hit_mask_vec);
+
+            let local_pos_on_edge = hit_mask_vec.component_mul(&ray_dir.map(glsl_sign));
+
+            local_pos += ray_dir * len - local_pos_on_edge;
+            voxel_pos += local_pos_on_edge;
+
+            local_pos += ray_dir * len;
+
+            col = self.fetch_voxel(brick_idx, voxel_pos - brick_origin);
+        }
+
+        Vec4::zeros()
+    }
+}
+
+fn ray_cube_intersect(origin: Vec3, dir: Vec3, inv_ray_dir: Vec3, span: f32) -> Vec3 {
+    let size_cp = span * 0.5;
+    let inv_pos = dir
+        .map(glsl_sign)
+        .component_mul(&(origin - Vec3::repeat(size_cp)))
+        - Vec3::repeat(size_cp);
+
+    -inv_pos.component_mul(&inv_ray_dir)
+}
+
+/// 1 on every axis, which is smaller than both others.
+fn min_axis_mask(hit: Vec3) -> Vec3 {
+    Vec3::new(
+        (hit.x < hit.y.min(hit.z)) as u32 as f32,
+        (hit.y < hit.z.min(hit.x)) as u32 as f32,
+        (hit.z < hit.x.min(hit.y)) as u32 as f32,
+    )
+}
+
+/// Sign like in glsl, 0 stays 0.
+fn glsl_sign(val: f32) -> f32 {
+    if val == 0.0 {
+        0.0
+    } else {
+        val.signum()
+    }
+}
+
+/// Same rounding as writing into an unorm attachment.
+fn to_unorm(val: f32) -> u8 {
+    (val.clamp(0.0, 1.0) * 255.0).round() as u8
+}
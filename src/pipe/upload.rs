@@ -0,0 +1,414 @@
+use std::{collections::VecDeque, mem};
+
+use ash::vk;
+
+use crate::interface::interface::Interface;
+
+use super::{
+    buffer::BufferSet,
+    graph::{Access, GraphBuffer, PassDesc, RenderGraph, TransientPool},
+};
+
+// Size of the staging ring, uploads which don't fit wait for a later frame
+pub const UPLOAD_STAGING_SIZE: u64 = 32 * 1024 * 1024;
+// Offset alignment inside the staging buffer, enough for every copy
+pub const UPLOAD_ALIGN: u64 = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub enum UploadTarget {
+    Buffer {
+        buffer: vk::Buffer,
+        offset: u64,
+    },
+    // Buffer offset of the region is set once it is recorded
+    Image {
+        img: vk::Image,
+        region: vk::BufferImageCopy,
+    },
+}
+
+#[derive(Clone)]
+pub struct Upload {
+    pub target: UploadTarget,
+    pub data: Vec<u8>,
+}
+
+/// Host visible buffer handed out front to back, wrapping around
+/// at the end. Space is given back per frame, once the copies of
+/// the frame have finished on the gpu.
+#[derive(Clone, Default)]
+pub struct StagingRing {
+    pub buffer: BufferSet,
+
+    // Next byte to hand out
+    pub head: u64,
+    // Bytes handed out and not released, including wrapped tails
+    pub used: u64,
+
+    // Bytes handed out by the frame being recorded
+    pub frame_used: u64,
+    // (frame slot, bytes) of every frame which could still be copying, oldest first
+    pub frame_used_list: VecDeque<(usize, u64)>,
+}
+
+/// Uploads are staged on the cpu during the frame and recorded
+/// into the draw command buffer, after its fence was waited on.
+/// So no upload waits on a submit of its own.
+#[derive(Clone, Default)]
+pub struct UploadQueue {
+    pub ring: StagingRing,
+    pub pending_list: VecDeque<Upload>,
+}
+
+/// Bytes of a slice of plain data, as they are copied to the gpu.
+pub fn as_bytes<Type: Copy>(data: &[Type]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, mem::size_of_val(data)) }
+}
+
+impl StagingRing {
+    pub fn new(interface: &Interface) -> Self {
+        log::info!("Creating StagingRing ...");
+
+        Self {
+            buffer: BufferSet::new(
+                UPLOAD_STAGING_SIZE,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                vk::SharingMode::EXCLUSIVE,
+                &interface.device,
+            )
+            .create_memory(
+                interface,
+                UPLOAD_ALIGN,
+                UPLOAD_STAGING_SIZE,
+                &vec![0u8; UPLOAD_STAGING_SIZE as usize],
+            ),
+
+            ..Default::default()
+        }
+    }
+
+    /// Offset of size free bytes, None if the frames in flight
+    /// still hold too much of the ring.
+    pub fn alloc(&mut self, size: u64) -> Option<u64> {
+        let capacity = self.buffer.size;
+
+        // Nothing in flight, start over instead of wrapping later
+        if self.used == 0 {
+            self.head = 0;
+        }
+
+        let aligned_head = self.head.next_multiple_of(UPLOAD_ALIGN);
+        // Data is never split, the rest of the ring is skipped instead
+        let offset = if aligned_head + size > capacity {
+            0
+        } else {
+            aligned_head
+        };
+
+        let consumed = if offset >= self.head {
+            offset + size - self.head
+        } else {
+            capacity - self.head + offset + size
+        };
+
+        if self.used + consumed > capacity {
+            return None;
+        }
+
+        self.head = offset + size;
+        self.used += consumed;
+        self.frame_used += consumed;
+
+        Some(offset)
+    }
+
+    /// Close the frame being recorded in slot frame_idx, its
+    /// space stays in use until the slot is released.
+    pub fn end_frame(&mut self, frame_idx: usize) {
+        self.frame_used_list
+            .push_back((frame_idx, std::mem::take(&mut self.frame_used)));
+    }
+
+    /// Give back the space of the last frame of slot frame_idx
+    /// and every older frame, its fence has to be signaled.
+    pub fn release_frame(&mut self, frame_idx: usize) {
+        let Some(last) = self
+            .frame_used_list
+            .iter()
+            .rposition(|&(other, _)| other == frame_idx)
+        else {
+            return;
+        };
+
+        self.frame_used_list
+            .drain(..=last)
+            .for_each(|(_, frame_used)| self.used -= frame_used);
+    }
+
+    /// Give back the space of every closed frame, the gpu has to
+    /// be idle.
+    pub fn release_all(&mut self) {
+        self.frame_used_list
+            .drain(..)
+            .for_each(|(_, frame_used)| self.used -= frame_used);
+    }
+
+    pub fn destroy(&self, interface: &Interface) {
+        self.buffer.destroy(interface);
+    }
+}
+
+impl UploadQueue {
+    pub fn new(interface: &Interface) -> Self {
+        log::info!("Creating UploadQueue ...");
+
+        Self {
+            ring: StagingRing::new(interface),
+            pending_list: VecDeque::new(),
+        }
+    }
+
+    /// Queue data for byte offset of buffer. Large data is split,
+    /// so that every part fits into the staging buffer.
+    pub fn stage_buffer<Type: Copy>(&mut self, buffer: vk::Buffer, offset: u64, data: &[Type]) {
+        as_bytes(data)
+            .chunks(UPLOAD_STAGING_SIZE as usize)
+            .enumerate()
+            .for_each(|(chunk_idx, chunk)| {
+                self.pending_list.push_back(Upload {
+                    target: UploadTarget::Buffer {
+                        buffer,
+                        offset: offset + chunk_idx as u64 * UPLOAD_STAGING_SIZE,
+                    },
+                    data: chunk.to_vec(),
+                });
+            });
+    }
+
+    /// Queue data for one region of an image, which is sampled
+    /// in SHADER_READ_ONLY_OPTIMAL outside of the upload.
+    pub fn stage_image<Type: Copy>(
+        &mut self,
+        img: vk::Image,
+        region: vk::BufferImageCopy,
+        data: &[Type],
+    ) {
+        assert!(
+            mem::size_of_val(data) as u64 <= UPLOAD_STAGING_SIZE,
+            "ERR_UPLOAD_TOO_LARGE"
+        );
+
+        // Regions of one copy must not overlap, newer data replaces older
+        let pending = self
+            .pending_list
+            .iter_mut()
+            .find(|upload| match upload.target {
+                UploadTarget::Image {
+                    img: other,
+                    region: other_region,
+                } => {
+                    other == img
+                        && other_region.image_offset == region.image_offset
+                        && other_region.image_extent == region.image_extent
+                        && other_region.image_subresource.mip_level
+                            == region.image_subresource.mip_level
+                }
+                UploadTarget::Buffer { .. } => false,
+            });
+
+        match pending {
+            Some(upload) => upload.data = as_bytes(data).to_vec(),
+            None => self.pending_list.push_back(Upload {
+                target: UploadTarget::Image { img, region },
+                data: as_bytes(data).to_vec(),
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending_list.is_empty()
+    }
+
+    /// Write as many uploads as fit into the staging ring and
+    /// record their copies, the rest stays queued for the next frame.
+    /// The fence of slot frame_idx has to be waited on.
+    pub fn record(
+        &mut self,
+        interface: &Interface,
+        cmd_buffer: vk::CommandBuffer,
+        pool: &mut TransientPool,
+        frame_idx: usize,
+    ) {
+        self.ring.release_frame(frame_idx);
+        self.record_copy(interface, cmd_buffer, pool);
+        self.ring.end_frame(frame_idx);
+    }
+
+    /// Record every pending upload on the setup command buffer and
+    /// wait for it, for data which is needed before the next frame.
+    pub fn flush(&mut self, interface: &Interface) {
+        interface.wait_for_gpu().expect("DEVICE_LOST");
+        self.ring.release_all();
+
+        while !self.pending_list.is_empty() {
+            interface.record_submit_cmd(
+                interface.setup_cmd_fence,
+                interface.setup_cmd_buffer,
+                &[],
+                &[],
+                // Copy passes create no transients
+                |cmd_buffer| self.record_copy(interface, cmd_buffer, &mut TransientPool::default()),
+            );
+
+            interface.wait_for_gpu().expect("DEVICE_LOST");
+            self.ring.end_frame(0);
+            self.ring.release_all();
+        }
+    }
+
+    /// Copies of everything fitting into the ring as a graph, it
+    /// leaves the targets ready for the draw and compute passes.
+    fn record_copy(
+        &mut self,
+        interface: &Interface,
+        cmd_buffer: vk::CommandBuffer,
+        pool: &mut TransientPool,
+    ) {
+        if self.pending_list.is_empty() {
+            return;
+        }
+
+        let mut buffer_copy_list: Vec<(vk::Buffer, vk::BufferCopy)> = vec![];
+        let mut image_copy_list: Vec<(vk::Image, vk::BufferImageCopy)> = vec![];
+
+        while let Some(upload) = self.pending_list.front() {
+            let offset = match self.ring.alloc(upload.data.len() as u64) {
+                Some(offset) => offset,
+                None => break,
+            };
+            let size = upload.data.len() as u64;
+
+            self.ring.buffer.rewrite_mem_range(offset, &upload.data);
+
+            match upload.target {
+                UploadTarget::Buffer {
+                    buffer,
+                    offset: dst_offset,
+                } => buffer_copy_list.push((
+                    buffer,
+                    vk::BufferCopy {
+                        src_offset: offset,
+                        dst_offset,
+                        size,
+                    },
+                )),
+                UploadTarget::Image { img, region } => image_copy_list.push((
+                    img,
+                    vk::BufferImageCopy {
+                        buffer_offset: offset,
+                        ..region
+                    },
+                )),
+            }
+
+            self.pending_list.pop_front();
+        }
+
+        let staging = self.ring.buffer.buffer;
+        let mut graph = RenderGraph::new();
+
+        // Copies to the same range have to stay in order, so an
+        // overlapping copy starts the next pass
+        let mut batch_list: Vec<Vec<(vk::Buffer, vk::BufferCopy)>> = vec![];
+        buffer_copy_list.into_iter().for_each(|(buffer, region)| {
+            let overlaps = batch_list.last().is_some_and(|batch| {
+                batch.iter().any(|&(other, other_region)| {
+                    other == buffer
+                        && region.dst_offset < other_region.dst_offset + other_region.size
+                        && other_region.dst_offset < region.dst_offset + region.size
+                })
+            });
+
+            match batch_list.last_mut() {
+                Some(batch) if !overlaps => batch.push((buffer, region)),
+                _ => batch_list.push(vec![(buffer, region)]),
+            }
+        });
+
+        // Reads of the last frame are waited on before overwriting
+        let mut graph_buffer_list: Vec<(vk::Buffer, GraphBuffer)> = vec![];
+        for batch in batch_list {
+            let mut pass = PassDesc::new("upload");
+            for &(buffer, _) in batch.iter() {
+                let graph_buffer = match graph_buffer_list
+                    .iter()
+                    .find(|&&(other, _)| other == buffer)
+                {
+                    Some(&(_, graph_buffer)) => graph_buffer,
+                    None => {
+                        let graph_buffer = graph.import_buffer(buffer, Access::SCENE_READ);
+                        graph.export_buffer(graph_buffer, Access::SCENE_READ);
+                        graph_buffer_list.push((buffer, graph_buffer));
+                        graph_buffer
+                    }
+                };
+
+                if !pass
+                    .buffer_list
+                    .iter()
+                    .any(|&(other, _)| other == graph_buffer)
+                {
+                    pass = pass.buffer(graph_buffer, Access::TRANSFER_DST);
+                }
+            }
+
+            graph.add_pass(pass, move |_, cmd_buffer| unsafe {
+                batch.iter().for_each(|&(buffer, region)| {
+                    interface
+                        .device
+                        .cmd_copy_buffer(cmd_buffer, staging, buffer, &[region]);
+                });
+            });
+        }
+
+        let mut img_list: Vec<vk::Image> = vec![];
+        image_copy_list.iter().for_each(|&(img, _)| {
+            if !img_list.contains(&img) {
+                img_list.push(img);
+            }
+        });
+
+        for img in img_list {
+            let image = graph.import_image(
+                img,
+                vk::ImageView::null(),
+                vk::ImageAspectFlags::COLOR,
+                Access::SCENE_READ,
+            );
+            graph.export_image(image, Access::SCENE_READ);
+
+            let region_list: Vec<vk::BufferImageCopy> = image_copy_list
+                .iter()
+                .filter(|&&(other, _)| other == img)
+                .map(|&(_, region)| region)
+                .collect();
+
+            let pass = PassDesc::new("upload_image").image(image, Access::TRANSFER_DST);
+            graph.add_pass(pass, move |_, cmd_buffer| unsafe {
+                interface.device.cmd_copy_buffer_to_image(
+                    cmd_buffer,
+                    staging,
+                    img,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &region_list,
+                );
+            });
+        }
+
+        graph.execute(interface, cmd_buffer, pool);
+    }
+
+    pub fn destroy(&self, interface: &Interface) {
+        self.ring.destroy(interface);
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::{BTreeSet, HashMap};
+
+use nalgebra_glm::{UVec3, Vec3};
+
+use crate::{mask_to_vec, vec_to_mask};
+
+use super::{
+    octant::Octant,
+    octree::{Octree, MAX_DEPTH, TEXTURE_ALIGN},
+};
+
+pub const BRICK_SIZE: u32 = TEXTURE_ALIGN as u32;
+pub const BRICK_VOXEL_COUNT: usize = (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize;
+pub const BRICK_BYTES: usize = BRICK_VOXEL_COUNT * std::mem::size_of::<Voxel>();
+
+/// Voxel in the same encoding as the brick texture.
+/// rgb = stored position of nearest seed / 256
+/// a = distance to nearest seed / 256, seed = 0, undefined = 1
+pub type Voxel = [u8; 4];
+
+pub const EMPTY_VOXEL: Voxel = [0, 0, 0, 255];
+pub const SEED_VOXEL: Voxel = [255, 255, 255, 0];
+
+/// Voxel value as the shader sees it after sampling the
+/// unorm texture: (stored position, distance).
+pub fn decode_voxel(voxel: Voxel) -> (Vec3, f32) {
+    let unorm = |val: u8| val as f32 / 255.0 * 256.0;
+
+    (
+        Vec3::new(unorm(voxel[0]), unorm(voxel[1]), unorm(voxel[2])),
+        unorm(voxel[3]),
+    )
+}
+
+/// Same rounding as the imageStore into the rgba8 texture.
+pub fn encode_voxel(pos: Vec3, dist: f32) -> Voxel {
+    let unorm = |val: f32| ((val / 256.0).clamp(0.0, 1.0) * 255.0).round() as u8;
+
+    [unorm(pos.x), unorm(pos.y), unorm(pos.z), unorm(dist)]
+}
+
+/// 16³ voxel block, belonging to one octree node at brick depth.
+#[derive(Clone)]
+pub struct Brick {
+    // Position of the brick in brick units (pos_on_edge / span)
+    pub coord: UVec3,
+
+    pub voxel_data: Vec<Voxel>,
+}
+
+/// Pool of bricks for all nodes at brick depth. A brick is
+/// identified by its index in the pool, where it is placed in
+/// the gpu atlas is decided by the BrickAtlas.
+#[derive(Clone)]
+pub struct BrickMap {
+    pub brick_depth: u32,
+    pub brick_span: f32,
+
+    pub brick_list: Vec<Brick>,
+    pub coord_map: HashMap<[u32; 3], usize>,
+
+    // Bricks changed since the last upload
+    pub dirty_list: BTreeSet<usize>,
+}
+
+impl Brick {
+    pub fn new(coord: UVec3) -> Self {
+        Self {
+            coord,
+            voxel_data: vec![EMPTY_VOXEL; BRICK_VOXEL_COUNT],
+        }
+    }
+
+    pub fn voxel_idx(pos: UVec3) -> usize {
+        (pos.x + pos.y * BRICK_SIZE + pos.z * BRICK_SIZE * BRICK_SIZE) as usize
+    }
+
+    pub fn idx_to_pos(idx: usize) -> UVec3 {
+        let idx = idx as u32;
+
+        UVec3::new(
+            idx % BRICK_SIZE,
+            (idx / BRICK_SIZE) % BRICK_SIZE,
+            idx / (BRICK_SIZE * BRICK_SIZE),
+        )
+    }
+
+    pub fn get(&self, pos: UVec3) -> Voxel {
+        self.voxel_data[Self::voxel_idx(pos)]
+    }
+
+    pub fn set(&mut self, pos: UVec3, voxel: Voxel) {
+        self.voxel_data[Self::voxel_idx(pos)] = voxel;
+    }
+}
+
+impl BrickMap {
+    /// Collect every node at brick depth and voxelize the leaves
+    /// below it. Brick depth is chosen so that one voxel has
+    /// the size of one world unit.
+    pub fn new(octree: &Octree) -> Self {
+        let brick_depth = (octree.root_span / TEXTURE_ALIGN).log2() as u32;
+
+        let mut result = Self {
+            brick_depth,
+            brick_span: octree.root_span / (1 << brick_depth) as f32,
+
+            ..Default::default()
+        };
+
+        // (index, depth, pos_on_edge, span)
+        let mut stack = vec![(0u32, 0u32, Vec3::zeros(), octree.root_span)];
+
+        while let Some((idx, depth, pos_on_edge, span)) = stack.pop() {
+            let node = octree.octant_data[idx as usize];
+
+            if depth == brick_depth {
+                let coord = UVec3::new(
+                    (pos_on_edge.x / span) as u32,
+                    (pos_on_edge.y / span) as u32,
+                    (pos_on_edge.z / span) as u32,
+                );
+
+                let mut brick = Brick::new(coord);
+                result.voxelize(octree, &mut brick, idx, depth, Vec3::zeros(), span);
+
+                result.insert_brick(brick);
+                continue;
+            }
+
+            if !node.is_subdiv() {
+                continue;
+            }
+
+            for child_mask in 0..8 {
+                if node.check_child_filled(child_mask) {
+                    let child_span = span * 0.5;
+                    stack.push((
+                        node.get_first_child_idx() + child_mask,
+                        depth + 1,
+                        pos_on_edge + mask_to_vec!(child_mask).xyz() * child_span,
+                        child_span,
+                    ));
+                }
+            }
+        }
+
+        log::info!(
+            "Created BrickMap with [ {} ] bricks at depth [ {} ] ...",
+            result.brick_list.len(),
+            result.brick_depth
+        );
+
+        result
+    }
+
+    /// Mark every voxel covered by a leaf below node as seed.
+    /// local_pos is relative to the brick in world units.
+    fn voxelize(
+        &self,
+        octree: &Octree,
+        brick: &mut Brick,
+        idx: u32,
+        depth: u32,
+        local_pos: Vec3,
+        span: f32,
+    ) {
+        let node = octree.octant_data[idx as usize];
+        let voxel_span = self.brick_span / BRICK_SIZE as f32;
+
+        if node.is_leaf() {
+            let min = local_pos / voxel_span;
+            let len = (span / voxel_span).max(1.0) as u32;
+
+            for x in 0..len {
+                for y in 0..len {
+                    for z in 0..len {
+                        let pos = UVec3::new(min.x as u32 + x, min.y as u32 + y, min.z as u32 + z);
+                        brick.set(pos, SEED_VOXEL);
+                    }
+                }
+            }
+        }
+
+        if node.is_subdiv() && (depth as usize) < MAX_DEPTH - 1 {
+            for child_mask in 0..8 {
+                if node.check_child_filled(child_mask) {
+                    let child_span = span * 0.5;
+                    self.voxelize(
+                        octree,
+                        brick,
+                        node.get_first_child_idx() + child_mask,
+                        depth + 1,
+                        local_pos + mask_to_vec!(child_mask).xyz() * child_span,
+                        child_span,
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn insert_brick(&mut self, brick: Brick) -> usize {
+        let brick_idx = self.brick_list.len();
+
+        self.coord_map.insert(brick.coord.into(), brick_idx);
+        self.brick_list.push(brick);
+        self.dirty_list.insert(brick_idx);
+
+        brick_idx
+    }
+
+    /// Index of the brick containing the world position.
+    pub fn brick_at_pos(&self, pos: Vec3) -> Option<usize> {
+        if pos.min() < 0.0 {
+            return None;
+        }
+
+        let coord = [
+            (pos.x / self.brick_span) as u32,
+            (pos.y / self.brick_span) as u32,
+            (pos.z / self.brick_span) as u32,
+        ];
+
+        self.coord_map.get(&coord).copied()
+    }
+
+    /// Brick holding the proxy at pos_on_edge and the corner of that
+    /// brick in world space. Proxies and bricks are cut at different
+    /// depths, so a proxy has to lie inside a single brick.
+    pub fn proxy_brick(&self, pos_on_edge: Vec3, span: f32) -> (Option<usize>, Vec3) {
+        assert!(
+            span <= self.brick_span,
+            "Proxy of span {} is larger than a brick of span {}",
+            span,
+            self.brick_span
+        );
+
+        let center = pos_on_edge + Vec3::repeat(span * 0.5);
+        let brick_origin = center.map(|val| (val / self.brick_span).floor() * self.brick_span);
+
+        (self.brick_at_pos(center), brick_origin)
+    }
+
+    /// Voxel position inside its brick for a world position.
+    pub fn voxel_pos(&self, pos: Vec3) -> UVec3 {
+        let voxel_span = self.brick_span / BRICK_SIZE as f32;
+        let local_pos = pos.map(|val| val.rem_euclid(self.brick_span)) / voxel_span;
+
+        UVec3::new(local_pos.x as u32, local_pos.y as u32, local_pos.z as u32)
+    }
+
+    pub fn get_voxel(&self, pos: Vec3) -> Option<Voxel> {
+        self.brick_at_pos(pos)
+            .map(|brick_idx| self.brick_list[brick_idx].get(self.voxel_pos(pos)))
+    }
+
+    /// Set voxel at world position, only possible if a brick
+    /// exists there. Marks the brick dirty for the next upload.
+    pub fn set_voxel(&mut self, pos: Vec3, voxel: Voxel) -> bool {
+        match self.brick_at_pos(pos) {
+            Some(brick_idx) => {
+                let voxel_pos = self.voxel_pos(pos);
+                self.brick_list[brick_idx].set(voxel_pos, voxel);
+                self.dirty_list.insert(brick_idx);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Voxelize the brick containing pos again after the octree
+    /// was edited there. A brick is created if the node at brick
+    /// depth is new, a removed node leaves an empty brick behind.
+    /// The distance field of the brick has to be rebuilt after.
+    pub fn rebuild_brick(&mut self, octree: &Octree, pos: Vec3) -> Option<usize> {
+        if pos.min() < 0.0 || pos.max() >= octree.root_span {
+            return None;
+        }
+
+        let mut idx = 0;
+        let mut pos_on_edge = Vec3::zeros();
+        let mut span = octree.root_span;
+        let mut found = true;
+
+        for _ in 0..self.brick_depth {
+            let node = octree.octant_data[idx as usize];
+            span *= 0.5;
+
+            let child_mask =
+                vec_to_mask!((pos - pos_on_edge).map(|val| (val >= span) as u32 as f32));
+            if !node.is_subdiv() || !node.check_child_filled(child_mask) {
+                found = false;
+                break;
+            }
+
+            idx = node.get_first_child_idx() + child_mask;
+            pos_on_edge += mask_to_vec!(child_mask).xyz() * span;
+        }
+
+        let coord = (pos / self.brick_span).map(|val| val as u32);
+        let brick_idx = match (self.coord_map.get(&<[u32; 3]>::from(coord)).copied(), found) {
+            (Some(brick_idx), _) => brick_idx,
+            (None, true) => self.insert_brick(Brick::new(coord)),
+            (None, false) => return None,
+        };
+
+        let mut brick = Brick::new(coord);
+        if found {
+            self.voxelize(
+                octree,
+                &mut brick,
+                idx,
+                self.brick_depth,
+                Vec3::zeros(),
+                span,
+            );
+        }
+
+        self.brick_list[brick_idx] = brick;
+        self.dirty_list.insert(brick_idx);
+
+        Some(brick_idx)
+    }
+
+    /// Return dirty bricks and reset the dirty list.
+    pub fn take_dirty(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.dirty_list).into_iter().collect()
+    }
+}
+
+impl Default for BrickMap {
+    fn default() -> Self {
+        Self {
+            brick_depth: Default::default(),
+            brick_span: TEXTURE_ALIGN,
+            brick_list: Default::default(),
+            coord_map: Default::default(),
+            dirty_list: Default::default(),
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt::Write,
+    fs,
+    path::Path,
+};
+
+use nalgebra_glm::Vec4;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    octant::Octant,
+    octree::{Octree, MAX_DEPTH},
+};
+
+/// One octant as it is stored in octant data, with the
+/// packed bits spelled out for reading and hand editing.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeDump {
+    pub idx: u32,
+    pub depth: u32,
+
+    pub leaf: bool,
+    pub subdiv: bool,
+    pub bitmask: u32,
+    pub first_child_idx: u32,
+
+    pub span: f32,
+}
+
+/// Serializable view of an octree or a subtree of it.
+/// Node indices are kept as they are in octant data, so a
+/// subtree dump can be matched against the full tree.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OctreeDump {
+    pub root_span: f32,
+    pub root_idx: u32,
+
+    pub node_list: Vec<NodeDump>,
+}
+
+impl OctreeDump {
+    /// Collect every reachable node below the node containing pos,
+    /// or below the root if no pos is given.
+    pub fn new(octree: &Octree, pos: Option<Vec4>) -> Self {
+        let (root_idx, root_depth, root_span) = match pos {
+            Some(pos) => {
+                let (branch, pos_info) = octree.branch_at_pos(pos);
+                let span = if pos_info.depth == 0 {
+                    octree.root_span
+                } else {
+                    branch.span
+                };
+
+                (branch.idx, pos_info.depth, span)
+            }
+            None => (0, 0, octree.root_span),
+        };
+
+        let mut node_list = vec![];
+        let mut stack = vec![(root_idx, root_depth, root_span)];
+
+        while let Some((idx, depth, span)) = stack.pop() {
+            let node = octree.octant_data[idx as usize];
+
+            node_list.push(NodeDump {
+                idx,
+                depth,
+                leaf: node.is_leaf(),
+                subdiv: node.is_subdiv(),
+                bitmask: node.get_child_bitmask(),
+                first_child_idx: node.get_first_child_idx(),
+                span,
+            });
+
+            if node.is_subdiv() && (depth as usize) < MAX_DEPTH - 1 {
+                // Reverse, so that children are listed in mask order
+                for child_mask in (0..8).rev() {
+                    if node.check_child_filled(child_mask) {
+                        stack.push((
+                            node.get_first_child_idx() + child_mask,
+                            depth + 1,
+                            span * 0.5,
+                        ));
+                    }
+                }
+            }
+        }
+
+        Self {
+            root_span: octree.root_span,
+            root_idx,
+            node_list,
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+
+        writeln!(dot, "digraph octree {{").unwrap();
+        writeln!(dot, "    node [shape=record, fontname=monospace];").unwrap();
+
+        for node in &self.node_list {
+            let flag = match (node.leaf, node.subdiv) {
+                (true, true) => "leaf subdiv",
+                (true, false) => "leaf",
+                (false, true) => "subdiv",
+                (false, false) => "empty",
+            };
+
+            writeln!(
+                dot,
+                "    n{} [label=\"{{idx {} | {} | mask {:#010b} | first {} | span {}}}\"];",
+                node.idx, node.idx, flag, node.bitmask, node.first_child_idx, node.span
+            )
+            .unwrap();
+
+            if node.subdiv {
+                for child_mask in 0..8 {
+                    if (node.bitmask >> child_mask) & 1 == 1 {
+                        writeln!(
+                            dot,
+                            "    n{} -> n{} [label=\"{}\"];",
+                            node.idx,
+                            node.first_child_idx + child_mask,
+                            child_mask
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        }
+
+        writeln!(dot, "}}").unwrap();
+
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("ERR_SERIALIZE_OCTREE")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Rebuild octant data from the node list. Slots that are
+    /// not listed stay empty, child blocks of subdivided nodes
+    /// are always allocated completely. A subtree dump becomes a
+    /// tree of its own, see to_root_dump.
+    pub fn to_octree(&self) -> Result<Octree, Box<dyn Error>> {
+        if self.root_idx != 0 {
+            return self.to_root_dump()?.to_octree();
+        }
+
+        // Indices are checked before anything is allocated, octant
+        // data can not address more than 16 bit anyway
+        let mut len = 1;
+        for node in &self.node_list {
+            if node.idx > 0xFFFF {
+                return Err(format!("node idx {} exceeds 16 bit", node.idx).into());
+            }
+            if node.bitmask > 0xFF {
+                return Err(format!(
+                    "node {} bitmask {:#x} exceeds 8 bit",
+                    node.idx, node.bitmask
+                )
+                .into());
+            }
+            if node.first_child_idx > 0xFFFF {
+                return Err(format!(
+                    "node {} first child idx {} exceeds 16 bit",
+                    node.idx, node.first_child_idx
+                )
+                .into());
+            }
+            if node.subdiv && node.first_child_idx <= node.idx {
+                return Err(format!(
+                    "node {} first child idx {} has to come after the node",
+                    node.idx, node.first_child_idx
+                )
+                .into());
+            }
+
+            len = len.max(node.idx as usize + 1);
+            if node.subdiv {
+                len = len.max(node.first_child_idx as usize + 8);
+            }
+        }
+
+        let mut octant_data = vec![0u32; len];
+        let mut written = vec![false; len];
+
+        for node in &self.node_list {
+            let idx = node.idx as usize;
+            if written[idx] {
+                return Err(format!("node {} is listed twice", node.idx).into());
+            }
+            written[idx] = true;
+
+            let mut octant = 0u32
+                .set_leaf(node.leaf)
+                .set_subdiv(node.subdiv)
+                .set_first_child_idx(node.first_child_idx);
+
+            for child_mask in 0..8 {
+                octant = octant.set_child_filled(child_mask, (node.bitmask >> child_mask) & 1 == 1);
+            }
+
+            octant_data[idx] = octant;
+        }
+
+        Ok(Octree {
+            octant_data,
+            root_span: self.root_span,
+
+            ..Default::default()
+        })
+    }
+
+    /// Dump of a subtree with its root moved to index 0 and the
+    /// child blocks packed behind it. Depths start at 0 again and
+    /// the root span is the span of the subtree root, so voxels
+    /// keep their size.
+    pub fn to_root_dump(&self) -> Result<Self, Box<dyn Error>> {
+        let node_map: HashMap<u32, &NodeDump> =
+            self.node_list.iter().map(|node| (node.idx, node)).collect();
+        let find_node = |idx: u32| node_map.get(&idx).copied();
+
+        let root = find_node(self.root_idx)
+            .ok_or_else(|| format!("root node {} is not listed", self.root_idx))?;
+
+        let mut node_list = vec![];
+        // (old node, new idx) in breadth first order
+        let mut queue = VecDeque::from([(root, 0u32)]);
+        let mut next_block_idx = 1;
+
+        while let Some((node, idx)) = queue.pop_front() {
+            let depth = node.depth - root.depth;
+            if depth as usize >= MAX_DEPTH {
+                return Err(format!("node {} is too deep below the root", node.idx).into());
+            }
+
+            let first_child_idx = if node.subdiv {
+                let block_idx = next_block_idx;
+                next_block_idx += 8;
+
+                for child_mask in 0..8 {
+                    if (node.bitmask >> child_mask) & 1 == 0 {
+                        continue;
+                    }
+
+                    // Children which are not listed stay empty
+                    if let Some(child) = find_node(node.first_child_idx + child_mask) {
+                        if child.depth != node.depth + 1 {
+                            return Err(format!(
+                                "node {} has depth {}, but its parent {} has depth {}",
+                                child.idx, child.depth, node.idx, node.depth
+                            )
+                            .into());
+                        }
+
+                        queue.push_back((child, block_idx + child_mask));
+                    }
+                }
+
+                block_idx
+            } else {
+                0
+            };
+
+            node_list.push(NodeDump {
+                idx,
+                depth,
+                first_child_idx,
+                ..node.clone()
+            });
+        }
+
+        Ok(Self {
+            root_span: root.span,
+            root_idx: 0,
+            node_list,
+        })
+    }
+}
+
+impl Octree {
+    /// Write dot graph and json dump next to each other,
+    /// path is used without extension.
+    pub fn save_dump(&self, path: &str, pos: Option<Vec4>) -> Result<(), Box<dyn Error>> {
+        let dump = OctreeDump::new(self, pos);
+
+        fs::write(Path::new(path).with_extension("dot"), dump.to_dot())?;
+        fs::write(Path::new(path).with_extension("json"), dump.to_json())?;
+
+        Ok(())
+    }
+
+    pub fn load_dump(path: &str) -> Result<Self, Box<dyn Error>> {
+        OctreeDump::from_json(&fs::read_to_string(path)?)?.to_octree()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_scene() -> Octree {
+        let mut octree = Octree::default();
+        octree.test_scene();
+        octree
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let octree = test_scene();
+        let path = std::env::temp_dir().join(format!("pathie_dump_{}", std::process::id()));
+
+        octree
+            .save_dump(path.to_str().unwrap(), None)
+            .expect("ERR_SAVE_DUMP");
+        let loaded = Octree::load_dump(path.with_extension("json").to_str().unwrap());
+
+        fs::remove_file(path.with_extension("dot")).ok();
+        fs::remove_file(path.with_extension("json")).ok();
+
+        let loaded = loaded.expect("ERR_LOAD_DUMP");
+        assert_eq!(loaded.root_span, octree.root_span);
+        assert_eq!(loaded.octant_data, octree.octant_data);
+    }
+
+    #[test]
+    fn subtree_is_moved_to_root() {
+        let octree = test_scene();
+        let full_dump = OctreeDump::new(&octree, None);
+
+        // The first node at depth 2 holds the voxels at 0, 8 and 17
+        let sub_root = full_dump
+            .node_list
+            .iter()
+            .find(|node| node.depth == 2)
+            .unwrap();
+        let sub_dump = OctreeDump {
+            root_idx: sub_root.idx,
+            ..full_dump.clone()
+        };
+
+        let root_dump = sub_dump.to_root_dump().expect("ERR_ROOT_DUMP");
+        assert_eq!(root_dump.root_idx, 0);
+        assert_eq!(root_dump.root_span, sub_root.span);
+        assert_eq!(root_dump.node_list[0].idx, 0);
+        assert_eq!(root_dump.node_list[0].depth, 0);
+        assert_eq!(root_dump.node_list[0].first_child_idx, 1);
+
+        let sub_octree = sub_dump.to_octree().expect("ERR_SUB_OCTREE");
+        let stats = sub_octree.stats();
+
+        assert_eq!(sub_octree.root_span, octree.root_span / 4.0);
+        assert_eq!(stats.node_count_per_depth, [1, 1, 2, 2, 3, 3, 0, 0]);
+        assert_eq!(stats.leaf_count, 3);
+        // Child blocks are packed without gaps behind the root
+        assert_eq!(stats.stored_bytes, stats.reachable_bytes);
+    }
+
+    #[test]
+    fn out_of_range_idx_is_rejected() {
+        let mut dump = OctreeDump::new(&test_scene(), None);
+        dump.node_list.last_mut().unwrap().idx = u32::MAX;
+
+        assert!(dump.to_octree().is_err());
+    }
+}
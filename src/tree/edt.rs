@@ -0,0 +1,232 @@
+use nalgebra_glm::UVec3;
+
+use super::{
+    brick::{encode_voxel, Brick, BrickMap, BRICK_SIZE, EMPTY_VOXEL},
+    jfa::JfaDiff,
+};
+
+// Squared distance of lines without any seed, kept finite so
+// that the envelope intersections don't turn into NaN
+pub const EDT_INF: f32 = 1e20;
+
+/// Algorithm used to fill the distance fields of the bricks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DistanceMethod {
+    // Jump flood, Chebyshev distance with propagation errors
+    #[default]
+    Jfa,
+    // Separable Felzenszwalb-Huttenlocher transform, exact Euclidean distance
+    Exact,
+}
+
+/// 1D squared distance transform of f by the lower envelope of
+/// parabolas. Writes the squared distance and the index of the
+/// parabola (the nearest sample) for every position.
+pub fn edt_1d(f: &[f32], dist: &mut [f32], arg: &mut [usize]) {
+    let len = f.len();
+
+    let mut v = vec![0usize; len];
+    let mut z = vec![0.0f32; len + 1];
+
+    let mut k = 0;
+    z[0] = -EDT_INF;
+    z[1] = EDT_INF;
+
+    // Intersection of the parabolas rooted at q and p
+    let intersect = |q: usize, p: usize| {
+        ((f[q] + (q * q) as f32) - (f[p] + (p * p) as f32)) / (2 * q - 2 * p) as f32
+    };
+
+    for q in 1..len {
+        let mut s = intersect(q, v[k]);
+
+        // z[0] is lower than any intersection, so k never underflows
+        while s <= z[k] {
+            k -= 1;
+            s = intersect(q, v[k]);
+        }
+
+        k += 1;
+        v[k] = q;
+        z[k] = s;
+        z[k + 1] = EDT_INF;
+    }
+
+    k = 0;
+    for q in 0..len {
+        while z[k + 1] < q as f32 {
+            k += 1;
+        }
+
+        let p = v[k];
+        dist[q] = (q as f32 - p as f32).powi(2) + f[p];
+        arg[q] = p;
+    }
+}
+
+impl Brick {
+    /// Exact Euclidean distance transform inside the brick, same
+    /// encoding as the jump flood. Every voxel stores the position
+    /// of its nearest seed, voxels of bricks without seeds stay
+    /// undefined. Mirrors EDT.comp, one pass per axis.
+    pub fn exact_distance(&mut self) {
+        let size = BRICK_SIZE as usize;
+
+        // Nearest seed of every voxel, None while no seed was reached
+        let mut feature_list: Vec<Option<UVec3>> = (0..size * size * size)
+            .map(|idx| {
+                let pos = Brick::idx_to_pos(idx);
+                (self.get(pos)[3] == 0).then_some(pos)
+            })
+            .collect();
+
+        let mut f = vec![0.0; size];
+        let mut dist = vec![0.0; size];
+        let mut arg = vec![0; size];
+
+        for axis in 0..3 {
+            let mut next_list = feature_list.clone();
+
+            for u in 0..BRICK_SIZE {
+                for v in 0..BRICK_SIZE {
+                    let line_pos = |q: u32| match axis {
+                        0 => UVec3::new(q, u, v),
+                        1 => UVec3::new(u, q, v),
+                        _ => UVec3::new(u, v, q),
+                    };
+
+                    // Squared distance to the feature without the part along
+                    // the axis, which the envelope adds back
+                    for q in 0..BRICK_SIZE {
+                        let pos = line_pos(q);
+
+                        f[q as usize] = match feature_list[Brick::voxel_idx(pos)] {
+                            Some(feature) => {
+                                let delta = pos.cast::<f32>() - feature.cast::<f32>();
+                                delta.norm_squared() - delta[axis].powi(2)
+                            }
+                            None => EDT_INF,
+                        };
+                    }
+
+                    edt_1d(&f, &mut dist, &mut arg);
+
+                    for q in 0..BRICK_SIZE {
+                        let pos = line_pos(q);
+
+                        next_list[Brick::voxel_idx(pos)] = if dist[q as usize] < EDT_INF {
+                            feature_list[Brick::voxel_idx(line_pos(arg[q as usize] as u32))]
+                        } else {
+                            None
+                        };
+                    }
+                }
+            }
+
+            feature_list = next_list;
+        }
+
+        for (idx, feature) in feature_list.iter().enumerate() {
+            self.voxel_data[idx] = match feature {
+                Some(feature) => {
+                    let pos = Brick::idx_to_pos(idx);
+                    let dist = (pos.cast::<f32>() - feature.cast::<f32>()).norm();
+
+                    encode_voxel(feature.cast::<f32>(), dist)
+                }
+                None => EMPTY_VOXEL,
+            };
+        }
+    }
+}
+
+impl BrickMap {
+    pub fn exact_distance(&mut self) {
+        log::info!(
+            "Running exact distance transform on [ {} ] bricks ...",
+            self.brick_list.len()
+        );
+
+        for brick_idx in 0..self.brick_list.len() {
+            self.brick_list[brick_idx].exact_distance();
+            self.dirty_list.insert(brick_idx);
+        }
+    }
+
+    /// Run both methods on a copy of every brick and compare the
+    /// distances of the jump flood against the exact transform.
+    /// Includes the metric difference, JFA stores Chebyshev
+    /// distances which are never bigger than the Euclidean ones.
+    pub fn distance_error_report(&self, step_list: &[u32]) -> JfaDiff {
+        self.brick_list
+            .iter()
+            .fold(JfaDiff::default(), |report, brick| {
+                let mut jfa = brick.clone();
+                jfa.jump_flood(step_list);
+
+                let mut exact = brick.clone();
+                exact.exact_distance();
+
+                report.merge(&JfaDiff::new(&exact, &jfa))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra_glm::Vec3;
+
+    use super::*;
+    use crate::tree::jfa::tests::{brute_force, seeded_brick};
+
+    #[test]
+    fn edt_1d_matches_brute_force() {
+        let f = [EDT_INF, 4.0, EDT_INF, EDT_INF, 0.0, EDT_INF, 9.0, EDT_INF];
+        let mut dist = vec![0.0; f.len()];
+        let mut arg = vec![0; f.len()];
+
+        edt_1d(&f, &mut dist, &mut arg);
+
+        for q in 0..f.len() {
+            let expected = (0..f.len())
+                .map(|p| (q as f32 - p as f32).powi(2) + f[p])
+                .fold(f32::MAX, f32::min);
+
+            assert_eq!(dist[q], expected, "position {}", q);
+            assert_eq!(dist[q], (q as f32 - arg[q] as f32).powi(2) + f[arg[q]]);
+        }
+    }
+
+    #[test]
+    fn exact_distance_matches_brute_force() {
+        let seed_case_list = [
+            vec![UVec3::new(8, 8, 8)],
+            vec![UVec3::new(0, 15, 3), UVec3::new(15, 0, 12)],
+            vec![
+                UVec3::new(2, 3, 5),
+                UVec3::new(13, 7, 1),
+                UVec3::new(6, 14, 9),
+                UVec3::new(10, 10, 14),
+                UVec3::new(1, 12, 12),
+            ],
+        ];
+
+        for seed_list in seed_case_list {
+            let mut brick = seeded_brick(&seed_list);
+            let reference = brute_force(&brick, |offset: Vec3| offset.norm());
+            brick.exact_distance();
+
+            let diff = JfaDiff::new(&reference, &brick);
+            assert_eq!(diff.undefined_count, 0, "{:?}: {}", seed_list, diff);
+            assert_eq!(diff.mismatch_count, 0, "{:?}: {}", seed_list, diff);
+        }
+    }
+
+    #[test]
+    fn exact_distance_without_seed() {
+        let mut brick = seeded_brick(&[]);
+        brick.exact_distance();
+
+        assert!(brick.voxel_data.iter().all(|&voxel| voxel == EMPTY_VOXEL));
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+
+use nalgebra_glm::{UVec3, Vec3};
+
+use super::brick::{decode_voxel, encode_voxel, Brick, BrickMap, BRICK_SIZE};
+
+/// Extra passes around the halving steps of the jump flood.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JfaVariant {
+    // One step-1 pass before the halving steps
+    #[default]
+    OnePlusJfa,
+    // Step-2 and step-1 pass after the halving steps
+    JfaPlusTwo,
+}
+
+/// Step of every pass for bricks of brick_size voxels. The
+/// halving steps start at half the brick size and end at 1.
+pub fn jfa_step_list(brick_size: u32, variant: JfaVariant) -> Vec<u32> {
+    let halving_list = (0..brick_size.max(2).ilog2()).rev().map(|exp| 1 << exp);
+
+    match variant {
+        JfaVariant::OnePlusJfa => std::iter::once(1).chain(halving_list).collect(),
+        JfaVariant::JfaPlusTwo => halving_list.chain([2, 1]).collect(),
+    }
+}
+
+/// Difference between a reference distance field and another
+/// one of the same brick, e.g. the readback of the GPU pass.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JfaDiff {
+    pub voxel_count: usize,
+    // Voxels where only one side is defined
+    pub undefined_count: usize,
+    // Defined on both sides, but with a different distance
+    pub mismatch_count: usize,
+
+    pub max_error: f32,
+    pub mean_error: f32,
+}
+
+impl Brick {
+    /// One pass of the jump flood, same as compare_neighbor in
+    /// JFA.comp. Neighbours are clamped to the brick and the
+    /// distance is Chebyshev. Seeds store their own position.
+    /// Every voxel reads the state before the pass, like the
+    /// ping-pong on the GPU.
+    pub fn jfa_pass(&self, step: u32) -> Brick {
+        let mut result = self.clone();
+        let step = step as f32;
+        let max_pos = Vec3::repeat((BRICK_SIZE - 1) as f32);
+
+        for z in 0..BRICK_SIZE {
+            for y in 0..BRICK_SIZE {
+                for x in 0..BRICK_SIZE {
+                    let pos = UVec3::new(x, y, z);
+                    let base_pos = Vec3::new(x as f32, y as f32, z as f32);
+
+                    let voxel = self.get(pos);
+                    let is_seed = voxel[3] == 0;
+
+                    let (mut position_of_seed, mut cur_dist) = decode_voxel(voxel);
+
+                    // Same order as the unrolled 2D pass, so ties resolve the same
+                    for ny in -1..=1 {
+                        for nz in -1..=1 {
+                            for nx in -1..=1 {
+                                if nx == 0 && ny == 0 && nz == 0 {
+                                    continue;
+                                }
+
+                                let check_neighbor =
+                                    Vec3::new(nx as f32, ny as f32, nz as f32) * step;
+                                let neighbour_pos = (base_pos + check_neighbor)
+                                    .sup(&Vec3::zeros())
+                                    .inf(&max_pos);
+
+                                let val = self.get(UVec3::new(
+                                    neighbour_pos.x as u32,
+                                    neighbour_pos.y as u32,
+                                    neighbour_pos.z as u32,
+                                ));
+
+                                let neighbour_seed = val[3] == 0;
+                                let not_undefined = val[3] != 255;
+
+                                let (stored_position, _) = decode_voxel(val);
+
+                                let direction_to_seed = if neighbour_seed {
+                                    check_neighbor.abs()
+                                } else {
+                                    (stored_position - base_pos).abs()
+                                };
+                                let dist = direction_to_seed.max();
+
+                                if (neighbour_seed || not_undefined) && dist < cur_dist {
+                                    cur_dist = dist;
+                                    position_of_seed = if neighbour_seed {
+                                        neighbour_pos
+                                    } else {
+                                        stored_position
+                                    };
+                                }
+                            }
+                        }
+                    }
+
+                    if is_seed {
+                        cur_dist = 0.0;
+                        position_of_seed = base_pos;
+                    }
+
+                    result.set(pos, encode_voxel(position_of_seed, cur_dist));
+                }
+            }
+        }
+
+        result
+    }
+
+    pub fn jump_flood(&mut self, step_list: &[u32]) {
+        for &step in step_list {
+            *self = self.jfa_pass(step);
+        }
+    }
+
+    /// Distance stored at pos, None if no seed was reached.
+    pub fn distance(&self, pos: UVec3) -> Option<f32> {
+        let voxel = self.get(pos);
+
+        match voxel[3] {
+            255 => None,
+            _ => Some(decode_voxel(voxel).1),
+        }
+    }
+}
+
+impl JfaDiff {
+    pub fn new(reference: &Brick, other: &Brick) -> Self {
+        let mut result = Self::default();
+        let mut error_sum = 0.0;
+
+        for z in 0..BRICK_SIZE {
+            for y in 0..BRICK_SIZE {
+                for x in 0..BRICK_SIZE {
+                    let pos = UVec3::new(x, y, z);
+                    result.voxel_count += 1;
+
+                    match (reference.distance(pos), other.distance(pos)) {
+                        (Some(expected), Some(dist)) => {
+                            let error = (dist - expected).abs();
+
+                            if error > 0.0 {
+                                result.mismatch_count += 1;
+                            }
+
+                            result.max_error = result.max_error.max(error);
+                            error_sum += error;
+                        }
+                        (None, None) => {}
+                        _ => result.undefined_count += 1,
+                    }
+                }
+            }
+        }
+
+        result.mean_error = error_sum / result.voxel_count as f32;
+
+        result
+    }
+
+    /// Combine the diffs of two sets of voxels.
+    pub fn merge(&self, other: &JfaDiff) -> Self {
+        let voxel_count = self.voxel_count + other.voxel_count;
+
+        Self {
+            voxel_count,
+            undefined_count: self.undefined_count + other.undefined_count,
+            mismatch_count: self.mismatch_count + other.mismatch_count,
+
+            max_error: self.max_error.max(other.max_error),
+            mean_error: (self.mean_error * self.voxel_count as f32
+                + other.mean_error * other.voxel_count as f32)
+                / voxel_count.max(1) as f32,
+        }
+    }
+}
+
+impl BrickMap {
+    /// Run the jump flood on every brick on the cpu, so that
+    /// the gpu passes can be skipped.
+    pub fn jump_flood(&mut self, step_list: &[u32]) {
+        log::info!(
+            "Running JFA on [ {} ] bricks with steps {:?} ...",
+            self.brick_list.len(),
+            step_list
+        );
+
+        for brick_idx in 0..self.brick_list.len() {
+            self.brick_list[brick_idx].jump_flood(step_list);
+            self.dirty_list.insert(brick_idx);
+        }
+    }
+}
+
+impl fmt::Display for JfaDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "voxels {} | mismatch {} | undefined {} | max error {:.3} | mean error {:.4}",
+            self.voxel_count,
+            self.mismatch_count,
+            self.undefined_count,
+            self.max_error,
+            self.mean_error
+        )
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tree::brick::SEED_VOXEL;
+
+    pub fn seeded_brick(seed_list: &[UVec3]) -> Brick {
+        let mut brick = Brick::new(UVec3::zeros());
+        seed_list
+            .iter()
+            .for_each(|&seed| brick.set(seed, SEED_VOXEL));
+
+        brick
+    }
+
+    /// Nearest seed of every voxel by trying all of them, metric
+    /// turns the offset to a seed into its distance.
+    pub fn brute_force(brick: &Brick, metric: impl Fn(Vec3) -> f32) -> Brick {
+        let seed_list: Vec<Vec3> = (0..brick.voxel_data.len())
+            .filter(|&idx| brick.voxel_data[idx][3] == 0)
+            .map(|idx| Brick::idx_to_pos(idx).cast::<f32>())
+            .collect();
+
+        let mut result = brick.clone();
+        for idx in 0..result.voxel_data.len() {
+            let pos = Brick::idx_to_pos(idx).cast::<f32>();
+
+            let nearest = seed_list
+                .iter()
+                .map(|&seed| (seed, metric(seed - pos)))
+                .min_by(|(_, a), (_, b)| a.total_cmp(b));
+
+            if let Some((seed, dist)) = nearest {
+                result.voxel_data[idx] = encode_voxel(seed, dist);
+            }
+        }
+
+        result
+    }
+
+    pub fn chebyshev(offset: Vec3) -> f32 {
+        offset.abs().max()
+    }
+
+    fn seed_case_list() -> Vec<Vec<UVec3>> {
+        vec![
+            vec![UVec3::new(8, 8, 8)],
+            vec![UVec3::zeros()],
+            vec![UVec3::new(0, 15, 3), UVec3::new(15, 0, 12)],
+            vec![
+                UVec3::new(2, 3, 5),
+                UVec3::new(13, 7, 1),
+                UVec3::new(6, 14, 9),
+                UVec3::new(10, 10, 14),
+                UVec3::new(1, 12, 12),
+            ],
+        ]
+    }
+
+    #[test]
+    fn step_list() {
+        assert_eq!(
+            jfa_step_list(16, JfaVariant::OnePlusJfa),
+            vec![1, 8, 4, 2, 1]
+        );
+        assert_eq!(
+            jfa_step_list(16, JfaVariant::JfaPlusTwo),
+            vec![8, 4, 2, 1, 2, 1]
+        );
+    }
+
+    #[test]
+    fn jump_flood_matches_brute_force() {
+        for variant in [JfaVariant::OnePlusJfa, JfaVariant::JfaPlusTwo] {
+            let step_list = jfa_step_list(BRICK_SIZE, variant);
+
+            for seed_list in seed_case_list() {
+                let mut brick = seeded_brick(&seed_list);
+                let reference = brute_force(&brick, chebyshev);
+                brick.jump_flood(&step_list);
+
+                let diff = JfaDiff::new(&reference, &brick);
+                let case = format!("{:?} {:?}", variant, seed_list);
+                assert_eq!(diff.undefined_count, 0, "{}: {}", case, diff);
+                assert_eq!(diff.mismatch_count, 0, "{}: {}", case, diff);
+            }
+        }
+    }
+
+    #[test]
+    fn jump_flood_without_seed() {
+        let mut brick = seeded_brick(&[]);
+        brick.jump_flood(&jfa_step_list(BRICK_SIZE, JfaVariant::OnePlusJfa));
+
+        assert!((0..BRICK_SIZE).all(|x| brick.distance(UVec3::new(x, x, x)).is_none()));
+    }
+}
@@ -1,3 +1,9 @@
+pub mod brick;
+pub mod dump;
+pub mod edt;
+pub mod jfa;
 pub mod octant;
 pub mod octree;
-pub mod trace;
\ No newline at end of file
+pub mod sdf;
+pub mod stats;
+pub mod trace;
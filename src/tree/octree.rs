@@ -1,4 +1,6 @@
-use nalgebra_glm::{Vec2, Vec3, Vec4};
+use std::{collections::BTreeSet, ops::Range};
+
+use nalgebra_glm::Vec4;
 
 use crate::{mask_to_vec, vector::Vector};
 
@@ -10,11 +12,33 @@ use super::{
 pub const MAX_DEPTH: usize = 8;
 pub const MAX_DEPTH_LIMIT: usize = 16;
 pub const TEXTURE_ALIGN: f32 = 16.0;
+pub const BRICK_TEXTURE_RES: u32 = 4096;
+// Edge length of the brick texture in the 3D layout, holds as many bricks as the 2D one
+pub const BRICK_TEXTURE_RES_3D: u32 = 256;
+pub const PROXY_DEPTH: u32 = 6;
 
 pub struct Octree {
     // RootIndex = 0
     pub octant_data: Vec<u32>,
     pub root_span: f32,
+
+    // Nodes changed since the last upload and where they were edited
+    pub dirty_list: BTreeSet<usize>,
+    pub edit_pos_list: Vec<Vec4>,
+}
+
+/// Merge indices into ranges of consecutive indices.
+pub fn idx_to_ranges<Iter: IntoIterator<Item = usize>>(idx_iter: Iter) -> Vec<Range<usize>> {
+    let mut range_list: Vec<Range<usize>> = vec![];
+
+    idx_iter
+        .into_iter()
+        .for_each(|idx| match range_list.last_mut() {
+            Some(range) if range.end == idx => range.end += 1,
+            _ => range_list.push(idx..idx + 1),
+        });
+
+    range_list
 }
 
 impl Octree {
@@ -46,6 +70,12 @@ impl Octree {
     }
 
     pub fn node_at_pos(&self, pos: Vec4) -> PosInfo {
+        self.branch_at_pos(pos).1
+    }
+
+    /// Same descent as node_at_pos, but also return the branch
+    /// of the deepest node, which holds its index in octant data.
+    pub fn branch_at_pos(&self, pos: Vec4) -> (BranchInfo, PosInfo) {
         let (mut branch_data, mut pos_info) = self.get_new_root_info(pos);
 
         for _ in 1..MAX_DEPTH {
@@ -62,7 +92,7 @@ impl Octree {
             }
         }
 
-        pos_info
+        (pos_info.branch(&branch_data), pos_info)
     }
 
     pub fn insert_node(&mut self, insert_pos: Vec4) -> PosInfo {
@@ -82,13 +112,16 @@ impl Octree {
 
                     // Add new child to octant data
                     for _ in 0..8 {
+                        self.dirty_list.insert(self.octant_data.len());
                         self.octant_data.push(0);
                     }
                 }
 
                 // Set child filled and update parent in octant data
-                self.octant_data[branch.parent_idx()] =
-                    branch.parent.set_child_filled(branch.mask, true);
+                self.set_node(
+                    branch.parent_idx(),
+                    branch.parent.set_child_filled(branch.mask, true),
+                );
 
                 (branch.idx, branch.node) = branch.get_child(&self.octant_data, branch.mask);
 
@@ -96,54 +129,93 @@ impl Octree {
             });
         }
 
-        self.octant_data[pos_info.branch(&branch_data).idx()] =
-            self.octant_data[pos_info.branch(&branch_data).idx()].set_leaf(true);
+        let leaf_idx = pos_info.branch(&branch_data).idx();
+        self.set_node(leaf_idx, self.octant_data[leaf_idx].set_leaf(true));
+        self.edit_pos_list
+            .push(Self::branch_center(&branch_data, pos_info.depth_idx()));
 
         pos_info
     }
 
-    pub fn collect_branch(
-        &self,
-        branch_data: &[BranchInfo; MAX_DEPTH],
-        pos_info: &PosInfo,
-        leaf_data: &mut Vec<(PosInfo, [BranchInfo; MAX_DEPTH])>,
-        max_depth: u32,
-    ) -> [BranchInfo; MAX_DEPTH] {
-        let mut branch_data = branch_data.clone();
+    /// Remove the leaf at pos, parents without children left are
+    /// cleared too. Their children stay allocated in octant data.
+    pub fn remove_node(&mut self, remove_pos: Vec4) -> bool {
+        let (mut branch_data, mut pos_info) = self.get_new_root_info(remove_pos);
 
-        for idx in 0..8 {
-            let mut pos_info = pos_info.clone();
+        for _ in 1..MAX_DEPTH {
+            if !pos_info.branch(&branch_data).node.is_subdiv() {
+                break;
+            }
 
-            pos_info.update_branch_to_child(&mut branch_data, |branch| {
-                let mut branch = branch.clone();
-                branch.mask = idx;
+            pos_info.move_into_child(&mut branch_data, |mut branch| {
                 (branch.idx, branch.node) = branch.get_child(&self.octant_data, branch.mask);
 
                 branch
             });
+        }
 
-            let branch = pos_info.branch(&branch_data);
+        if !pos_info.branch(&branch_data).node.is_leaf() {
+            return false;
+        }
 
-            if branch.node.is_subdiv() && pos_info.depth < max_depth - 1 {
-                branch_data = self.collect_branch(&branch_data, &pos_info, leaf_data, max_depth);
-            } else if branch.node.is_leaf() || branch.node.is_subdiv() {
-                pos_info.move_up(&mut branch_data);
+        self.set_node(pos_info.branch(&branch_data).idx(), 0);
 
-                leaf_data.push((pos_info, branch_data.clone()));
+        for depth in (1..=pos_info.depth_idx()).rev() {
+            let branch = branch_data[depth];
+            let parent = self.octant_data[branch.parent_idx()].set_child_filled(branch.mask, false);
+
+            if parent.has_children() {
+                self.set_node(branch.parent_idx(), parent);
+                break;
             }
+
+            // Keep the root, even if the tree is empty now
+            self.set_node(branch.parent_idx(), if depth == 1 { parent } else { 0 });
         }
 
-        branch_data
+        self.edit_pos_list
+            .push(Self::branch_center(&branch_data, pos_info.depth_idx()));
+
+        true
+    }
+
+    /// Center of the node the branch path ends in. The path does
+    /// not always end in the node under the edited position, so
+    /// edits are tracked by the node which was actually changed.
+    pub fn branch_center(branch_data: &[BranchInfo; MAX_DEPTH], depth: usize) -> Vec4 {
+        let pos_on_edge = branch_data[1..=depth]
+            .iter()
+            .fold(Vec4::zeros(), |pos, branch| {
+                pos + mask_to_vec!(branch.mask) * branch.span
+            });
+
+        let half_span = branch_data[depth].span * 0.5;
+        pos_on_edge + Vec4::new(half_span, half_span, half_span, 0.0)
+    }
+
+    /// Only nodes whose value changes become dirty, proxies are
+    /// rebuilt below the topmost dirty node of an edit.
+    pub fn set_node(&mut self, idx: usize, node: u32) {
+        if self.octant_data[idx] != node {
+            self.octant_data[idx] = node;
+            self.dirty_list.insert(idx);
+        }
     }
 
-    pub fn write_branch_to_texture(
+    /// Return ranges of dirty nodes and the edited positions,
+    /// then reset both.
+    pub fn take_dirty(&mut self) -> (Vec<Range<usize>>, Vec<Vec4>) {
+        (
+            idx_to_ranges(std::mem::take(&mut self.dirty_list)),
+            std::mem::take(&mut self.edit_pos_list),
+        )
+    }
+
+    pub fn collect_branch(
         &self,
         branch_data: &[BranchInfo; MAX_DEPTH],
         pos_info: &PosInfo,
-        img: &mut image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
-        base_px: Vec2,
-        pos_on_edge: Vec4,
-        base_span: f32,
+        leaf_data: &mut Vec<(PosInfo, [BranchInfo; MAX_DEPTH])>,
         max_depth: u32,
     ) -> [BranchInfo; MAX_DEPTH] {
         let mut branch_data = branch_data.clone();
@@ -162,23 +234,11 @@ impl Octree {
             let branch = pos_info.branch(&branch_data);
 
             if branch.node.is_subdiv() && pos_info.depth < max_depth - 1 {
-                branch_data = self.write_branch_to_texture(
-                    &branch_data,
-                    &pos_info,
-                    img,
-                    base_px,
-                    pos_on_edge,
-                    base_span,
-                    max_depth,
-                );
+                branch_data = self.collect_branch(&branch_data, &pos_info, leaf_data, max_depth);
             } else if branch.node.is_leaf() || branch.node.is_subdiv() {
                 pos_info.move_up(&mut branch_data);
 
-                let local_pos = pos_info.local_pos - pos_on_edge;
-                let mut pos = base_px + Vec2::new(local_pos.x, local_pos.y + (local_pos.z * base_span));
-
-                img.put_pixel(pos.x as u32, pos.y as u32, image::Rgba([255, 255, 255, 0]));
-                // *img.get_pixel_mut(5, 5) = image::Rgb([255, 255, 255]);
+                leaf_data.push((pos_info, branch_data.clone()));
             }
         }
 
@@ -199,15 +259,7 @@ impl Octree {
 
         self.insert_node(Vec4::ftv(78.0));
 
-        for nude in self.octant_data.clone() {
-            log::info!(
-                "leaf {} subdiv {} bitmask {:#09b} offset {}",
-                nude.is_leaf(),
-                nude.is_subdiv(),
-                nude.get_child_bitmask(),
-                nude.get_first_child_idx()
-            );
-        }
+        log::info!("{}", self.stats());
     }
 }
 
@@ -216,6 +268,9 @@ impl Default for Octree {
         Self {
             octant_data: vec![0],
             root_span: (1 << MAX_DEPTH) as f32,
+
+            dirty_list: BTreeSet::new(),
+            edit_pos_list: vec![],
         }
     }
 }
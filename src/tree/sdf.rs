@@ -0,0 +1,561 @@
+use nalgebra_glm::{UVec3, Vec3, Vec4};
+
+use crate::{mask_to_vec, vec_to_mask};
+
+use super::{
+    edt::{edt_1d, EDT_INF},
+    octant::Octant,
+    octree::{Octree, MAX_DEPTH},
+};
+
+/// Depth of the cells of the global distance field, 64³ cells
+/// of 4 world units for the default root span of 256.
+pub const GLOBAL_SDF_DEPTH: u32 = 6;
+pub const GLOBAL_SDF_RES: u32 = 1 << GLOBAL_SDF_DEPTH;
+/// Cells around removed geometry which update recomputes. Cells
+/// whose nearest occupied cell is farther away are clamped to it.
+pub const GLOBAL_SDF_UPDATE_RADIUS: u32 = 8;
+
+/// Coarse distance field over the whole octree. Unlike the
+/// bricks it is not clamped to brick borders, every empty cell
+/// stores the distance to the nearest occupied cell in world
+/// units, so rays can skip it without missing geometry.
+#[derive(Clone, Default)]
+pub struct GlobalDistanceField {
+    pub res: u32,
+    pub cell_span: f32,
+
+    // Occupied cells, before the transform
+    pub occupied_list: Vec<bool>,
+    // Squared distance between cell centers, kept for updates
+    pub dist_sq: Vec<f32>,
+    // Conservative distance in world units, 0 for occupied cells
+    pub dist_data: Vec<f32>,
+    // Coarser levels from mip 1 on, empty until the pyramid is built
+    pub mip_list: Vec<Vec<f32>>,
+}
+
+impl GlobalDistanceField {
+    pub fn new(octree: &Octree) -> Self {
+        let res = GLOBAL_SDF_RES;
+
+        let mut result = Self {
+            res,
+            cell_span: octree.root_span / res as f32,
+
+            occupied_list: vec![false; (res * res * res) as usize],
+            dist_sq: vec![],
+            dist_data: vec![],
+            mip_list: vec![],
+        };
+
+        // (index, depth, pos_on_edge, span)
+        let mut stack = vec![(0u32, 0u32, Vec3::zeros(), octree.root_span)];
+
+        while let Some((idx, depth, pos_on_edge, span)) = stack.pop() {
+            let node = octree.octant_data[idx as usize];
+
+            // Anything below cell depth fills the whole cell
+            if node.is_leaf() || (depth == GLOBAL_SDF_DEPTH && node.is_subdiv()) {
+                let (min, len) = result.cell_cube(pos_on_edge, span);
+                result.fill_cube(min, len, true);
+                continue;
+            }
+
+            if !node.is_subdiv() || depth as usize >= MAX_DEPTH - 1 {
+                continue;
+            }
+
+            for child_mask in 0..8 {
+                if node.check_child_filled(child_mask) {
+                    let child_span = span * 0.5;
+                    stack.push((
+                        node.get_first_child_idx() + child_mask,
+                        depth + 1,
+                        pos_on_edge + mask_to_vec!(child_mask).xyz() * child_span,
+                        child_span,
+                    ));
+                }
+            }
+        }
+
+        result.transform();
+
+        log::info!(
+            "Created global distance field with [ {}³ ] cells of span [ {} ] ...",
+            result.res,
+            result.cell_span
+        );
+
+        result
+    }
+
+    pub fn cell_idx(&self, cell: UVec3) -> usize {
+        (cell.x + cell.y * self.res + cell.z * self.res * self.res) as usize
+    }
+
+    /// Distance at level 0 of the cell containing pos, same as
+    /// global_distance in the shader. 0 outside of the field.
+    pub fn distance(&self, pos: Vec3) -> f32 {
+        let cell = (pos / self.cell_span).map(|val| val.floor());
+
+        if cell.min() < 0.0 || cell.max() >= self.res as f32 {
+            return 0.0;
+        }
+
+        self.dist_data[self.cell_idx(cell.map(|val| val as u32))]
+    }
+
+    /// First cell and cells per axis of the cube at pos_on_edge.
+    fn cell_cube(&self, pos_on_edge: Vec3, span: f32) -> (UVec3, u32) {
+        let min = (pos_on_edge / self.cell_span).map(|val| val.floor() as u32);
+        let len = (span / self.cell_span).max(1.0) as u32;
+
+        (min, len)
+    }
+
+    fn fill_cube(&mut self, min: UVec3, len: u32, occupied: bool) {
+        let max = (min + UVec3::repeat(len)).inf(&UVec3::repeat(self.res));
+
+        for cell in box_cell_list(min, max) {
+            let idx = self.cell_idx(cell);
+            self.occupied_list[idx] = occupied;
+        }
+    }
+
+    /// Squared EDT over the cell centers of the box min..max,
+    /// occupied cells outside of the box are not seen. Indexed
+    /// relative to min, x first.
+    fn transform_box(&self, min: UVec3, max: UVec3) -> Vec<f32> {
+        let ext = max - min;
+        let box_idx = |cell: UVec3| (cell.x + cell.y * ext.x + cell.z * ext.x * ext.y) as usize;
+
+        let mut dist_sq: Vec<f32> = box_cell_list(min, max)
+            .map(|cell| match self.occupied_list[self.cell_idx(cell)] {
+                true => 0.0,
+                false => EDT_INF,
+            })
+            .collect();
+
+        let len = ext.max() as usize;
+        let mut f = vec![0.0; len];
+        let mut dist = vec![0.0; len];
+        let mut arg = vec![0; len];
+
+        for axis in 0..3 {
+            let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+            let len = ext[axis] as usize;
+
+            for u in 0..ext[u_axis] {
+                for v in 0..ext[v_axis] {
+                    let line_idx = |q: u32| {
+                        let mut cell = UVec3::zeros();
+                        cell[axis] = q;
+                        cell[u_axis] = u;
+                        cell[v_axis] = v;
+                        box_idx(cell)
+                    };
+
+                    for q in 0..len {
+                        f[q] = dist_sq[line_idx(q as u32)];
+                    }
+
+                    edt_1d(&f[..len], &mut dist[..len], &mut arg[..len]);
+
+                    for q in 0..len {
+                        dist_sq[line_idx(q as u32)] = dist[q].min(EDT_INF);
+                    }
+                }
+            }
+        }
+
+        dist_sq
+    }
+
+    /// Separable squared EDT over the cell centers of the whole
+    /// field, see to_distance.
+    fn transform(&mut self) {
+        self.dist_sq = self.transform_box(UVec3::zeros(), UVec3::repeat(self.res));
+        self.dist_data = self
+            .dist_sq
+            .iter()
+            .map(|&dist_sq| self.to_distance(dist_sq))
+            .collect();
+    }
+
+    /// Points of two cells are at most half a cell diagonal closer
+    /// to each other than their centers, which is subtracted so that
+    /// a ray never skips into an occupied cell.
+    fn to_distance(&self, dist_sq: f32) -> f32 {
+        if dist_sq >= EDT_INF {
+            // Empty octree, everything can be skipped
+            return self.res as f32 * self.cell_span * 3.0f32.sqrt();
+        }
+
+        (dist_sq.sqrt() - 3.0f32.sqrt()).max(0.0) * self.cell_span
+    }
+
+    /// Cells of the node an edit at pos ended in, at most of cell
+    /// size, and whether they are occupied now. The edit did not
+    /// change the occupancy of any other cell.
+    fn edit_cube(&self, octree: &Octree, pos: Vec3) -> (UVec3, u32, bool) {
+        let mut node = octree.octant_data[0];
+        let mut pos_on_edge = Vec3::zeros();
+        let mut span = octree.root_span;
+
+        for _ in 0..GLOBAL_SDF_DEPTH {
+            if node.is_leaf() || !node.is_subdiv() {
+                break;
+            }
+
+            let child_span = span * 0.5;
+            let child_mask = vec_to_mask!((pos - pos_on_edge).add_scalar(-child_span));
+
+            pos_on_edge += mask_to_vec!(child_mask).xyz() * child_span;
+            span = child_span;
+
+            if !node.check_child_filled(child_mask) {
+                let (min, len) = self.cell_cube(pos_on_edge, span);
+                return (min, len, false);
+            }
+
+            node = octree.octant_data[(node.get_first_child_idx() + child_mask) as usize];
+        }
+
+        let (min, len) = self.cell_cube(pos_on_edge, span);
+        (min, len, node.is_leaf() || node.is_subdiv())
+    }
+
+    /// Apply the edits at edit_pos_list. Only cells around cells
+    /// which changed occupancy are recomputed, cells far from a
+    /// removal keep their old distance, which is still a lower
+    /// bound. Returns the box of cells whose distance changed,
+    /// min inclusive and max exclusive.
+    pub fn update(&mut self, octree: &Octree, edit_pos_list: &[Vec4]) -> Option<(UVec3, UVec3)> {
+        let res = UVec3::repeat(self.res);
+
+        let mut added_list = vec![];
+        let mut removed_box: Option<(UVec3, UVec3)> = None;
+
+        for pos in edit_pos_list {
+            let (min, len, occupied) = self.edit_cube(octree, pos.xyz());
+            let max = (min + UVec3::repeat(len)).inf(&res);
+
+            for cell in box_cell_list(min, max) {
+                match (self.occupied_list[self.cell_idx(cell)], occupied) {
+                    (false, true) => added_list.push(cell),
+                    (true, false) => removed_box = Some(grow_box(removed_box, cell)),
+                    _ => {}
+                }
+            }
+
+            self.fill_cube(min, len, occupied);
+        }
+        // A cell can be added and removed again by the same batch
+        added_list.retain(|&cell| self.occupied_list[self.cell_idx(cell)]);
+
+        let mut dirty_box = None;
+
+        // Occupied cells in the radius around the window are found,
+        // farther ones are only known to be outside of it
+        if let Some((min, max)) = removed_box {
+            let radius = UVec3::repeat(GLOBAL_SDF_UPDATE_RADIUS);
+            let win_min = min.zip_map(&radius, u32::saturating_sub);
+            let win_max = (max + radius).inf(&res);
+            let seed_min = win_min.zip_map(&radius, u32::saturating_sub);
+            let seed_max = (win_max + radius).inf(&res);
+
+            let max_dist_sq = match seed_min == UVec3::zeros() && seed_max == res {
+                true => EDT_INF,
+                false => GLOBAL_SDF_UPDATE_RADIUS.pow(2) as f32,
+            };
+
+            let seed_ext = seed_max - seed_min;
+            let box_dist_sq = self.transform_box(seed_min, seed_max);
+
+            for cell in box_cell_list(win_min, win_max) {
+                let local = cell - seed_min;
+                let box_idx = local.x + local.y * seed_ext.x + local.z * seed_ext.x * seed_ext.y;
+
+                let idx = self.cell_idx(cell);
+                self.dist_sq[idx] = box_dist_sq[box_idx as usize].min(max_dist_sq);
+            }
+
+            dirty_box = Some((win_min, win_max));
+        }
+
+        // Added cells only lower distances, every cell they are
+        // closer to than its nearest occupied cell is updated
+        if !added_list.is_empty() {
+            let reach = self.dist_sq.iter().fold(0.0f32, |max, &val| max.max(val));
+            let reach = UVec3::repeat((reach.sqrt().ceil() as u32).min(self.res));
+
+            for added in added_list {
+                let min = added.zip_map(&reach, u32::saturating_sub);
+                let max = (added + reach + UVec3::repeat(1)).inf(&res);
+
+                for cell in box_cell_list(min, max) {
+                    let offset = cell.cast::<f32>() - added.cast::<f32>();
+                    let dist_sq = offset.norm_squared();
+
+                    let idx = self.cell_idx(cell);
+                    if dist_sq < self.dist_sq[idx] {
+                        self.dist_sq[idx] = dist_sq;
+                        dirty_box = Some(grow_box(dirty_box, cell));
+                    }
+                }
+            }
+        }
+
+        let (min, max) = dirty_box?;
+        for cell in box_cell_list(min, max) {
+            let idx = self.cell_idx(cell);
+            self.dist_data[idx] = self.to_distance(self.dist_sq[idx]);
+        }
+
+        if self.mip_list.is_empty() {
+            self.build_pyramid();
+        } else {
+            self.update_pyramid(min, max);
+        }
+
+        Some((min, max))
+    }
+
+    /// Levels down to a single cell, including the full resolution.
+    pub fn mip_count(&self) -> u32 {
+        self.res.max(1).ilog2() + 1
+    }
+
+    pub fn mip_res(&self, level: u32) -> u32 {
+        (self.res >> level).max(1)
+    }
+
+    /// Float count of all levels, the staging size of the texture.
+    pub fn pyramid_len(&self) -> usize {
+        (0..self.mip_count())
+            .map(|level| self.mip_res(level).pow(3) as usize)
+            .sum()
+    }
+
+    /// Cells of level which cover the box min..max of level 0.
+    pub fn mip_box(&self, min: UVec3, max: UVec3, level: u32) -> (UVec3, UVec3) {
+        let res = UVec3::repeat(self.mip_res(level));
+
+        (
+            min.map(|val| val >> level).inf(&res),
+            max.map(|val| val.div_ceil(1 << level)).inf(&res),
+        )
+    }
+
+    /// Cells of the box min..max of level, x first.
+    pub fn box_data(&self, level: u32, min: UVec3, max: UVec3) -> Vec<f32> {
+        let level_data = match level {
+            0 => &self.dist_data,
+            _ => &self.mip_list[level as usize - 1],
+        };
+        let res = self.mip_res(level);
+
+        box_cell_list(min, max)
+            .map(|cell| level_data[(cell.x + cell.y * res + cell.z * res * res) as usize])
+            .collect()
+    }
+
+    /// Every level stores the minimum distance of its 2×2×2
+    /// children, same as SDF_MIP.comp. A cell is occupied if any
+    /// child is, so occupancy is a distance of 0 on every level.
+    pub fn build_pyramid(&mut self) {
+        let mut mip_list: Vec<Vec<f32>> = vec![];
+
+        for level in 1..self.mip_count() {
+            let src = mip_list.last().unwrap_or(&self.dist_data);
+            mip_list.push(downsample_min(src, self.mip_res(level - 1)));
+        }
+
+        log::info!(
+            "Built distance pyramid with [ {} ] levels ...",
+            mip_list.len() + 1
+        );
+
+        self.mip_list = mip_list;
+    }
+
+    /// Rebuild the cells of every coarser level which cover the
+    /// box min..max of level 0.
+    fn update_pyramid(&mut self, min: UVec3, max: UVec3) {
+        for level in 1..self.mip_count() {
+            let (level_min, level_max) = self.mip_box(min, max, level);
+            let (src_res, res) = (self.mip_res(level - 1), self.mip_res(level));
+
+            let (src_list, dst_list) = self.mip_list.split_at_mut(level as usize - 1);
+            let src = src_list.last().unwrap_or(&self.dist_data);
+
+            for cell in box_cell_list(level_min, level_max) {
+                dst_list[0][(cell.x + cell.y * res + cell.z * res * res) as usize] =
+                    downsample_cell(src, src_res, cell);
+            }
+        }
+    }
+}
+
+/// Every cell of the box min..max, x first.
+fn box_cell_list(min: UVec3, max: UVec3) -> impl Iterator<Item = UVec3> {
+    (min.z..max.z).flat_map(move |z| {
+        (min.y..max.y).flat_map(move |y| (min.x..max.x).map(move |x| UVec3::new(x, y, z)))
+    })
+}
+
+fn grow_box(cell_box: Option<(UVec3, UVec3)>, cell: UVec3) -> (UVec3, UVec3) {
+    let (min, max) = cell_box.unwrap_or((cell, cell));
+    (min.inf(&cell), max.sup(&(cell + UVec3::repeat(1))))
+}
+
+/// Minimum over 2×2×2 cells of a cube with src_res cells per axis.
+fn downsample_min(src: &[f32], src_res: u32) -> Vec<f32> {
+    let res = (src_res / 2).max(1);
+
+    box_cell_list(UVec3::zeros(), UVec3::repeat(res))
+        .map(|cell| downsample_cell(src, src_res, cell))
+        .collect()
+}
+
+/// Minimum over the 2×2×2 children of cell.
+fn downsample_cell(src: &[f32], src_res: u32, cell: UVec3) -> f32 {
+    let src_idx = |pos: UVec3| (pos.x + pos.y * src_res + pos.z * src_res * src_res) as usize;
+
+    (0..8).fold(f32::MAX, |min, child_mask| {
+        let child = cell * 2 + mask_to_vec!(child_mask).xyz().map(|val| val as u32);
+
+        min.min(src[src_idx(child.inf(&UVec3::repeat(src_res - 1)))])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pyramid_stores_child_minimum() {
+        let res = 8;
+        let mut global_sdf = GlobalDistanceField {
+            res,
+            cell_span: 1.0,
+            // Arbitrary but fixed distances
+            dist_data: (0..res * res * res)
+                .map(|idx| ((idx * 37 + 11) % 23) as f32 + 1.0)
+                .collect(),
+            ..Default::default()
+        };
+        // One occupied cell has to stay visible on every level
+        let occupied_idx = global_sdf.cell_idx(UVec3::new(5, 2, 7));
+        global_sdf.dist_data[occupied_idx] = 0.0;
+
+        global_sdf.build_pyramid();
+        assert_eq!(global_sdf.mip_list.len() as u32, global_sdf.mip_count() - 1);
+
+        for level in 1..global_sdf.mip_count() {
+            let src = match level {
+                1 => &global_sdf.dist_data,
+                _ => &global_sdf.mip_list[level as usize - 2],
+            };
+            let dst = &global_sdf.mip_list[level as usize - 1];
+            let (src_res, res) = (global_sdf.mip_res(level - 1), global_sdf.mip_res(level));
+            assert_eq!(dst.len() as u32, res.pow(3));
+
+            for idx in 0..dst.len() as u32 {
+                let cell = UVec3::new(idx % res, (idx / res) % res, idx / (res * res));
+                let min = (0..8)
+                    .map(|child_mask| {
+                        let child = cell * 2 + mask_to_vec!(child_mask).xyz().map(|val| val as u32);
+                        src[(child.x + child.y * src_res + child.z * src_res * src_res) as usize]
+                    })
+                    .fold(f32::MAX, f32::min);
+
+                assert_eq!(dst[idx as usize], min, "level {} cell {:?}", level, cell);
+            }
+
+            // Occupied cell is found on every level
+            let occupied = UVec3::new(5, 2, 7) / (1 << level);
+            assert_eq!(
+                dst[(occupied.x + occupied.y * res + occupied.z * res * res) as usize],
+                0.0
+            );
+        }
+    }
+
+    fn built_field(octree: &Octree) -> GlobalDistanceField {
+        let mut global_sdf = GlobalDistanceField::new(octree);
+        global_sdf.build_pyramid();
+        global_sdf
+    }
+
+    fn in_box(cell: UVec3, (min, max): (UVec3, UVec3)) -> bool {
+        (0..3).all(|axis| min[axis] <= cell[axis] && cell[axis] < max[axis])
+    }
+
+    #[test]
+    fn update_after_insert_matches_rebuild() {
+        let mut octree = Octree::default();
+        octree.test_scene();
+        let mut global_sdf = built_field(&octree);
+        octree.take_dirty();
+
+        octree.insert_node(Vec4::new(40.0, 200.0, 120.0, 0.0));
+        // Same cell as the last one
+        octree.insert_node(Vec4::new(41.0, 201.0, 121.0, 0.0));
+        let (_, edit_pos_list) = octree.take_dirty();
+
+        let old_sdf = global_sdf.clone();
+        let dirty_box = global_sdf.update(&octree, &edit_pos_list).unwrap();
+        let rebuilt = built_field(&octree);
+
+        assert_eq!(global_sdf.occupied_list, rebuilt.occupied_list);
+        assert_eq!(global_sdf.dist_data, rebuilt.dist_data);
+        assert_eq!(global_sdf.mip_list, rebuilt.mip_list);
+
+        for cell in box_cell_list(UVec3::zeros(), UVec3::repeat(global_sdf.res)) {
+            let idx = global_sdf.cell_idx(cell);
+            if !in_box(cell, dirty_box) {
+                assert_eq!(global_sdf.dist_data[idx], old_sdf.dist_data[idx]);
+            }
+        }
+    }
+
+    #[test]
+    fn update_after_remove_stays_conservative() {
+        let mut octree = Octree::default();
+        octree.test_scene();
+        let mut global_sdf = built_field(&octree);
+        octree.take_dirty();
+
+        assert!(octree.remove_node(Vec4::new(78.0, 78.0, 78.0, 0.0)));
+        let (_, edit_pos_list) = octree.take_dirty();
+
+        let old_sdf = global_sdf.clone();
+        let dirty_box = global_sdf.update(&octree, &edit_pos_list).unwrap();
+        let rebuilt = built_field(&octree);
+
+        assert_eq!(global_sdf.occupied_list, rebuilt.occupied_list);
+
+        // The other voxels are farther than the update radius
+        let exact_dist = rebuilt.to_distance(GLOBAL_SDF_UPDATE_RADIUS.pow(2) as f32);
+        for cell in box_cell_list(UVec3::zeros(), UVec3::repeat(global_sdf.res)) {
+            let idx = global_sdf.cell_idx(cell);
+            let (dist, rebuilt_dist) = (global_sdf.dist_data[idx], rebuilt.dist_data[idx]);
+
+            assert!(dist <= rebuilt_dist, "cell {:?}", cell);
+            if !in_box(cell, dirty_box) {
+                assert_eq!(dist, old_sdf.dist_data[idx]);
+            } else if rebuilt_dist <= exact_dist {
+                assert_eq!(dist, rebuilt_dist, "cell {:?}", cell);
+            }
+        }
+
+        for (level_data, rebuilt_data) in global_sdf.mip_list.iter().zip(&rebuilt.mip_list) {
+            assert!(level_data
+                .iter()
+                .zip(rebuilt_data)
+                .all(|(dist, rebuilt_dist)| dist <= rebuilt_dist));
+        }
+    }
+}
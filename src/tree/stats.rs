@@ -0,0 +1,247 @@
+use std::{fmt, mem};
+
+use nalgebra_glm::Vec4;
+
+use crate::{
+    pipe::{
+        engine::SCENE_BUFFER_HEADROOM,
+        obj::{BASE_CUBE_IDX, BASE_CUBE_VERT},
+        pipe::{LocInfo, Vertex},
+    },
+    uniform::Uniform,
+};
+
+use super::{
+    octant::Octant,
+    octree::{Octree, BRICK_TEXTURE_RES, MAX_DEPTH, PROXY_DEPTH},
+};
+
+/// Byte sizes of the buffers and images `Engine::create_base`
+/// allocates for an octree. Swapchain sized targets are not
+/// included, because they only depend on the surface.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GpuBufferEstimate {
+    pub proxy_count: usize,
+
+    pub brick_texture: u64,
+    pub brick_staging: u64,
+
+    pub vertex: u64,
+    pub index: u64,
+    pub loc_info: u64,
+
+    pub uniform: u64,
+    pub octree: u64,
+}
+
+/// Structured report about the encoding of an octree.
+/// Everything is measured by walking the tree from the root,
+/// so nodes which are stored but not linked anymore show up
+/// as difference between stored and reachable bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OctreeStats {
+    pub node_count_per_depth: [usize; MAX_DEPTH],
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub subdiv_count: usize,
+
+    // Occupied volume of the leaves relative to the root volume
+    pub fill_ratio: f64,
+
+    pub stored_bytes: u64,
+    pub reachable_bytes: u64,
+
+    // Child slots that are allocated, but not flagged in the bitmask
+    pub wasted_child_slots: usize,
+
+    pub gpu: GpuBufferEstimate,
+}
+
+impl GpuBufferEstimate {
+    pub fn new(octree: &Octree) -> Self {
+        let (branch_data, pos_info) = octree.get_new_root_info(Vec4::default());
+        let mut leaf_data = vec![];
+        octree.collect_branch(&branch_data, &pos_info, &mut leaf_data, PROXY_DEPTH);
+
+        let proxy_count = leaf_data.len();
+        let texture_size = (BRICK_TEXTURE_RES as u64).pow(2) * 4;
+
+        Self {
+            proxy_count,
+
+            brick_texture: texture_size,
+            brick_staging: texture_size,
+
+            vertex: (proxy_count * BASE_CUBE_VERT.len() * mem::size_of::<Vertex>()) as u64
+                * SCENE_BUFFER_HEADROOM,
+            index: (proxy_count * BASE_CUBE_IDX.len() * mem::size_of::<u32>()) as u64
+                * SCENE_BUFFER_HEADROOM,
+            loc_info: (proxy_count * mem::size_of::<LocInfo>()) as u64 * SCENE_BUFFER_HEADROOM,
+
+            uniform: mem::size_of::<Uniform>() as u64,
+            octree: mem::size_of_val(&octree.octant_data[..]) as u64 * SCENE_BUFFER_HEADROOM,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.brick_texture
+            + self.brick_staging
+            + self.vertex
+            + self.index
+            + self.loc_info
+            + self.uniform
+            + self.octree
+    }
+}
+
+impl OctreeStats {
+    pub fn new(octree: &Octree) -> Self {
+        let mut result = Self {
+            stored_bytes: mem::size_of_val(&octree.octant_data[..]) as u64,
+            gpu: GpuBufferEstimate::new(octree),
+
+            ..Default::default()
+        };
+
+        // The root is always reachable, every subdivided node
+        // adds a block of 8 slots
+        let mut reachable_slots = 1;
+
+        // (index, depth)
+        let mut stack = vec![(0usize, 0usize)];
+
+        while let Some((idx, depth)) = stack.pop() {
+            let node = octree.octant_data[idx];
+
+            result.node_count += 1;
+            result.node_count_per_depth[depth] += 1;
+
+            if node.is_leaf() {
+                result.leaf_count += 1;
+                result.fill_ratio += 0.125f64.powi(depth as i32);
+            }
+
+            if !node.is_subdiv() {
+                continue;
+            }
+
+            result.subdiv_count += 1;
+            reachable_slots += 8;
+
+            let first_child_idx = node.get_first_child_idx() as usize;
+            for child_mask in 0..8 {
+                if !node.check_child_filled(child_mask) {
+                    result.wasted_child_slots += 1;
+                } else if depth + 1 < MAX_DEPTH {
+                    stack.push((first_child_idx + child_mask as usize, depth + 1));
+                }
+            }
+        }
+
+        result.reachable_bytes = (reachable_slots * mem::size_of::<u32>()) as u64;
+
+        result
+    }
+}
+
+impl Octree {
+    pub fn stats(&self) -> OctreeStats {
+        OctreeStats::new(self)
+    }
+}
+
+fn fmt_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1048575 => format!("{:.2} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.2} MiB", bytes as f64 / 1048576.0),
+    }
+}
+
+impl fmt::Display for OctreeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Octree stats")?;
+        writeln!(
+            f,
+            "  nodes {} | leaf {} | subdiv {}",
+            self.node_count, self.leaf_count, self.subdiv_count
+        )?;
+
+        for (depth, count) in self.node_count_per_depth.iter().enumerate() {
+            writeln!(f, "  depth {} -> {} nodes", depth, count)?;
+        }
+
+        writeln!(f, "  fill ratio {:.6}%", self.fill_ratio * 100.0)?;
+        writeln!(
+            f,
+            "  octant data {} stored | {} reachable",
+            fmt_bytes(self.stored_bytes),
+            fmt_bytes(self.reachable_bytes)
+        )?;
+        writeln!(f, "  wasted child slots {}", self.wasted_child_slots)?;
+
+        writeln!(f, "GPU estimate ({} proxies)", self.gpu.proxy_count)?;
+        writeln!(f, "  brick texture {}", fmt_bytes(self.gpu.brick_texture))?;
+        writeln!(f, "  brick staging {}", fmt_bytes(self.gpu.brick_staging))?;
+        writeln!(f, "  vertex {}", fmt_bytes(self.gpu.vertex))?;
+        writeln!(f, "  index {}", fmt_bytes(self.gpu.index))?;
+        writeln!(f, "  location info {}", fmt_bytes(self.gpu.loc_info))?;
+        writeln!(f, "  uniform {}", fmt_bytes(self.gpu.uniform))?;
+        writeln!(f, "  octree {}", fmt_bytes(self.gpu.octree))?;
+        write!(f, "  total {}", fmt_bytes(self.gpu.total()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_scene() -> Octree {
+        let mut octree = Octree::default();
+        octree.test_scene();
+        octree
+    }
+
+    #[test]
+    fn test_scene_counts() {
+        let octree = test_scene();
+        let stats = octree.stats();
+
+        // Voxels on a child edge fall into the lower child, so 78, 17 and 8
+        // split off at depth 2, 4 and 6
+        assert_eq!(stats.node_count_per_depth, [1, 1, 2, 2, 3, 3, 4, 4]);
+        assert_eq!(stats.node_count, 20);
+        assert_eq!(stats.leaf_count, 4);
+        assert_eq!(stats.subdiv_count, 16);
+        assert_eq!(stats.fill_ratio, 4.0 * 0.125f64.powi(MAX_DEPTH as i32 - 1));
+
+        // Every stored node is linked, root plus 8 slots per subdivided node
+        assert_eq!(octree.octant_data.len(), 1 + 8 * 16);
+        assert_eq!(stats.stored_bytes, stats.reachable_bytes);
+        assert_eq!(stats.reachable_bytes, (1 + 8 * 16) * 4);
+        assert_eq!(stats.wasted_child_slots, 8 * 16 - (20 - 1));
+
+        assert_eq!(
+            stats.gpu.octree,
+            octree.octant_data.len() as u64 * 4 * SCENE_BUFFER_HEADROOM
+        );
+    }
+
+    #[test]
+    fn removed_nodes_stay_stored() {
+        let mut octree = test_scene();
+        assert!(octree.remove_node(Vec4::new(78.0, 78.0, 78.0, 78.0)));
+
+        let stats = octree.stats();
+
+        // The branch of 78 below depth 1 is unlinked, but still allocated
+        assert_eq!(stats.node_count_per_depth, [1, 1, 1, 1, 2, 2, 3, 3]);
+        assert_eq!(stats.node_count, 14);
+        assert_eq!(stats.leaf_count, 3);
+        assert_eq!(stats.subdiv_count, 11);
+
+        assert_eq!(stats.stored_bytes, (1 + 8 * 16) * 4);
+        assert_eq!(stats.reachable_bytes, (1 + 8 * 11) * 4);
+        assert_eq!(stats.wasted_child_slots, 8 * 11 - (14 - 1));
+    }
+}